@@ -1,15 +1,19 @@
+use std::time::{Duration, Instant};
+
 use crate::error::PeerResult;
 use crate::wire_protocol::messages::ProtocolMessage;
 
+#[derive(Debug)]
 pub struct ConversationAction {
-    pub message: Option<ProtocolMessage>,
+    /// messages to send, in order
+    pub messages: Vec<ProtocolMessage>,
     pub topic_finished: bool,
 }
 
 impl ConversationAction {
     pub fn nop() -> Self {
         ConversationAction {
-            message: None,
+            messages: vec![],
             topic_finished: false,
         }
     }
@@ -20,9 +24,17 @@ pub trait ConversationTopicHandler {
 
     fn initial_action(&mut self) -> ConversationAction;
     fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction>;
-    /// the result of this conversation, once it's finished
-    fn outcome(self) -> PeerResult<Self::Outcome>;
-}
 
+    /// Interval, in which [Self::on_tick] shall be called. `None` (default) for topics, which are driven by incoming messages only.
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
 
+    /// called periodically according to [Self::tick_interval]
+    fn on_tick(&mut self, _now: Instant) -> PeerResult<ConversationAction> {
+        Ok(ConversationAction::nop())
+    }
 
+    /// the result of this conversation, once it's finished
+    fn outcome(self) -> PeerResult<Self::Outcome>;
+}
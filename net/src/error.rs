@@ -1,34 +1,89 @@
 use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+
+use crate::wire_protocol::node::Chain;
 
 pub type PeerResult<T> = Result<T, PeerError>;
 
 #[derive(Debug)]
-pub struct PeerError {
-    pub msg: String,
+pub enum PeerError {
+    /// reading from or writing to the connection failed
+    Io(std::io::Error),
+    /// the remote node closed the connection
+    Disconnected,
+    /// the payload of a received message does not match its checksum
+    Checksum { command: String },
+    /// a message of another network than the expected one was received
+    WrongNetwork { expected: Chain, got: u32 },
+    /// no network is known for this magic value
+    UnknownNetwork { magic: u32 },
+    UnknownCommand(String),
+    /// the payload of a received message could not be decoded
+    Malformed { command: String, source: std::io::Error },
+    /// a message announced a payload larger than we are willing to receive
+    PayloadTooLarge { command: String, len: usize, max: usize },
+    /// the remote node does not follow the protocol, e.g. sends messages in an unexpected order
+    ProtocolViolation(String),
+    /// a block header does not satisfy the consensus rules, e.g. lacks the proof of work it claims
+    InvalidHeader { hash: String, reason: String },
+    /// a block does not match its header, e.g. its transactions do not hash to the merkle root
+    InvalidBlock { hash: String, reason: String },
+    /// a compact block filter does not match the filter header chain
+    InvalidFilter { hash: String, reason: String },
+    /// we are connected to ourself, detected by receiving our own version nonce
+    SelfConnection { nonce: u64 },
+    /// the remote node did not answer in time, e.g. too many pings without a pong
+    Timeout(String),
+    /// a partial merkle tree was requested with a match flag count differing from the txid count
+    MatchFlagCount { txids: usize, matches: usize },
+    /// a conversation was requested with a node, which we are not connected to
+    NotConnected(SocketAddr),
+    /// no well-known network has this name
+    UnknownChain(String),
 }
 
 impl Display for PeerError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.msg)
-    }
-}
-
-impl std::error::Error for PeerError {}
-
-impl From<String> for PeerError {
-    fn from(msg: String) -> Self {
-        PeerError { msg }
+        match self {
+            PeerError::Io(err) => write!(f, "{}", err),
+            PeerError::Disconnected => write!(f, "Remote node hung up"),
+            PeerError::Checksum { command } => write!(f, "checksum error in '{}' message", command),
+            PeerError::WrongNetwork { expected, got } => {
+                match Chain::try_from(*got) {
+                    Ok(chain) => write!(f, "expected network chain {:?}, but got a message from {:?}", expected, chain),
+                    Err(_) => write!(f, "expected network chain {:?}, but got a message with unknown magic value {:#010x}", expected, got),
+                }
+            }
+            PeerError::UnknownNetwork { magic } => write!(f, "No chain known having magic value {:#010x}", magic),
+            PeerError::UnknownCommand(command) => write!(f, "'{}' does not represent a known bitcoin command", command),
+            PeerError::Malformed { command, source } => write!(f, "malformed '{}' message: {}", command, source),
+            PeerError::PayloadTooLarge { command, len, max } => {
+                write!(f, "'{}' message payload of {} bytes exceeds the maximum of {} bytes", command, len, max)
+            }
+            PeerError::ProtocolViolation(msg) => write!(f, "Protocol error: {}", msg),
+            PeerError::InvalidHeader { hash, reason } => write!(f, "invalid block header {}: {}", hash, reason),
+            PeerError::InvalidBlock { hash, reason } => write!(f, "invalid block {}: {}", hash, reason),
+            PeerError::InvalidFilter { hash, reason } => write!(f, "invalid compact filter of block {}: {}", hash, reason),
+            PeerError::SelfConnection { nonce } => write!(f, "connected to ourself: received our own version nonce {}", nonce),
+            PeerError::Timeout(msg) => write!(f, "timeout: {}", msg),
+            PeerError::MatchFlagCount { txids, matches } => write!(f, "{} match flags given for {} txids", matches, txids),
+            PeerError::NotConnected(addr) => write!(f, "not connected to {}", addr),
+            PeerError::UnknownChain(name) => write!(f, "unknown chain '{}'", name),
+        }
     }
 }
 
-impl From<&str> for PeerError {
-    fn from(msg: &str) -> Self {
-        PeerError::from(msg.to_string())
+impl std::error::Error for PeerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PeerError::Io(err) | PeerError::Malformed { source: err, .. } => Some(err),
+            _ => None,
+        }
     }
 }
 
 impl From<std::io::Error> for PeerError {
     fn from(value: std::io::Error) -> Self {
-        Self::from(format!("{}", value))
+        PeerError::Io(value)
     }
 }
@@ -0,0 +1,138 @@
+use std::time::{Duration, Instant};
+
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::PeerResult;
+use crate::wire_protocol::address::PeerAddress;
+use crate::wire_protocol::messages::{GetAddrMessage, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::Chain;
+
+/// Address discovery (after the handshake):
+///
+/// - send __getaddr__ message
+/// - expect __addr__ or __addrv2__ messages
+///
+/// Nodes announce their own address now and then in a message with a single entry.
+/// Such entries are collected as well, but the topic is finished by the first message with more entries, which is the
+/// answer to our __getaddr__. A node which doesn't know any addresses does not answer at all, so the topic is also
+/// finished once `response_timeout` has passed.
+pub struct AddrDiscoveryConversationTopic {
+    chain: Chain,
+    response_timeout: Duration,
+    addresses: Vec<PeerAddress>,
+}
+
+impl AddrDiscoveryConversationTopic {
+    pub fn new(chain: Chain, response_timeout: Duration) -> Self {
+        AddrDiscoveryConversationTopic {
+            chain,
+            response_timeout,
+            addresses: vec![],
+        }
+    }
+
+    fn collect(&mut self, addresses: Vec<PeerAddress>) -> ConversationAction {
+        let topic_finished = addresses.len() > 1;
+        self.addresses.extend(addresses);
+        ConversationAction {
+            messages: vec![],
+            topic_finished,
+        }
+    }
+}
+
+impl ConversationTopicHandler for AddrDiscoveryConversationTopic {
+    type Outcome = Vec<PeerAddress>;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        ConversationAction {
+            messages: vec![ProtocolMessage::GetAddr(GetAddrMessage::new(self.chain))],
+            topic_finished: false,
+        }
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::Addr(m) => {
+                Ok(self.collect(m.addresses.into_iter().map(PeerAddress::from).collect()))
+            }
+            ProtocolMessage::AddrV2(m) => {
+                Ok(self.collect(m.addresses))
+            }
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.response_timeout)
+    }
+
+    fn on_tick(&mut self, _now: Instant) -> PeerResult<ConversationAction> {
+        log::debug!("no (further) answer to 'getaddr' within {:?}", self.response_timeout);
+        Ok(ConversationAction {
+            messages: vec![],
+            topic_finished: true,
+        })
+    }
+
+    fn outcome(self) -> PeerResult<Vec<PeerAddress>> {
+        Ok(self.addresses)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::wire_protocol::address::{NetworkAddress, TimestampedNetAddr};
+    use crate::wire_protocol::messages::{AddrMessage, AddrV2Message};
+    use crate::wire_protocol::node::NodeServiceSet;
+
+    use super::*;
+
+    fn addr(i: u8) -> TimestampedNetAddr {
+        TimestampedNetAddr {
+            time: 1678901234,
+            services: NodeServiceSet::default(),
+            addr: SocketAddr::from((Ipv4Addr::new(10, 0, 0, i), 18444)),
+        }
+    }
+
+    #[test]
+    fn test_addresses_are_collected_until_getaddr_answer() {
+        let mut topic = AddrDiscoveryConversationTopic::new(Chain::Regtest, Duration::from_secs(30));
+        assert!(matches!(topic.initial_action().messages[..], [ProtocolMessage::GetAddr(_)]));
+
+        // self announcement
+        let action = topic.on_message(ProtocolMessage::Addr(AddrMessage::new(Chain::Regtest, vec![addr(1)]))).unwrap();
+        assert!(!action.topic_finished);
+
+        let tor = PeerAddress {
+            time: 1678901234,
+            services: NodeServiceSet::default(),
+            addr: NetworkAddress::TorV3([1; 32]),
+            port: 8333,
+        };
+        let answer = vec![PeerAddress::from(addr(2)), tor.clone()];
+        let action = topic.on_message(ProtocolMessage::AddrV2(AddrV2Message::new(Chain::Regtest, answer))).unwrap();
+        assert!(action.topic_finished);
+
+        let addresses = topic.outcome().unwrap();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[0].to_string(), "10.0.0.1:18444");
+        assert_eq!(addresses[2], tor);
+    }
+
+    #[test]
+    fn test_missing_answer_finishes_topic() {
+        let mut topic = AddrDiscoveryConversationTopic::new(Chain::Regtest, Duration::from_secs(30));
+        topic.initial_action();
+        assert!(topic.on_tick(Instant::now()).unwrap().topic_finished);
+        assert!(topic.outcome().unwrap().is_empty());
+    }
+}
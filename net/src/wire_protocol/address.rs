@@ -0,0 +1,238 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::node::NodeServiceSet;
+
+/// Maximum length of an address in an __addrv2__ message (see `MAX_ADDRV2_SIZE` in bitcoin core)
+pub(super) const MAX_ADDRV2_SIZE: usize = 512;
+
+/// Network address with time field, as used in __addr__ messages
+///
+/// size | field    | type     | description
+/// ---  | -----    | ----     | ------------
+/// 4    | time     | u32      | the time the node was last seen
+/// 26   | net_addr | net_addr | services, IPv6 address and port
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimestampedNetAddr {
+    pub time: u32,
+    pub services: NodeServiceSet,
+    pub addr: SocketAddr,
+}
+
+impl TimestampedNetAddr {
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let time = parser.read_u32_le()?;
+        let (services, addr) = parser.parse_net_addr()?;
+        Ok(TimestampedNetAddr { time, services, addr })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.time.to_le_bytes());
+        composer.append_net_addr(&self.services, &self.addr);
+    }
+}
+
+/// Address of a node in any of the networks defined by BIP155
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum NetworkAddress {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// ed25519 public key of a Tor v3 onion service
+    TorV3([u8; 32]),
+    /// SHA256 hash of an I2P destination
+    I2p([u8; 32]),
+    /// IPv6 address in the CJDNS range fc00::/8
+    Cjdns(Ipv6Addr),
+}
+
+impl NetworkAddress {
+    /// BIP155 network ID
+    pub fn network_id(&self) -> u8 {
+        match self {
+            NetworkAddress::Ipv4(_) => 1,
+            NetworkAddress::Ipv6(_) => 2,
+            NetworkAddress::TorV3(_) => 4,
+            NetworkAddress::I2p(_) => 5,
+            NetworkAddress::Cjdns(_) => 6,
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        match self {
+            NetworkAddress::Ipv4(ip) => ip.octets().to_vec(),
+            NetworkAddress::Ipv6(ip) | NetworkAddress::Cjdns(ip) => ip.octets().to_vec(),
+            NetworkAddress::TorV3(key) | NetworkAddress::I2p(key) => key.to_vec(),
+        }
+    }
+
+    /// `None` for networks unknown to us (e.g. the deprecated Tor v2), which have to be ignored according to BIP155
+    fn from_bytes(network_id: u8, bytes: &[u8]) -> io::Result<Option<Self>> {
+        fn fixed<const N: usize>(network_id: u8, bytes: &[u8]) -> io::Result<[u8; N]> {
+            bytes.try_into().map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("address of network {} has {} bytes instead of {}", network_id, bytes.len(), N))
+            )
+        }
+
+        Ok(Some(match network_id {
+            1 => NetworkAddress::Ipv4(Ipv4Addr::from(fixed::<4>(network_id, bytes)?)),
+            2 => NetworkAddress::Ipv6(Ipv6Addr::from(fixed::<16>(network_id, bytes)?)),
+            4 => NetworkAddress::TorV3(fixed::<32>(network_id, bytes)?),
+            5 => NetworkAddress::I2p(fixed::<32>(network_id, bytes)?),
+            6 => NetworkAddress::Cjdns(Ipv6Addr::from(fixed::<16>(network_id, bytes)?)),
+            _ => return Ok(None),
+        }))
+    }
+}
+
+impl From<IpAddr> for NetworkAddress {
+    fn from(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => NetworkAddress::Ipv4(ip),
+            IpAddr::V6(ip) => NetworkAddress::Ipv6(ip),
+        }
+    }
+}
+
+impl Display for NetworkAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkAddress::Ipv4(ip) => write!(f, "{}", ip),
+            NetworkAddress::Ipv6(ip) | NetworkAddress::Cjdns(ip) => write!(f, "[{}]", ip),
+            NetworkAddress::TorV3(key) => write!(f, "torv3:{}", hex(key)),
+            NetworkAddress::I2p(hash) => write!(f, "i2p:{}", hex(hash)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Address of a node as gossiped by __addr__ and __addrv2__ messages
+///
+/// Entry of an __addrv2__ message (BIP155):
+///
+/// size | field    | type         | description
+/// ---  | -----    | ----         | ------------
+/// 4    | time     | u32          | the time the node was last seen
+/// ?    | services | CompactSize  | service bits
+/// 1    | networkID| u8           | network of the address
+/// ?    | addr     | var_bytes    | network address, interpreted according to networkID
+/// 2    | port     | u16          | port number, big endian
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerAddress {
+    pub time: u32,
+    pub services: NodeServiceSet,
+    pub addr: NetworkAddress,
+    pub port: u16,
+}
+
+impl PeerAddress {
+    /// `None` for addresses of networks unknown to us
+    pub(super) fn parse_v2(parser: &mut ByteBufferParser) -> io::Result<Option<Self>> {
+        let time = parser.read_u32_le()?;
+        let services = NodeServiceSet::from_bitmask(parser.read_compact_size()?);
+        let network_id = parser.read(1)?[0];
+        let bytes = parser.read_var_bytes(MAX_ADDRV2_SIZE)?;
+        let port = u16::from_be_bytes(parser.read(2)?.try_into().unwrap());
+        Ok(NetworkAddress::from_bytes(network_id, bytes)?
+            .map(|addr| PeerAddress { time, services, addr, port }))
+    }
+
+    pub(super) fn append_v2(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.time.to_le_bytes());
+        composer.append_compact_size(self.services.as_bitmask());
+        composer.append(&[self.addr.network_id()]);
+        composer.append_var_bytes(&self.addr.bytes());
+        composer.append(&self.port.to_be_bytes());
+    }
+
+    /// the socket address, if the node is reachable by IP
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self.addr {
+            NetworkAddress::Ipv4(ip) => Some(SocketAddr::new(IpAddr::V4(ip), self.port)),
+            NetworkAddress::Ipv6(ip) => Some(SocketAddr::new(IpAddr::V6(ip), self.port)),
+            _ => None,
+        }
+    }
+}
+
+impl From<TimestampedNetAddr> for PeerAddress {
+    fn from(addr: TimestampedNetAddr) -> Self {
+        PeerAddress {
+            time: addr.time,
+            services: addr.services,
+            addr: NetworkAddress::from(addr.addr.ip()),
+            port: addr.addr.port(),
+        }
+    }
+}
+
+impl Display for PeerAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.addr, self.port)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use crate::wire_protocol::node::NodeService;
+
+    use super::*;
+
+    #[rstest]
+    #[case(NetworkAddress::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), & hex ! ("f2ff1164" "09" "01" "04" "01020304" "208d")[..])]
+    #[case(NetworkAddress::Ipv6(Ipv6Addr::LOCALHOST), & hex ! ("f2ff1164" "09" "02" "10" "00000000000000000000000000000001" "208d"))]
+    #[case(NetworkAddress::TorV3([0xab; 32]), & hex ! ("f2ff1164" "09" "04" "20" "abababababababababababababababababababababababababababababababab" "208d"))]
+    #[case(NetworkAddress::I2p([0x01; 32]), & hex ! ("f2ff1164" "09" "05" "20" "0101010101010101010101010101010101010101010101010101010101010101" "208d"))]
+    #[case(NetworkAddress::Cjdns("fc00::1".parse().unwrap()), & hex ! ("f2ff1164" "09" "06" "10" "fc000000000000000000000000000001" "208d"))]
+    fn test_addr_v2_round_trip(#[case] addr: NetworkAddress, #[case] encoded: &[u8]) {
+        let address = PeerAddress {
+            time: 1678901234,
+            services: NodeServiceSet::new(&[NodeService::NodeNetwork, NodeService::NodeWitness]),
+            addr,
+            port: 8333,
+        };
+        let mut composer = ByteBufferComposer::new();
+        address.append_v2(&mut composer);
+        assert_eq!(composer.result(), encoded);
+
+        let mut parser = ByteBufferParser::new(encoded);
+        assert_eq!(PeerAddress::parse_v2(&mut parser).unwrap(), Some(address));
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_addr_v2_of_unknown_network_is_skipped() {
+        // Tor v2 (network id 3) is not supported anymore
+        let encoded = hex!("f2ff1164" "09" "03" "0a" "01020304050607080910" "208d");
+        let mut parser = ByteBufferParser::new(&encoded);
+        assert_eq!(PeerAddress::parse_v2(&mut parser).unwrap(), None);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_addr_v2_with_wrong_address_length_is_rejected() {
+        let encoded = hex!("f2ff1164" "09" "01" "05" "0102030405" "208d");
+        assert!(PeerAddress::parse_v2(&mut ByteBufferParser::new(&encoded)).is_err());
+    }
+
+    #[test]
+    fn test_timestamped_net_addr_round_trip() {
+        let encoded = hex!("f2ff1164" "0100000000000000" "00000000000000000000ffff7f000001" "480c");
+        let mut parser = ByteBufferParser::new(&encoded);
+        let addr = TimestampedNetAddr::parse(&mut parser).unwrap();
+        assert_eq!(addr.addr, "127.0.0.1:18444".parse().unwrap());
+
+        let mut composer = ByteBufferComposer::new();
+        addr.append(&mut composer);
+        assert_eq!(composer.result(), encoded);
+        assert_eq!(PeerAddress::from(addr).to_string(), "127.0.0.1:18444");
+    }
+}
@@ -0,0 +1,355 @@
+use std::io;
+
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::raw_message::sha256;
+use crate::wire_protocol::transaction::Transaction;
+
+/// size of a serialized block header
+pub const BLOCK_HEADER_SIZE: usize = 80;
+
+/// Maximum size of a serialized block including witness data (see `MAX_BLOCK_SERIALIZED_SIZE` in bitcoin core)
+pub const MAX_BLOCK_SERIALIZED_SIZE: usize = 4_000_000;
+
+/// minimal size of a serialized transaction: version, empty input and output lists and lock time
+pub(super) const MIN_TRANSACTION_SIZE: usize = 4 + 1 + 1 + 4;
+
+/// Block header
+///
+/// size | field       | type     | description
+/// ---  | -----       | ----     | ------------
+/// 4    | version     | i32      | block version
+/// 32   | prev_block  | [u8; 32] | hash of the previous block (internal byte order)
+/// 32   | merkle_root | [u8; 32] | merkle root of the block's transactions (internal byte order)
+/// 4    | time        | u32      | block timestamp (unix time)
+/// 4    | bits        | u32      | difficulty target in compact format (`nBits`)
+/// 4    | nonce       | u32      | nonce used to satisfy the proof of work
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BlockHeader {
+    pub version: i32,
+    pub prev_block: [u8; 32],
+    pub merkle_root: [u8; 32],
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        Ok(BlockHeader {
+            version: parser.read_i32_le()?,
+            prev_block: parser.read(32)?.try_into().unwrap(),
+            merkle_root: parser.read(32)?.try_into().unwrap(),
+            time: parser.read_u32_le()?,
+            bits: parser.read_u32_le()?,
+            nonce: parser.read_u32_le()?,
+        })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.version.to_le_bytes());
+        composer.append(&self.prev_block);
+        composer.append(&self.merkle_root);
+        composer.append(&self.time.to_le_bytes());
+        composer.append(&self.bits.to_le_bytes());
+        composer.append(&self.nonce.to_le_bytes());
+    }
+
+    pub fn from_bytes(bytes: &[u8; BLOCK_HEADER_SIZE]) -> Self {
+        // cannot fail, the parser always has enough bytes
+        Self::parse(&mut ByteBufferParser::new(bytes)).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut composer = ByteBufferComposer::new();
+        self.append(&mut composer);
+        composer.result()
+    }
+
+    /// sha256(sha256(header)) in internal byte order
+    pub fn block_hash(&self) -> [u8; 32] {
+        sha256(&sha256(&self.to_bytes()))
+    }
+
+    /// the target encoded in `bits`
+    pub fn target(&self) -> PeerResult<Target> {
+        Target::from_compact(self.bits).map_err(|reason| self.invalid(reason))
+    }
+
+    /// Checks, that the block hash does not exceed the target encoded in `bits` and that the target does not exceed the
+    /// proof of work limit of `chain` (see `CheckProofOfWork` in bitcoin core)
+    pub fn validate_pow(&self, chain: Chain) -> PeerResult<()> {
+        let target = self.target()?;
+        if target > chain.pow_limit() {
+            return Err(self.invalid(format!("target {:#010x} exceeds the proof of work limit of {:?}", self.bits, chain)));
+        }
+        if Target::from_hash(&self.block_hash()) > target {
+            return Err(self.invalid(format!("hash exceeds target {:#010x}", self.bits)));
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, reason: String) -> PeerError {
+        PeerError::InvalidHeader { hash: display_hash(&self.block_hash()), reason }
+    }
+}
+
+/// Block
+///
+/// size | field        | type          | description
+/// ---  | -----        | ----          | ------------
+/// 80   | header       | block_header  | the block header
+/// ?    | transactions | Transaction[] | transactions with CompactSize count prefix, the first one is the coinbase
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub header: BlockHeader,
+    pub transactions: Vec<Transaction>,
+}
+
+impl Block {
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let header = BlockHeader::parse(parser)?;
+        let count = parser.read_length(parser.remaining() / MIN_TRANSACTION_SIZE)?;
+        let transactions = (0..count)
+            .map(|_| Transaction::parse(parser))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Block { header, transactions })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        self.header.append(composer);
+        composer.append_compact_size(self.transactions.len() as u64);
+        for tx in &self.transactions {
+            tx.append(composer, true);
+        }
+    }
+
+    pub fn block_hash(&self) -> [u8; 32] {
+        self.header.block_hash()
+    }
+
+    /// merkle root of the txids, `None` for a block without transactions
+    pub fn merkle_root(&self) -> Option<[u8; 32]> {
+        merkle_root(self.transactions.iter().map(|tx| tx.txid()).collect()).map(|(root, _)| root)
+    }
+
+    /// Checks, that the transactions match the merkle root of the header.
+    ///
+    /// Blocks with a duplicated sequence of transactions at the end are rejected as well, because they have the same
+    /// merkle root as the original block (CVE-2012-2459).
+    pub fn validate_merkle_root(&self) -> PeerResult<()> {
+        let invalid = |reason: &str| PeerError::InvalidBlock {
+            hash: display_hash(&self.block_hash()),
+            reason: reason.to_string(),
+        };
+        match merkle_root(self.transactions.iter().map(|tx| tx.txid()).collect()) {
+            None => Err(invalid("no transactions")),
+            Some((_, true)) => Err(invalid("duplicate transactions")),
+            Some((root, false)) if root != self.header.merkle_root => Err(invalid("merkle root mismatch")),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// Merkle root of the given hashes and whether the tree is mutated, i.e. contains two identical siblings
+/// (see `ComputeMerkleRoot` in bitcoin core). An odd hash at the end of a level is paired with itself.
+fn merkle_root(mut hashes: Vec<[u8; 32]>) -> Option<([u8; 32], bool)> {
+    let mut mutated = false;
+    while hashes.len() > 1 {
+        mutated |= hashes.chunks(2).any(|pair| pair.len() == 2 && pair[0] == pair[1]);
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    hashes.first().map(|root| (*root, mutated))
+}
+
+/// sha256(sha256(left || right)), i.e. the hash of an inner node of a merkle tree
+pub(super) fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut concatenated = left.to_vec();
+    concatenated.extend_from_slice(right);
+    sha256(&sha256(&concatenated))
+}
+
+/// hashes are displayed in reversed byte order
+pub fn display_hash(hash: &[u8; 32]) -> String {
+    hash.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 256-bit proof of work target, stored big-endian so that the derived ordering is the numeric one
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub [u8; 32]);
+
+impl Target {
+    /// Decodes the compact format used in the `bits` field of a block header (see `arith_uint256::SetCompact` in
+    /// bitcoin core): the highest byte is the size of the number in bytes, the lower three bytes are its most
+    /// significant bytes. Negative, zero and overflowing values are rejected.
+    pub fn from_compact(bits: u32) -> Result<Self, String> {
+        let size = (bits >> 24) as usize;
+        let mantissa = bits & 0x007fffff;
+        if bits & 0x00800000 != 0 && mantissa != 0 {
+            return Err(format!("negative target {:#010x}", bits));
+        }
+        let mut target = [0u8; 32];
+        for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+            // position of the byte counted from the least significant end; bytes below zero are shifted out
+            match (size + 2 - i).checked_sub(3) {
+                Some(pos) if pos < 32 => target[31 - pos] = *byte,
+                Some(_) if *byte != 0 => return Err(format!("target {:#010x} exceeds 256 bits", bits)),
+                _ => {}
+            }
+        }
+        if target == [0u8; 32] {
+            return Err(format!("zero target {:#010x}", bits));
+        }
+        Ok(Target(target))
+    }
+
+    /// interprets a hash in internal byte order as number
+    pub fn from_hash(hash: &[u8; 32]) -> Self {
+        let mut target = *hash;
+        target.reverse();
+        Target(target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Chain::Mainnet.genesis_header();
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), BLOCK_HEADER_SIZE);
+        assert_eq!(&bytes[..4], &hex!("01000000"));
+        assert_eq!(&bytes[68..], &hex!("29ab5f49" "ffff001d" "1dac2b7c"));
+
+        let mut parser = ByteBufferParser::new(&bytes);
+        assert_eq!(BlockHeader::parse(&mut parser).unwrap(), header);
+        assert_eq!(parser.remaining(), 0);
+        assert_eq!(BlockHeader::from_bytes(&bytes.try_into().unwrap()), header);
+    }
+
+    #[rstest]
+    #[case(Chain::Mainnet)]
+    #[case(Chain::Testnet3)]
+    #[case(Chain::Testnet4)]
+    #[case(Chain::signet())]
+    #[case(Chain::Regtest)]
+    fn test_genesis_header_matches_genesis_hash(#[case] chain: Chain) {
+        let header = chain.genesis_header();
+        assert_eq!(header.block_hash(), chain.genesis_hash());
+        header.validate_pow(chain).unwrap();
+    }
+
+    #[rstest]
+    #[case(0x1d00ffff, hex!("00000000ffff0000000000000000000000000000000000000000000000000000"))]
+    #[case(0x207fffff, hex!("7fffff0000000000000000000000000000000000000000000000000000000000"))]
+    #[case(0x1e0377ae, hex!("00000377ae000000000000000000000000000000000000000000000000000000"))]
+    #[case(0x03123456, hex!("0000000000000000000000000000000000000000000000000000000000123456"))]
+    #[case(0x02123456, hex!("0000000000000000000000000000000000000000000000000000000000001234"))]
+    #[case(0x22000001, hex!("0100000000000000000000000000000000000000000000000000000000000000"))]
+    fn test_target_from_compact(#[case] bits: u32, #[case] expected: [u8; 32]) {
+        assert_eq!(Target::from_compact(bits).unwrap(), Target(expected));
+    }
+
+    #[rstest]
+    #[case::negative(0x04923456)]
+    #[case::zero(0x1d000000)]
+    #[case::overflow(0x23000001)]
+    #[case::overflow_of_mantissa(0x22000100)]
+    fn test_invalid_compact_target(#[case] bits: u32) {
+        assert!(Target::from_compact(bits).is_err());
+    }
+
+    #[test]
+    fn test_insufficient_pow_is_rejected() {
+        let mut header = Chain::Mainnet.genesis_header();
+        header.nonce += 1;
+        assert!(matches!(header.validate_pow(Chain::Mainnet), Err(PeerError::InvalidHeader { .. })));
+    }
+
+    #[test]
+    fn test_target_above_pow_limit_is_rejected() {
+        // a mainnet header claiming the regtest difficulty
+        let mut header = BlockHeader { bits: 0x207fffff, ..Chain::Mainnet.genesis_header() };
+        while header.validate_pow(Chain::Regtest).is_err() {
+            header.nonce += 1;
+        }
+        match header.validate_pow(Chain::Mainnet) {
+            Err(PeerError::InvalidHeader { reason, .. }) => assert!(reason.contains("proof of work limit"), "{}", reason),
+            other => panic!("expected an invalid header error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_display_hash() {
+        assert_eq!(display_hash(&Chain::Mainnet.genesis_hash()), "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+    }
+
+    /// regtest block, which contains the genesis coinbase together with a copy of the BIP143 example transaction
+    fn block() -> Block {
+        let coinbase = Transaction::from_bytes(&hex!(
+            "01000000" "01" "0000000000000000000000000000000000000000000000000000000000000000" "ffffffff" "03" "510101" "ffffffff"
+            "01" "00f2052a01000000" "01" "51" "00000000")).unwrap();
+        let spend = Transaction::from_bytes(&hex!(
+            "01000000" "0001" "01" "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a" "01000000" "00" "ffffffff"
+            "01" "9093510d00000000" "01" "51" "01" "01" "01" "11000000")).unwrap();
+        let mut block = Block {
+            header: Chain::Regtest.genesis_header(),
+            transactions: vec![coinbase, spend],
+        };
+        block.header.prev_block = Chain::Regtest.genesis_hash();
+        block.header.merkle_root = block.merkle_root().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_block_round_trip() {
+        let block = block();
+        let mut composer = ByteBufferComposer::new();
+        block.append(&mut composer);
+        let bytes = composer.result();
+
+        let mut parser = ByteBufferParser::new(&bytes);
+        assert_eq!(Block::parse(&mut parser).unwrap(), block);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let block = block();
+        block.validate_merkle_root().unwrap();
+
+        let mut concatenated = block.transactions[0].txid().to_vec();
+        concatenated.extend(block.transactions[1].txid());
+        assert_eq!(block.merkle_root(), Some(sha256(&sha256(&concatenated))));
+
+        // a single transaction is the root itself
+        assert_eq!(merkle_root(vec![[1; 32]]), Some(([1; 32], false)));
+        assert_eq!(merkle_root(vec![]), None);
+    }
+
+    #[test]
+    fn test_merkle_root_mismatch_is_rejected() {
+        let mut block = block();
+        block.transactions.swap(0, 1);
+        assert!(matches!(block.validate_merkle_root(), Err(PeerError::InvalidBlock { .. })));
+    }
+
+    #[test]
+    fn test_duplicated_transactions_are_rejected() {
+        let mut block = block();
+        let coinbase = block.transactions[0].clone();
+        block.transactions.push(coinbase.clone());
+        block.transactions.push(coinbase);
+        block.header.merkle_root = block.merkle_root().unwrap();
+        assert!(matches!(block.validate_merkle_root(), Err(PeerError::InvalidBlock { .. })));
+    }
+}
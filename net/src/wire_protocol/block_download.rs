@@ -0,0 +1,240 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::{Block, display_hash};
+use crate::wire_protocol::inventory::{Inventory, InventoryType, MAX_INV_SZ};
+use crate::wire_protocol::messages::{GetDataMessage, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::Chain;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockDownload {
+    /// received blocks in the order they were requested
+    pub blocks: Vec<Block>,
+    /// hashes of the requested blocks, which the remote node does not have
+    pub not_found: Vec<[u8; 32]>,
+}
+
+/// Block download (after the handshake):
+///
+/// - send __getdata__ for the requested block hashes (MSG_WITNESS_BLOCK)
+/// - expect a __block__ message for each of them, or a __notfound__ message listing the missing ones
+///
+/// Blocks may arrive in any order. Each block has to match its merkle root; unrequested blocks are ignored.
+/// The topic is finished, once every requested block was either received or reported as not found.
+///
+/// Bitcoin core does not answer requests for blocks it doesn't have, so the topic fails with [PeerError::Timeout],
+/// when no requested block arrives within `response_timeout`.
+pub struct BlockDownloadConversationTopic {
+    chain: Chain,
+    response_timeout: Duration,
+    /// whether a requested block arrived since the last tick
+    progressed: bool,
+    requested: Vec<[u8; 32]>,
+    pending: HashSet<[u8; 32]>,
+    received: HashMap<[u8; 32], Block>,
+    not_found: Vec<[u8; 32]>,
+}
+
+impl BlockDownloadConversationTopic {
+    pub fn new(chain: Chain, block_hashes: Vec<[u8; 32]>, response_timeout: Duration) -> Self {
+        BlockDownloadConversationTopic {
+            chain,
+            response_timeout,
+            progressed: false,
+            pending: block_hashes.iter().copied().collect(),
+            requested: block_hashes,
+            received: HashMap::new(),
+            not_found: vec![],
+        }
+    }
+
+    fn progress(&self) -> ConversationAction {
+        ConversationAction {
+            messages: vec![],
+            topic_finished: self.pending.is_empty(),
+        }
+    }
+}
+
+impl ConversationTopicHandler for BlockDownloadConversationTopic {
+    type Outcome = BlockDownload;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        let inventory: Vec<Inventory> = self.requested.iter()
+            .map(|hash| Inventory::new(InventoryType::WitnessBlock, *hash))
+            .collect();
+        ConversationAction {
+            messages: inventory
+                .chunks(MAX_INV_SZ)
+                .map(|chunk| ProtocolMessage::GetData(GetDataMessage::new(self.chain, chunk.to_vec())))
+                .collect(),
+            topic_finished: self.pending.is_empty(),
+        }
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::Block(m) => {
+                let hash = m.block.block_hash();
+                if !self.pending.remove(&hash) {
+                    log::debug!("ignoring unrequested block {}", display_hash(&hash));
+                    return Ok(ConversationAction::nop());
+                }
+                m.block.validate_merkle_root()?;
+                self.received.insert(hash, m.block);
+                self.progressed = true;
+                Ok(self.progress())
+            }
+            ProtocolMessage::NotFound(m) => {
+                for inv in m.inventory.iter().filter(|inv| inv.inv_type.is_block()) {
+                    if self.pending.remove(&inv.hash) {
+                        log::debug!("block {} not found", display_hash(&inv.hash));
+                        self.not_found.push(inv.hash);
+                    }
+                }
+                Ok(self.progress())
+            }
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.response_timeout)
+    }
+
+    fn on_tick(&mut self, _now: Instant) -> PeerResult<ConversationAction> {
+        if !std::mem::take(&mut self.progressed) {
+            let missing: Vec<String> = self.requested.iter()
+                .filter(|hash| self.pending.contains(*hash))
+                .map(display_hash)
+                .collect();
+            return Err(PeerError::Timeout(format!("no block received within {:?}, missing {}", self.response_timeout, missing.join(", "))));
+        }
+        Ok(ConversationAction::nop())
+    }
+
+    fn outcome(mut self) -> PeerResult<BlockDownload> {
+        let blocks = self.requested.iter()
+            .filter_map(|hash| self.received.remove(hash))
+            .collect();
+        Ok(BlockDownload { blocks, not_found: self.not_found })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::block::BlockHeader;
+    use crate::wire_protocol::messages::{BlockMessage, NotFoundMessage};
+    use crate::wire_protocol::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// regtest block on top of genesis, containing a coinbase transaction with the given height
+    fn block(height: u8) -> Block {
+        let coinbase = Transaction {
+            version: 1,
+            inputs: vec![TxIn {
+                previous_output: OutPoint::NULL,
+                script_sig: vec![0x01, height],
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            outputs: vec![TxOut { value: 50_0000_0000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                prev_block: Chain::Regtest.genesis_hash(),
+                ..Chain::Regtest.genesis_header()
+            },
+            transactions: vec![coinbase],
+        };
+        block.header.merkle_root = block.merkle_root().unwrap();
+        block
+    }
+
+    fn block_message(block: &Block) -> ProtocolMessage {
+        ProtocolMessage::Block(BlockMessage::new(Chain::Regtest, block.clone()))
+    }
+
+    #[test]
+    fn test_blocks_are_returned_in_requested_order() {
+        let blocks = [block(1), block(2), block(3)];
+        let hashes = blocks.iter().map(|b| b.block_hash()).collect();
+        let mut topic = BlockDownloadConversationTopic::new(Chain::Regtest, hashes, TIMEOUT);
+
+        match &topic.initial_action().messages[..] {
+            [ProtocolMessage::GetData(m)] => {
+                assert_eq!(m.inventory.len(), 3);
+                assert!(m.inventory.iter().all(|inv| inv.inv_type == InventoryType::WitnessBlock));
+            }
+            other => panic!("expected a getdata message, got {:?}", other),
+        }
+
+        assert!(!topic.on_message(block_message(&blocks[2])).unwrap().topic_finished);
+        // unrequested
+        assert!(!topic.on_message(block_message(&block(4))).unwrap().topic_finished);
+        assert!(!topic.on_message(block_message(&blocks[0])).unwrap().topic_finished);
+        assert!(topic.on_message(block_message(&blocks[1])).unwrap().topic_finished);
+
+        let download = topic.outcome().unwrap();
+        assert_eq!(download.blocks, blocks.to_vec());
+        assert!(download.not_found.is_empty());
+    }
+
+    #[test]
+    fn test_not_found_blocks_finish_the_topic() {
+        let found = block(1);
+        let missing = [7; 32];
+        let mut topic = BlockDownloadConversationTopic::new(Chain::Regtest, vec![missing, found.block_hash()], TIMEOUT);
+        topic.initial_action();
+
+        assert!(!topic.on_message(block_message(&found)).unwrap().topic_finished);
+        let not_found = NotFoundMessage::new(Chain::Regtest, vec![Inventory::new(InventoryType::WitnessBlock, missing)]);
+        assert!(topic.on_message(ProtocolMessage::NotFound(not_found)).unwrap().topic_finished);
+
+        let download = topic.outcome().unwrap();
+        assert_eq!(download.blocks, vec![found]);
+        assert_eq!(download.not_found, vec![missing]);
+    }
+
+    #[test]
+    fn test_block_not_matching_its_merkle_root_is_rejected() {
+        let mut tampered = block(1);
+        tampered.transactions[0].outputs[0].value += 1;
+        let mut topic = BlockDownloadConversationTopic::new(Chain::Regtest, vec![tampered.block_hash()], TIMEOUT);
+        topic.initial_action();
+        let err = topic.on_message(block_message(&tampered)).unwrap_err();
+        assert!(matches!(err, PeerError::InvalidBlock { .. }));
+    }
+
+    #[test]
+    fn test_stalled_download_times_out() {
+        let found = block(1);
+        let missing = block(2);
+        let hashes = vec![found.block_hash(), missing.block_hash()];
+        let mut topic = BlockDownloadConversationTopic::new(Chain::Regtest, hashes, TIMEOUT);
+        topic.initial_action();
+        assert_eq!(topic.tick_interval(), Some(TIMEOUT));
+
+        topic.on_message(block_message(&found)).unwrap();
+        assert!(topic.on_tick(Instant::now()).is_ok());
+        match topic.on_tick(Instant::now()) {
+            Err(PeerError::Timeout(msg)) => {
+                assert!(msg.contains(&display_hash(&missing.block_hash())), "{}", msg);
+                assert!(!msg.contains(&display_hash(&found.block_hash())), "{}", msg);
+            }
+            other => panic!("expected a timeout, got {:?}", other.map(|_| ())),
+        }
+    }
+}
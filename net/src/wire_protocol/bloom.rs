@@ -0,0 +1,387 @@
+use std::io;
+
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::transaction::{OutPoint, Transaction};
+
+/// Maximum size of the bit field of a bloom filter in bytes (BIP37)
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// Maximum number of hash functions of a bloom filter (BIP37)
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Maximum size of an element added by __filteradd__, i.e. the maximum size of a script push
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+/// multiplier of the hash function index in the seed of MurmurHash3 (BIP37)
+const SEED_MULTIPLIER: u32 = 0xfba4c795;
+
+const LN2: f64 = std::f64::consts::LN_2;
+const LN2_SQUARED: f64 = LN2 * LN2;
+
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+
+/// How the remote node updates the filter, when an output matches (BIP37)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BloomUpdate {
+    /// the filter is not updated
+    None = 0,
+    /// the outpoint of every matching output is added
+    All = 1,
+    /// the outpoint is only added for matching pay-to-pubkey and bare multisig outputs
+    P2PubKeyOnly = 2,
+}
+
+impl BloomUpdate {
+    /// the update mode encoded in the lower two bits of `flags`
+    fn from_flags(flags: u8) -> Self {
+        match flags & 0x03 {
+            1 => BloomUpdate::All,
+            2 => BloomUpdate::P2PubKeyOnly,
+            _ => BloomUpdate::None,
+        }
+    }
+}
+
+/// Bloom filter of transactions relevant to an SPV client (BIP37)
+///
+/// Elements are hashed by `hash_funcs` instances of MurmurHash3, seeded with `i * 0xfba4c795 + tweak`, to bits in
+/// `data`. A transaction matches, if its txid, an outpoint it spends, or any data pushed by one of its scripts is
+/// contained in the filter.
+///
+/// size | field      | type      | description
+/// ---  | -----      | ----      | ------------
+/// ?    | data       | var_bytes | the bit field (max: 36000 bytes)
+/// 4    | hash_funcs | u32       | number of hash functions (max: 50)
+/// 4    | tweak      | u32       | random value added to the seeds of the hash functions
+/// 1    | flags      | u8        | update mode in the lower two bits, see [Self::update]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    pub data: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    /// as received, so that undefined bits survive a round trip
+    pub flags: u8,
+}
+
+impl BloomFilter {
+    /// Empty filter sized for `elements` elements at the given false positive rate, within the limits of BIP37
+    /// (see `CBloomFilter` in bitcoin core)
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, update: BloomUpdate) -> Self {
+        let elements = elements.max(1);
+        let bits = (-1.0 / LN2_SQUARED * elements as f64 * fp_rate.ln()) as usize;
+        let size = bits.min(MAX_BLOOM_FILTER_SIZE * 8) / 8;
+        let hash_funcs = ((size * 8 / elements) as f64 * LN2) as u32;
+        BloomFilter {
+            data: vec![0; size],
+            hash_funcs: hash_funcs.min(MAX_HASH_FUNCS),
+            tweak,
+            flags: update as u8,
+        }
+    }
+
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let data = parser.read_var_bytes(MAX_BLOOM_FILTER_SIZE)?.to_vec();
+        let hash_funcs = parser.read_u32_le()?;
+        if hash_funcs > MAX_HASH_FUNCS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("too many hash functions: {}", hash_funcs)));
+        }
+        let tweak = parser.read_u32_le()?;
+        let flags = parser.read(1)?[0];
+        Ok(BloomFilter { data, hash_funcs, tweak, flags })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append_var_bytes(&self.data);
+        composer.append(&self.hash_funcs.to_le_bytes());
+        composer.append(&self.tweak.to_le_bytes());
+        composer.append(&[self.flags]);
+    }
+
+    /// how the remote node updates the filter, when an output matches
+    pub fn update(&self) -> BloomUpdate {
+        BloomUpdate::from_flags(self.flags)
+    }
+
+    pub fn insert(&mut self, element: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+        for i in 0..self.hash_funcs {
+            let bit = self.bit_index(i, element);
+            self.data[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// whether `element` is contained in the filter; false positives are possible
+    pub fn contains(&self, element: &[u8]) -> bool {
+        !self.data.is_empty() && (0..self.hash_funcs).all(|i| {
+            let bit = self.bit_index(i, element);
+            self.data[bit / 8] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// adds an outpoint serialized as txid and little endian output index
+    pub fn insert_outpoint(&mut self, outpoint: &OutPoint) {
+        self.insert(&serialize_outpoint(outpoint));
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&serialize_outpoint(outpoint))
+    }
+
+    /// Whether `tx` matches the filter, as checked by the remote node (see `IsRelevantAndUpdate` in bitcoin core).
+    ///
+    /// Outpoints of matching outputs are added to the filter according to [Self::update], so that transactions
+    /// spending them match as well.
+    pub fn is_relevant_and_update(&mut self, tx: &Transaction) -> bool {
+        let txid = tx.txid();
+        let mut found = self.contains(&txid);
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if pushed_data(&output.script_pubkey).any(|data| self.contains(data)) {
+                found = true;
+                let update = match self.update() {
+                    BloomUpdate::None => false,
+                    BloomUpdate::All => true,
+                    BloomUpdate::P2PubKeyOnly => is_pay_to_pubkey_or_multisig(&output.script_pubkey),
+                };
+                if update {
+                    self.insert_outpoint(&OutPoint { txid, vout: vout as u32 });
+                }
+            }
+        }
+        found || tx.inputs.iter().any(|input| {
+            self.contains_outpoint(&input.previous_output) || pushed_data(&input.script_sig).any(|data| self.contains(data))
+        })
+    }
+
+    fn bit_index(&self, i: u32, element: &[u8]) -> usize {
+        let seed = i.wrapping_mul(SEED_MULTIPLIER).wrapping_add(self.tweak);
+        murmur_hash3(seed, element) as usize % (self.data.len() * 8)
+    }
+}
+
+fn serialize_outpoint(outpoint: &OutPoint) -> [u8; 36] {
+    let mut bytes = [0; 36];
+    bytes[..32].copy_from_slice(&outpoint.txid);
+    bytes[32..].copy_from_slice(&outpoint.vout.to_le_bytes());
+    bytes
+}
+
+/// MurmurHash3 (x86, 32 bit)
+fn murmur_hash3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        h ^= mix(u32::from_le_bytes(block.try_into().unwrap()));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        h ^= mix(tail.iter().rev().fold(0, |k, &b| (k << 8) | b as u32));
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+/// non-empty data pushed by the operations of `script`, up to the first malformed push
+fn pushed_data(script: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut parser = ByteBufferParser::new(script);
+    std::iter::from_fn(move || loop {
+        let opcode = parser.read(1).ok()?[0];
+        let len = match opcode {
+            0x01..OP_PUSHDATA1 => opcode as usize,
+            OP_PUSHDATA1 => parser.read(1).ok()?[0] as usize,
+            OP_PUSHDATA2 => u16::from_le_bytes(parser.read(2).ok()?.try_into().unwrap()) as usize,
+            OP_PUSHDATA4 => parser.read_u32_le().ok()? as usize,
+            _ => continue,
+        };
+        let data = parser.read(len).ok()?;
+        if !data.is_empty() {
+            return Some(data);
+        }
+    })
+}
+
+/// `<pubkey> OP_CHECKSIG` or `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`
+fn is_pay_to_pubkey_or_multisig(script: &[u8]) -> bool {
+    let is_pubkey_len = |len: usize| len == 33 || len == 65;
+    let is_small_int = |op: u8| (OP_1..=OP_16).contains(&op);
+    match script {
+        [len, key @ .., OP_CHECKSIG] => *len as usize == key.len() && is_pubkey_len(key.len()),
+        [m, keys @ .., n, OP_CHECKMULTISIG] if is_small_int(*m) && is_small_int(*n) && m <= n => {
+            let mut keys = keys;
+            let mut count = 0;
+            while let Some((&len, rest)) = keys.split_first() {
+                if !is_pubkey_len(len as usize) || rest.len() < len as usize {
+                    return false;
+                }
+                keys = &rest[len as usize..];
+                count += 1;
+            }
+            count == (n - OP_1 + 1) as usize
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use crate::wire_protocol::transaction::{TxIn, TxOut};
+
+    use super::*;
+
+    /// test vectors of bitcoin core
+    #[rstest]
+    #[case(0x00000000, &[], 0x00000000)]
+    #[case(0xfba4c795, &[], 0x6a396f08)]
+    #[case(0xffffffff, &[], 0x81f16f39)]
+    #[case(0x00000000, &hex!("00"), 0x514e28b7)]
+    #[case(0xfba4c795, &hex!("00"), 0xea3f0b17)]
+    #[case(0x00000000, &hex!("ff"), 0xfd6cf10d)]
+    #[case(0x00000000, &hex!("0011"), 0x16c6b7ab)]
+    #[case(0x00000000, &hex!("001122"), 0x8eb51c3d)]
+    #[case(0x00000000, &hex!("00112233"), 0xb4471bf8)]
+    #[case(0x00000000, &hex!("0011223344"), 0xe2301fa8)]
+    #[case(0x00000000, &hex!("001122334455"), 0xfc2e4a15)]
+    #[case(0x00000000, &hex!("00112233445566"), 0xb074502c)]
+    #[case(0x00000000, &hex!("0011223344556677"), 0x8034d2a0)]
+    #[case(0x00000000, &hex!("001122334455667788"), 0xb4698def)]
+    fn test_murmur_hash3(#[case] seed: u32, #[case] data: &[u8], #[case] expected: u32) {
+        assert_eq!(murmur_hash3(seed, data), expected);
+    }
+
+    /// test vectors of bitcoin core
+    #[rstest]
+    #[case(0, &hex!("03614e9b050000000000000001"))]
+    #[case(2147483649, &hex!("03ce4299050000000100008001"))]
+    fn test_insert_and_serialize(#[case] tweak: u32, #[case] expected: &[u8]) {
+        let mut filter = BloomFilter::new(3, 0.01, tweak, BloomUpdate::All);
+        filter.insert(&hex!("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+        assert!(filter.contains(&hex!("99108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        assert!(!filter.contains(&hex!("19108ad8ed9bb6274d3980bab5a85c048f0950c8")));
+        filter.insert(&hex!("b5a2c786d9ef4658287ced5914b37a1b4aa32eee"));
+        filter.insert(&hex!("b9300670b4c5366e95b2699e8b18bc75e5f729c5"));
+
+        let mut composer = ByteBufferComposer::new();
+        filter.append(&mut composer);
+        assert_eq!(composer.result(), expected);
+
+        let mut parser = ByteBufferParser::new(expected);
+        assert_eq!(BloomFilter::parse(&mut parser).unwrap(), filter);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let filter = BloomFilter::new(1_000_000, 0.0001, 0, BloomUpdate::None);
+        assert_eq!(filter.data.len(), MAX_BLOOM_FILTER_SIZE);
+        assert_eq!(BloomFilter::new(1, 1e-30, 0, BloomUpdate::None).hash_funcs, MAX_HASH_FUNCS);
+
+        let too_many_hash_funcs = hex!("0100" "33000000" "00000000" "00");
+        assert!(BloomFilter::parse(&mut ByteBufferParser::new(&too_many_hash_funcs)).is_err());
+    }
+
+    #[test]
+    fn test_undefined_flags_survive_a_round_trip() {
+        let payload = hex!("0100" "01000000" "00000000" "86");
+        let mut parser = ByteBufferParser::new(&payload);
+        let filter = BloomFilter::parse(&mut parser).unwrap();
+        assert_eq!(filter.update(), BloomUpdate::P2PubKeyOnly);
+
+        let mut composer = ByteBufferComposer::new();
+        filter.append(&mut composer);
+        assert_eq!(composer.result(), payload);
+    }
+
+    #[test]
+    fn test_empty_filter_matches_nothing() {
+        let mut filter = BloomFilter { data: vec![], hash_funcs: 0, tweak: 0, flags: BloomUpdate::All as u8 };
+        filter.insert(b"abc");
+        assert!(!filter.contains(b"abc"));
+    }
+
+    fn tx(script_pubkey: Vec<u8>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint { txid: [1; 32], vout: 0 },
+                script_sig: vec![],
+                sequence: u32::MAX,
+                witness: vec![],
+            }],
+            outputs: vec![TxOut { value: 1000, script_pubkey }],
+            lock_time: 0,
+        }
+    }
+
+    #[rstest]
+    #[case(BloomUpdate::None, &[0x76, 0xa9, 0x14, 0xaa, 0x88, 0xac], 20, false)]
+    #[case(BloomUpdate::All, &[0x76, 0xa9, 0x14, 0xaa, 0x88, 0xac], 20, true)]
+    #[case(BloomUpdate::P2PubKeyOnly, &[0x76, 0xa9, 0x14, 0xaa, 0x88, 0xac], 20, false)]
+    #[case(BloomUpdate::P2PubKeyOnly, &[0x21, 0xaa, 0xac], 33, true)]
+    #[case(BloomUpdate::P2PubKeyOnly, &[0x51, 0x21, 0xaa, 0x51, 0xae], 33, true)]
+    fn test_matching_output_updates_the_filter(#[case] flags: BloomUpdate, #[case] template: &[u8], #[case] element_len: usize,
+                                               #[case] updated: bool) {
+        // 0xaa in the template stands for the watched element, a pubkey hash or a pubkey
+        let element = vec![0x02; element_len];
+        let script: Vec<u8> = template.iter()
+            .flat_map(|&op| match op {
+                0xaa => element.clone(),
+                op => vec![op],
+            })
+            .collect();
+        let mut filter = BloomFilter::new(10, 0.000001, 5, flags);
+        filter.insert(&element);
+
+        let funding = tx(script);
+        assert!(filter.is_relevant_and_update(&funding));
+        let mut spending = tx(vec![0x51]);
+        spending.inputs[0].previous_output = OutPoint { txid: funding.txid(), vout: 0 };
+        assert_eq!(filter.is_relevant_and_update(&spending), updated);
+    }
+
+    #[test]
+    fn test_relevant_transactions() {
+        let mut filter = BloomFilter::new(10, 0.000001, 0, BloomUpdate::None);
+        let unrelated = tx(vec![0x51]);
+        assert!(!filter.is_relevant_and_update(&unrelated));
+
+        filter.insert(&unrelated.txid());
+        assert!(filter.is_relevant_and_update(&unrelated));
+
+        let mut filter = BloomFilter::new(10, 0.000001, 0, BloomUpdate::None);
+        filter.insert_outpoint(&OutPoint { txid: [1; 32], vout: 0 });
+        assert!(filter.is_relevant_and_update(&unrelated));
+
+        let mut filter = BloomFilter::new(10, 0.000001, 0, BloomUpdate::None);
+        let mut signed = tx(vec![0x51]);
+        signed.inputs[0].script_sig = hex!("4c03abcdef").to_vec();
+        filter.insert(&hex!("abcdef"));
+        assert!(filter.is_relevant_and_update(&signed));
+    }
+
+    #[test]
+    fn test_pushed_data() {
+        let script = hex!("00" "02abcd" "51" "4c01ef" "4c00" "4d0200aabb" "4e01000000cc" "ac" "05aa");
+        let pushes: Vec<&[u8]> = pushed_data(&script).collect();
+        assert_eq!(pushes, vec![&hex!("abcd")[..], &hex!("ef"), &hex!("aabb"), &hex!("cc")]);
+    }
+}
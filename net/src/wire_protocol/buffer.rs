@@ -4,6 +4,9 @@ use std::net::{IpAddr, SocketAddr};
 
 use crate::wire_protocol::node::NodeServiceSet;
 
+/// Upper bound for any length prefix (see `MAX_SIZE` in bitcoin core's serialize.h)
+pub(super) const MAX_SIZE: usize = 0x02000000;
+
 pub(super) struct ByteBufferParser<'a> {
     buffer: &'a [u8],
     pos: usize,
@@ -23,12 +26,6 @@ impl<'a> ByteBufferParser<'a> {
         self.buffer.len() - self.pos
     }
 
-    pub fn skip_bytes(&mut self, count: usize) -> io::Result<()> {
-        self.eof_check(count)?;
-        self.pos += count;
-        Ok(())
-    }
-
     pub fn read(&mut self, size: usize) -> io::Result<&'a [u8]> {
         self.eof_check(size)?;
         let range = self.pos..self.pos + size;
@@ -66,11 +63,55 @@ impl<'a> ByteBufferParser<'a> {
         ))
     }
 
+    /// CompactSize unsigned integer (aka var_int).
+    /// Only the canonical (shortest possible) encoding is accepted.
+    ///
+    /// value           | size | format
+    /// ---             | ---  | ---
+    /// < 0xFD          | 1    | u8
+    /// <= 0xFFFF       | 3    | 0xFD followed by the value as u16
+    /// <= 0xFFFF_FFFF  | 5    | 0xFE followed by the value as u32
+    /// > 0xFFFF_FFFF   | 9    | 0xFF followed by the value as u64
+    pub fn read_compact_size(&mut self) -> io::Result<u64> {
+        let (value, min_value) = match self.read(1)?[0] {
+            0xFD => (u16::from_le_bytes(self.read(2)?.try_into().unwrap()) as u64, 0xFD),
+            0xFE => (u32::from_le_bytes(self.read(4)?.try_into().unwrap()) as u64, 0x1_0000),
+            0xFF => (self.read_u64_le()?, 0x1_0000_0000),
+            v => (v as u64, 0),
+        };
+        if value < min_value {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("non-canonical CompactSize encoding of value {}", value))
+            );
+        }
+        Ok(value)
+    }
+
+    /// CompactSize used as a length prefix of a following vector, limited by `max_len` and [MAX_SIZE]
+    pub fn read_length(&mut self, max_len: usize) -> io::Result<usize> {
+        let len = self.read_compact_size()?;
+        if len > max_len.min(MAX_SIZE) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("length {} exceeds the limit of {}", len, max_len.min(MAX_SIZE)))
+            );
+        }
+        Ok(len as usize)
+    }
+
+    /// byte vector with CompactSize length prefix
+    pub fn read_var_bytes(&mut self, max_len: usize) -> io::Result<&'a [u8]> {
+        let len = self.read_length(max_len)?;
+        self.read(len)
+    }
+
     /// net address without time field
     pub fn parse_net_addr(&mut self) -> io::Result<(NodeServiceSet, SocketAddr)> {
         let services_mask = self.read_u64_le()?;
         let ip: [u8; 16] = self.read(16)?.try_into().unwrap();
-        let ip = IpAddr::from(ip);
+        // IPv4 addresses are transmitted as IPv4-mapped IPv6 addresses
+        let ip = IpAddr::from(ip).to_canonical();
         let port = self.read_u16_be()?;
         Ok((
             NodeServiceSet::from_bitmask(services_mask),
@@ -108,6 +149,31 @@ impl ByteBufferComposer {
         self.buffer.extend_from_slice(bytes);
     }
 
+    /// CompactSize unsigned integer (aka var_int) in canonical encoding
+    pub fn append_compact_size(&mut self, value: u64) {
+        match value {
+            0..=0xFC => self.append(&[value as u8]),
+            0xFD..=0xFFFF => {
+                self.append(&[0xFD]);
+                self.append(&(value as u16).to_le_bytes());
+            }
+            0x1_0000..=0xFFFF_FFFF => {
+                self.append(&[0xFE]);
+                self.append(&(value as u32).to_le_bytes());
+            }
+            _ => {
+                self.append(&[0xFF]);
+                self.append(&value.to_le_bytes());
+            }
+        }
+    }
+
+    /// byte vector with CompactSize length prefix
+    pub fn append_var_bytes(&mut self, bytes: &[u8]) {
+        self.append_compact_size(bytes.len() as u64);
+        self.append(bytes);
+    }
+
     /// net address struct without time field
     pub fn append_net_addr(&mut self, service: &NodeServiceSet, addr: &SocketAddr) {
         self.append(&service.as_bitmask().to_le_bytes());
@@ -120,18 +186,33 @@ impl ByteBufferComposer {
     }
 }
 
+/// Receive buffer of a connection.
+///
+/// Grows on demand, so that messages of any size up to the connection's maximum message size can be assembled
+/// from an arbitrary number of socket reads. Partially received messages stay in the buffer until they are complete.
+#[derive(Default)]
 pub struct IOBuffer {
-    buffer: [u8; 1024],
+    buffer: Vec<u8>,
     /// length of valid content (content starts at index 0)
     mark: usize,
 }
 
 impl IOBuffer {
+    /// minimum number of writable bytes exposed by [Self::expose_writable_part]
+    const READ_CHUNK_SIZE: usize = 16 * 1024;
+    /// buffers grown beyond this size are shrunk again, once their content was consumed
+    const RETAINED_CAPACITY: usize = 256 * 1024;
+
     pub fn content(&self) -> &[u8] {
         &self.buffer[..self.mark]
     }
 
+    /// Exposes the free space behind the content. Grows the buffer beforehand, if less than [Self::READ_CHUNK_SIZE]
+    /// bytes are free.
     pub fn expose_writable_part(&mut self) -> &mut [u8] {
+        if self.buffer.len() - self.mark < Self::READ_CHUNK_SIZE {
+            self.buffer.resize(self.mark + Self::READ_CHUNK_SIZE, 0);
+        }
         &mut self.buffer[self.mark..]
     }
 
@@ -142,19 +223,111 @@ impl IOBuffer {
         self.mark += size;
     }
 
+    /// appends `bytes` to the content
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.buffer.truncate(self.mark);
+        self.buffer.extend_from_slice(bytes);
+        self.mark += bytes.len();
+    }
+
     /// removes `size` bytes from beginning of buffer. reduces `mark` by `size`
     pub fn shift_left(&mut self, size: usize) {
         assert!(size <= self.mark);
-        self.buffer.rotate_left(size);
+        self.buffer.copy_within(size..self.mark, 0);
         self.mark -= size;
+        if self.buffer.len() > Self::RETAINED_CAPACITY && self.mark <= Self::RETAINED_CAPACITY {
+            self.buffer.truncate(Self::RETAINED_CAPACITY);
+            self.buffer.shrink_to_fit();
+        }
     }
 }
 
-impl Default for IOBuffer {
-    fn default() -> Self {
-        IOBuffer {
-            buffer: [0_u8; 1024],
-            mark: 0,
-        }
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0, & hex ! ("00")[..])]
+    #[case(0xFC, & hex ! ("fc"))]
+    #[case(0xFD, & hex ! ("fdfd00"))]
+    #[case(0xFFFF, & hex ! ("fdffff"))]
+    #[case(0x1_0000, & hex ! ("fe00000100"))]
+    #[case(0xFFFF_FFFF, & hex ! ("feffffffff"))]
+    #[case(0x1_0000_0000, & hex ! ("ff0000000001000000"))]
+    #[case(u64::MAX, & hex ! ("ffffffffffffffffff"))]
+    fn test_compact_size_round_trip(#[case] value: u64, #[case] encoded: &[u8]) {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_compact_size(value);
+        assert_eq!(composer.result(), encoded);
+
+        let mut parser = ByteBufferParser::new(encoded);
+        assert_eq!(parser.read_compact_size().unwrap(), value);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[rstest]
+    #[case(& hex ! ("fd0000")[..])]
+    #[case(& hex ! ("fdfc00"))]
+    #[case(& hex ! ("fe00000000"))]
+    #[case(& hex ! ("feffff0000"))]
+    #[case(& hex ! ("ff0000000000000000"))]
+    #[case(& hex ! ("ffffffffff00000000"))]
+    fn test_compact_size_rejects_non_canonical(#[case] encoded: &[u8]) {
+        let err = ByteBufferParser::new(encoded).read_compact_size().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[rstest]
+    #[case(& hex ! ("fd")[..])]
+    #[case(& hex ! ("fe0000"))]
+    #[case(& hex ! ("ff00000000"))]
+    fn test_compact_size_truncated(#[case] encoded: &[u8]) {
+        let err = ByteBufferParser::new(encoded).read_compact_size().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_length_limits() {
+        assert_eq!(ByteBufferParser::new(&hex!("fd0001")).read_length(256).unwrap(), 256);
+        assert!(ByteBufferParser::new(&hex!("fd0101")).read_length(256).is_err());
+        // MAX_SIZE applies regardless of the requested limit
+        assert!(ByteBufferParser::new(&hex!("fe01000002")).read_length(usize::MAX).is_err());
+        assert_eq!(ByteBufferParser::new(&hex!("fe00000002")).read_length(usize::MAX).unwrap(), MAX_SIZE);
+    }
+
+    #[rstest]
+    #[case("", & hex ! ("00")[..])]
+    #[case("/Satoshi:24.0.1/", & hex ! ("102f5361746f7368693a32342e302e312f"))]
+    fn test_user_agent_round_trip(#[case] value: &str, #[case] encoded: &[u8]) {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_var_bytes(value.as_bytes());
+        assert_eq!(composer.result(), encoded);
+
+        let mut parser = ByteBufferParser::new(encoded);
+        assert_eq!(parser.read_var_bytes(256).unwrap(), value.as_bytes());
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_var_bytes_limits() {
+        let encoded = hex!("102f5361746f7368693a32342e302e312f");
+        assert!(ByteBufferParser::new(&encoded).read_var_bytes(15).is_err());
+        assert!(ByteBufferParser::new(&encoded[..10]).read_var_bytes(256).is_err());
+    }
+
+    #[test]
+    fn test_var_bytes_round_trip() {
+        let bytes = vec![0xAB_u8; 300];
+        let mut composer = ByteBufferComposer::new();
+        composer.append_var_bytes(&bytes);
+        let encoded = composer.result();
+        assert_eq!(&encoded[..3], &hex!("fd2c01"));
+
+        let mut parser = ByteBufferParser::new(&encoded);
+        assert_eq!(parser.read_var_bytes(MAX_SIZE).unwrap(), &bytes[..]);
+        assert_eq!(parser.remaining(), 0);
     }
 }
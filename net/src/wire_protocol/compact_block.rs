@@ -0,0 +1,539 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::{Block, BlockHeader, display_hash, MIN_TRANSACTION_SIZE};
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::raw_message::sha256;
+use crate::wire_protocol::transaction::Transaction;
+
+/// Compact block version we support: short ids are derived from wtxids and transactions include their witness data
+pub const COMPACT_BLOCK_VERSION: u64 = 2;
+
+/// size of a serialized short transaction id
+const SHORT_ID_SIZE: usize = 6;
+
+/// Transaction of a compact block, which is sent in full, because the receiver most likely does not know it yet
+/// (e.g. the coinbase)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrefilledTransaction {
+    /// position in the block
+    pub index: usize,
+    pub tx: Transaction,
+}
+
+/// Compact block (`HeaderAndShortIDs`, BIP152)
+///
+/// size | field            | type                   | description
+/// ---  | -----            | ----                   | ------------
+/// 80   | header           | block_header           | the block header
+/// 8    | nonce            | u64                    | salt of the short ids
+/// ?    | short ids length | var_int                | number of short ids
+/// 6x?  | short ids        | u48[]                  | short ids of the transactions, which are not prefilled, in block order
+/// ?    | prefilled length | var_int                | number of prefilled transactions
+/// ?    | prefilled txns   | PrefilledTransaction[] | differentially encoded index (var_int), followed by the transaction
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderAndShortIds {
+    pub header: BlockHeader,
+    pub nonce: u64,
+    pub short_ids: Vec<u64>,
+    /// in ascending order of their index
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl HeaderAndShortIds {
+    /// Compact representation of `block`, which prefills the coinbase only (like bitcoin core does)
+    pub fn from_block(block: &Block, nonce: u64) -> Self {
+        let mut compact = HeaderAndShortIds {
+            header: block.header,
+            nonce,
+            short_ids: vec![],
+            prefilled: vec![],
+        };
+        for (index, tx) in block.transactions.iter().enumerate() {
+            match index {
+                0 => compact.prefilled.push(PrefilledTransaction { index, tx: tx.clone() }),
+                _ => compact.short_ids.push(compact.short_id(&tx.wtxid())),
+            }
+        }
+        compact
+    }
+
+    /// number of transactions in the block
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Short id of a transaction: SipHash-2-4 of its wtxid, truncated to 6 bytes. The SipHash keys are the first two
+    /// little endian u64 values of sha256(header || nonce).
+    pub fn short_id(&self, wtxid: &[u8; 32]) -> u64 {
+        let mut composer = ByteBufferComposer::new();
+        self.header.append(&mut composer);
+        composer.append(&self.nonce.to_le_bytes());
+        let hash = sha256(&composer.result());
+        let k0 = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(hash[8..16].try_into().unwrap());
+        siphash_2_4(k0, k1, wtxid) & 0xffff_ffff_ffff
+    }
+
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let header = BlockHeader::parse(parser)?;
+        let nonce = parser.read_u64_le()?;
+        let count = parser.read_length(parser.remaining() / SHORT_ID_SIZE)?;
+        let short_ids = (0..count)
+            .map(|_| {
+                let mut bytes = [0; 8];
+                bytes[..SHORT_ID_SIZE].copy_from_slice(parser.read(SHORT_ID_SIZE)?);
+                Ok(u64::from_le_bytes(bytes))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let count = parser.read_length(parser.remaining() / (1 + MIN_TRANSACTION_SIZE))?;
+        let mut prefilled: Vec<PrefilledTransaction> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let index = read_index(parser, prefilled.last().map(|p| p.index))?;
+            prefilled.push(PrefilledTransaction { index, tx: Transaction::parse(parser)? });
+        }
+        let tx_count = short_ids.len() + prefilled.len();
+        if let Some(p) = prefilled.last().filter(|p| p.index >= tx_count) {
+            return Err(invalid_data(format!("prefilled transaction index {} exceeds the transaction count {}", p.index, tx_count)));
+        }
+        Ok(HeaderAndShortIds { header, nonce, short_ids, prefilled })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        self.header.append(composer);
+        composer.append(&self.nonce.to_le_bytes());
+        composer.append_compact_size(self.short_ids.len() as u64);
+        for short_id in &self.short_ids {
+            composer.append(&short_id.to_le_bytes()[..SHORT_ID_SIZE]);
+        }
+        composer.append_compact_size(self.prefilled.len() as u64);
+        let mut previous = None;
+        for p in &self.prefilled {
+            append_index(composer, previous, p.index);
+            p.tx.append(composer, true);
+            previous = Some(p.index);
+        }
+    }
+}
+
+/// Request of transactions of a block, which could not be reconstructed from a compact block
+/// (`BlockTransactionsRequest`, BIP152)
+///
+/// size | field          | type      | description
+/// ---  | -----          | ----      | ------------
+/// 32   | block_hash     | [u8; 32]  | hash of the block (internal byte order)
+/// ?    | indexes length | var_int   | number of requested transactions
+/// ?    | indexes        | var_int[] | differentially encoded positions of the requested transactions in the block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactionsRequest {
+    pub block_hash: [u8; 32],
+    /// in ascending order
+    pub indexes: Vec<usize>,
+}
+
+impl BlockTransactionsRequest {
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let block_hash = parser.read(32)?.try_into().unwrap();
+        let count = parser.read_length(parser.remaining())?;
+        let mut indexes: Vec<usize> = Vec::with_capacity(count);
+        for _ in 0..count {
+            indexes.push(read_index(parser, indexes.last().copied())?);
+        }
+        Ok(BlockTransactionsRequest { block_hash, indexes })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.block_hash);
+        composer.append_compact_size(self.indexes.len() as u64);
+        let mut previous = None;
+        for index in &self.indexes {
+            append_index(composer, previous, *index);
+            previous = Some(*index);
+        }
+    }
+}
+
+/// Transactions of a block answering a [BlockTransactionsRequest] (`BlockTransactions`, BIP152)
+///
+/// size | field        | type          | description
+/// ---  | -----        | ----          | ------------
+/// 32   | block_hash   | [u8; 32]      | hash of the block (internal byte order)
+/// ?    | transactions | Transaction[] | the requested transactions in their order, with CompactSize count prefix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockTransactions {
+    pub block_hash: [u8; 32],
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockTransactions {
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let block_hash = parser.read(32)?.try_into().unwrap();
+        let count = parser.read_length(parser.remaining() / MIN_TRANSACTION_SIZE)?;
+        let transactions = (0..count)
+            .map(|_| Transaction::parse(parser))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(BlockTransactions { block_hash, transactions })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.block_hash);
+        composer.append_compact_size(self.transactions.len() as u64);
+        for tx in &self.transactions {
+            tx.append(composer, true);
+        }
+    }
+}
+
+/// Block being reconstructed from a compact block (see `PartiallyDownloadedBlock` in bitcoin core)
+///
+/// The transactions announced by short id are looked up in a pool of transactions provided by the caller, e.g. the
+/// transactions collected by a [MempoolObserverConversationTopic](crate::wire_protocol::mempool_observer::MempoolObserverConversationTopic).
+/// The ones not found are requested with a __getblocktxn__ message (see [Self::request]) and completed by the
+/// __blocktxn__ answer (see [Self::fill]).
+#[derive(Clone, Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Fills the compact block with the prefilled and the pool transactions.
+    ///
+    /// A short id matching several pool transactions is left open, so that the transaction is requested. A compact
+    /// block containing the same short id twice can not be reconstructed at all and results in
+    /// [PeerError::InvalidBlock]; the block has to be downloaded in full then.
+    pub fn new<'a>(compact: &HeaderAndShortIds, pool: impl IntoIterator<Item = &'a Transaction>) -> PeerResult<Self> {
+        let invalid = |reason: String| PeerError::InvalidBlock { hash: display_hash(&compact.header.block_hash()), reason };
+        if compact.tx_count() == 0 {
+            return Err(invalid("no transactions".to_string()));
+        }
+        let mut transactions: Vec<Option<Transaction>> = vec![None; compact.tx_count()];
+        for p in &compact.prefilled {
+            match transactions.get_mut(p.index) {
+                Some(slot @ None) => *slot = Some(p.tx.clone()),
+                _ => return Err(invalid(format!("invalid prefilled transaction index {}", p.index))),
+            }
+        }
+
+        // the short ids occupy the remaining positions in block order
+        let free_positions = (0..transactions.len()).filter(|&i| transactions[i].is_none()).collect::<Vec<_>>();
+        let mut positions = HashMap::with_capacity(compact.short_ids.len());
+        for (short_id, position) in compact.short_ids.iter().zip(free_positions) {
+            if positions.insert(*short_id, position).is_some() {
+                return Err(invalid(format!("duplicate short id {:012x}", short_id)));
+            }
+        }
+
+        let mut collisions = HashSet::new();
+        for tx in pool {
+            let wtxid = tx.wtxid();
+            if let Some(&position) = positions.get(&compact.short_id(&wtxid)) {
+                match &transactions[position] {
+                    None if !collisions.contains(&position) => transactions[position] = Some(tx.clone()),
+                    Some(known) if known.wtxid() == wtxid => {}
+                    _ => {
+                        transactions[position] = None;
+                        collisions.insert(position);
+                    }
+                }
+            }
+        }
+        Ok(PartialBlock { header: compact.header, transactions })
+    }
+
+    pub fn block_hash(&self) -> [u8; 32] {
+        self.header.block_hash()
+    }
+
+    /// positions of the transactions, which are still missing
+    pub fn missing(&self) -> Vec<usize> {
+        (0..self.transactions.len()).filter(|&i| self.transactions[i].is_none()).collect()
+    }
+
+    /// request of the missing transactions
+    pub fn request(&self) -> BlockTransactionsRequest {
+        BlockTransactionsRequest { block_hash: self.block_hash(), indexes: self.missing() }
+    }
+
+    /// Completes the block with the transactions of a __blocktxn__ answer to [Self::request].
+    /// The result has to match its merkle root; a mismatch may also be caused by a short id collision, so the block
+    /// has to be downloaded in full then.
+    pub fn fill(self, answer: BlockTransactions) -> PeerResult<Block> {
+        if answer.block_hash != self.block_hash() {
+            return Err(PeerError::ProtocolViolation(
+                format!("got transactions of block {} instead of {}", display_hash(&answer.block_hash), display_hash(&self.block_hash()))
+            ));
+        }
+        self.complete(answer.transactions)
+    }
+
+    /// The block, if no transaction is missing
+    pub fn into_block(self) -> PeerResult<Block> {
+        self.complete(vec![])
+    }
+
+    fn complete(self, supplied: Vec<Transaction>) -> PeerResult<Block> {
+        let missing = self.transactions.iter().filter(|tx| tx.is_none()).count();
+        if supplied.len() != missing {
+            return Err(PeerError::InvalidBlock {
+                hash: display_hash(&self.block_hash()),
+                reason: format!("{} transactions are missing, but {} were supplied", missing, supplied.len()),
+            });
+        }
+        let mut supplied = supplied.into_iter();
+        let block = Block {
+            header: self.header,
+            transactions: self.transactions.into_iter()
+                .map(|tx| tx.or_else(|| supplied.next()).unwrap())
+                .collect(),
+        };
+        block.validate_merkle_root()?;
+        Ok(block)
+    }
+}
+
+/// Reads an index, which is encoded as the difference to its predecessor minus one (the first one as it is).
+/// Indexes are limited to 16 bits like in bitcoin core.
+fn read_index(parser: &mut ByteBufferParser, previous: Option<usize>) -> io::Result<usize> {
+    let index = parser.read_compact_size()?.saturating_add(previous.map_or(0, |p| p as u64 + 1));
+    if index > u16::MAX as u64 {
+        return Err(invalid_data(format!("transaction index {} exceeds the limit of {}", index, u16::MAX)));
+    }
+    Ok(index as usize)
+}
+
+fn append_index(composer: &mut ByteBufferComposer, previous: Option<usize>, index: usize) {
+    composer.append_compact_size((index - previous.map_or(0, |p| p + 1)) as u64);
+}
+
+/// SipHash-2-4 (see https://www.aumasson.jp/siphash/siphash.pdf)
+pub(super) fn siphash_2_4(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+    let compress = |v: &mut [u64; 4], m: u64| {
+        v[3] ^= m;
+        round(v);
+        round(v);
+        v[0] ^= m;
+    };
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in chunks.by_ref() {
+        compress(&mut v, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    // the last block holds the remaining bytes and the length of the data in its most significant byte
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use crate::wire_protocol::node::Chain;
+    use crate::wire_protocol::transaction::{OutPoint, TxIn, TxOut};
+
+    use super::*;
+
+    /// txid of the genesis coinbase transaction (internal byte order)
+    const GENESIS_COINBASE_TXID: [u8; 32] = hex!("3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a");
+
+    fn tx(n: u8, witness: bool) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint { txid: [n; 32], vout: 0 },
+                script_sig: vec![],
+                sequence: u32::MAX,
+                witness: match witness {
+                    true => vec![vec![n]],
+                    false => vec![],
+                },
+            }],
+            outputs: vec![TxOut { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        }
+    }
+
+    /// regtest block with a coinbase and `count` further transactions, alternately with and without witness data
+    fn block(count: u8) -> Block {
+        let coinbase = Transaction {
+            inputs: vec![TxIn { previous_output: OutPoint::NULL, script_sig: vec![0x01, 0x01], ..tx(0, false).inputs[0].clone() }],
+            ..tx(0, false)
+        };
+        let mut block = Block {
+            header: BlockHeader { prev_block: Chain::Regtest.genesis_hash(), ..Chain::Regtest.genesis_header() },
+            transactions: std::iter::once(coinbase).chain((1..=count).map(|n| tx(n, n % 2 == 0))).collect(),
+        };
+        block.header.merkle_root = block.merkle_root().unwrap();
+        block
+    }
+
+    // reference vectors of the SipHash paper and of bitcoin core's `siphash_tests`
+    #[rstest]
+    #[case(& hex ! ("")[..], 0x726fdb47dd0e0e31)]
+    #[case(& hex ! ("000102030405060708090a0b0c0d0e"), 0xa129ca6149be45e5)]
+    #[case(& hex ! ("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"), 0x7127512f72f27cce)]
+    fn test_siphash(#[case] data: &[u8], #[case] expected: u64) {
+        assert_eq!(siphash_2_4(0x0706050403020100, 0x0f0e0d0c0b0a0908, data), expected);
+    }
+
+    #[test]
+    fn test_short_id() {
+        let compact = HeaderAndShortIds {
+            header: Chain::Mainnet.genesis_header(),
+            nonce: 0x0102030405060708,
+            short_ids: vec![],
+            prefilled: vec![],
+        };
+        assert_eq!(compact.short_id(&GENESIS_COINBASE_TXID), 0xd3dad4322e88);
+    }
+
+    #[test]
+    fn test_compact_block_round_trip() {
+        let block = block(3);
+        let compact = HeaderAndShortIds::from_block(&block, 42);
+        assert_eq!(compact.tx_count(), 4);
+        assert_eq!(compact.prefilled, vec![PrefilledTransaction { index: 0, tx: block.transactions[0].clone() }]);
+        assert_eq!(compact.short_ids[1], compact.short_id(&block.transactions[2].wtxid()));
+
+        let mut composer = ByteBufferComposer::new();
+        compact.append(&mut composer);
+        let bytes = composer.result();
+        assert_eq!(&bytes[80..88 + 1 + 3 * SHORT_ID_SIZE + 2], [
+            &42_u64.to_le_bytes()[..], &[3],
+            &compact.short_ids[0].to_le_bytes()[..6], &compact.short_ids[1].to_le_bytes()[..6], &compact.short_ids[2].to_le_bytes()[..6],
+            &[1, 0]
+        ].concat());
+
+        let mut parser = ByteBufferParser::new(&bytes);
+        assert_eq!(HeaderAndShortIds::parse(&mut parser).unwrap(), compact);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_prefilled_index_beyond_transaction_count_is_rejected() {
+        let mut compact = HeaderAndShortIds::from_block(&block(1), 0);
+        compact.prefilled[0].index = 2;
+        let mut composer = ByteBufferComposer::new();
+        compact.append(&mut composer);
+        assert!(HeaderAndShortIds::parse(&mut ByteBufferParser::new(&composer.result())).is_err());
+    }
+
+    #[test]
+    fn test_differential_index_encoding() {
+        let request = BlockTransactionsRequest { block_hash: [7; 32], indexes: vec![0, 1, 3, 7, 0xffff] };
+        let mut composer = ByteBufferComposer::new();
+        request.append(&mut composer);
+        let bytes = composer.result();
+        assert_eq!(&bytes[32..], &hex!("05" "00" "00" "01" "03" "fdf7ff"));
+        assert_eq!(BlockTransactionsRequest::parse(&mut ByteBufferParser::new(&bytes)).unwrap(), request);
+
+        // indexes beyond 16 bits
+        let bytes = [&[7; 32][..], &hex!("02" "fdffff" "00")].concat();
+        assert!(BlockTransactionsRequest::parse(&mut ByteBufferParser::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn test_block_transactions_round_trip() {
+        let answer = BlockTransactions { block_hash: [7; 32], transactions: vec![tx(1, false), tx(2, true)] };
+        let mut composer = ByteBufferComposer::new();
+        answer.append(&mut composer);
+        let bytes = composer.result();
+        let mut parser = ByteBufferParser::new(&bytes);
+        assert_eq!(BlockTransactions::parse(&mut parser).unwrap(), answer);
+        assert_eq!(parser.remaining(), 0);
+    }
+
+    #[test]
+    fn test_block_is_reconstructed_from_the_pool() {
+        let block = block(4);
+        let compact = HeaderAndShortIds::from_block(&block, 1);
+        let pool = [tx(9, true), block.transactions[1].clone(), block.transactions[2].clone(),
+            block.transactions[3].clone(), block.transactions[4].clone()];
+
+        let partial = PartialBlock::new(&compact, &pool).unwrap();
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.into_block().unwrap(), block);
+    }
+
+    #[test]
+    fn test_missing_transactions_are_requested() {
+        let block = block(4);
+        let compact = HeaderAndShortIds::from_block(&block, 1);
+        let pool = [block.transactions[2].clone(), block.transactions[3].clone()];
+
+        let partial = PartialBlock::new(&compact, &pool).unwrap();
+        let request = partial.request();
+        assert_eq!(request, BlockTransactionsRequest { block_hash: block.block_hash(), indexes: vec![1, 4] });
+        assert!(partial.clone().into_block().is_err());
+
+        let wrong_block = BlockTransactions { block_hash: [7; 32], transactions: vec![] };
+        assert!(matches!(partial.clone().fill(wrong_block), Err(PeerError::ProtocolViolation(_))));
+        let too_few = BlockTransactions { block_hash: block.block_hash(), transactions: vec![block.transactions[1].clone()] };
+        assert!(matches!(partial.clone().fill(too_few), Err(PeerError::InvalidBlock { .. })));
+        let swapped = BlockTransactions {
+            block_hash: block.block_hash(),
+            transactions: vec![block.transactions[4].clone(), block.transactions[1].clone()],
+        };
+        assert!(matches!(partial.clone().fill(swapped), Err(PeerError::InvalidBlock { .. })));
+
+        let answer = BlockTransactions {
+            block_hash: block.block_hash(),
+            transactions: vec![block.transactions[1].clone(), block.transactions[4].clone()],
+        };
+        assert_eq!(partial.fill(answer).unwrap(), block);
+    }
+
+    #[test]
+    fn test_wrongly_matched_pool_transaction_fails_the_merkle_check() {
+        let block = block(2);
+        let mut compact = HeaderAndShortIds::from_block(&block, 1);
+        // a pool transaction, which happens to have the same short id as the block's transaction at index 2
+        let other = tx(9, false);
+        compact.short_ids[1] = compact.short_id(&other.wtxid());
+        let pool = [block.transactions[1].clone(), other];
+
+        let partial = PartialBlock::new(&compact, &pool).unwrap();
+        assert!(partial.missing().is_empty());
+        assert!(matches!(partial.into_block(), Err(PeerError::InvalidBlock { .. })));
+    }
+
+    #[test]
+    fn test_duplicate_short_ids_are_rejected() {
+        let block = block(2);
+        let mut compact = HeaderAndShortIds::from_block(&block, 1);
+        compact.short_ids[1] = compact.short_ids[0];
+        assert!(matches!(PartialBlock::new(&compact, &block.transactions), Err(PeerError::InvalidBlock { .. })));
+    }
+}
@@ -0,0 +1,188 @@
+use std::io;
+
+use crate::wire_protocol::buffer::ByteBufferParser;
+use crate::wire_protocol::compact_block::siphash_2_4;
+use crate::wire_protocol::raw_message::sha256;
+
+/// filter type of the basic block filter (BIP158)
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+/// Golomb-Rice coding parameter of the basic filter
+const BASIC_FILTER_P: u8 = 19;
+
+/// inverse false positive rate of the basic filter
+const BASIC_FILTER_M: u64 = 784931;
+
+/// Compact filter of a block (BIP158)
+///
+/// The basic filter contains the output scripts of all transactions of the block and the scripts of the outputs they
+/// spend (except for the coinbase input), apart from empty and OP_RETURN scripts.
+/// Each one is hashed to a number in `[0, N * M)` using SipHash-2-4 keyed with the first 16 bytes of the block hash.
+/// The sorted numbers are stored as Golomb-Rice coded differences.
+///
+/// size | field    | type    | description
+/// ---  | -----    | ----    | ------------
+/// ?    | N        | var_int | number of elements
+/// ?    | elements | bits    | Golomb-Rice coded differences of the sorted element hashes, padded to whole bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockFilter {
+    pub filter_type: u8,
+    /// hash of the block the filter belongs to (internal byte order)
+    pub block_hash: [u8; 32],
+    /// the serialized filter
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new(filter_type: u8, block_hash: [u8; 32], content: Vec<u8>) -> Self {
+        BlockFilter { filter_type, block_hash, content }
+    }
+
+    /// sha256(sha256(content)) in internal byte order
+    pub fn filter_hash(&self) -> [u8; 32] {
+        sha256(&sha256(&self.content))
+    }
+
+    /// header of this filter, given the header of the filter of the previous block (zero for the genesis block)
+    pub fn filter_header(&self, previous_header: &[u8; 32]) -> [u8; 32] {
+        filter_header(&self.filter_hash(), previous_header)
+    }
+
+    /// whether the filter contains `element`; there is a chance of 1 / 784931 for a false positive
+    pub fn matches(&self, element: &[u8]) -> io::Result<bool> {
+        self.match_any([element])
+    }
+
+    /// Whether the filter contains any of `elements`, which is the case if the block is of interest to a wallet
+    /// watching these scripts
+    pub fn match_any<'a>(&self, elements: impl IntoIterator<Item = &'a [u8]>) -> io::Result<bool> {
+        if self.filter_type != BASIC_FILTER_TYPE {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("unknown filter type {}", self.filter_type)));
+        }
+        let mut parser = ByteBufferParser::new(&self.content);
+        // every element takes at least P + 1 bits
+        let n = parser.read_length(parser.remaining() * 8 / (BASIC_FILTER_P as usize + 1))? as u64;
+        let range = n * BASIC_FILTER_M;
+        let k0 = u64::from_le_bytes(self.block_hash[..8].try_into().unwrap());
+        let k1 = u64::from_le_bytes(self.block_hash[8..16].try_into().unwrap());
+        let mut queries: Vec<u64> = elements.into_iter()
+            .map(|element| hash_to_range(siphash_2_4(k0, k1, element), range))
+            .collect();
+        queries.sort_unstable();
+
+        let mut reader = BitReader::new(parser.read(parser.remaining())?);
+        let mut value = 0;
+        let mut queries = queries.into_iter().peekable();
+        for _ in 0..n {
+            value += reader.read_golomb_rice(BASIC_FILTER_P)?;
+            while let Some(query) = queries.next_if(|&query| query <= value) {
+                if query == value {
+                    return Ok(true);
+                }
+            }
+            if queries.peek().is_none() {
+                break;
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// sha256(sha256(filter_hash || previous_header)), i.e. the header of a filter in the chain of filter headers
+pub fn filter_header(filter_hash: &[u8; 32], previous_header: &[u8; 32]) -> [u8; 32] {
+    let mut concatenated = filter_hash.to_vec();
+    concatenated.extend_from_slice(previous_header);
+    sha256(&sha256(&concatenated))
+}
+
+/// maps a uniformly distributed 64-bit hash to `[0, range)`
+fn hash_to_range(hash: u64, range: u64) -> u64 {
+    ((hash as u128 * range as u128) >> 64) as u64
+}
+
+/// Reads bits starting with the most significant one of each byte
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        BitReader { bytes, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> io::Result<bool> {
+        let byte = self.bytes.get(self.pos / 8).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "filter ends within an element")
+        })?;
+        let bit = byte & (0x80 >> (self.pos % 8)) != 0;
+        self.pos += 1;
+        Ok(bit)
+    }
+
+    /// quotient in unary coding (ones terminated by a zero), followed by the remainder in `p` bits
+    fn read_golomb_rice(&mut self, p: u8) -> io::Result<u64> {
+        let mut quotient = 0_u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0;
+        for _ in 0..p {
+            remainder = (remainder << 1) | self.read_bit()? as u64;
+        }
+        Ok((quotient << p) | remainder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
+
+    /// output script of the genesis coinbase
+    const GENESIS_SCRIPT: [u8; 67] = hex!("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac");
+
+    fn reversed(mut hash: [u8; 32]) -> [u8; 32] {
+        hash.reverse();
+        hash
+    }
+
+    /// BIP158 test vector of the testnet genesis block
+    #[test]
+    fn test_testnet_genesis_filter() {
+        let filter = BlockFilter::new(BASIC_FILTER_TYPE, Chain::Testnet3.genesis_hash(), hex!("019dfca8").to_vec());
+        assert!(filter.matches(&GENESIS_SCRIPT).unwrap());
+        assert!(!filter.matches(&hex!("51")).unwrap());
+        assert_eq!(filter.filter_header(&[0; 32]),
+                   reversed(hex!("21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750")));
+    }
+
+    #[test]
+    fn test_filter_with_several_elements() {
+        let filter = BlockFilter::new(BASIC_FILTER_TYPE, Chain::Regtest.genesis_hash(), hex!("05d92c826cdb751c1e4492776e39").to_vec());
+        let p2wpkh = [&hex!("0014")[..], &[0; 20]].concat();
+        for element in [&hex!("51")[..], &p2wpkh, b"abc", &GENESIS_SCRIPT, &hex!("6a")] {
+            assert!(filter.matches(element).unwrap());
+        }
+        assert!(!filter.match_any([&hex!("52")[..], b"abd", &hex!("00140101010101010101010101010101010101010101")]).unwrap());
+        assert!(filter.match_any([&hex!("52")[..], b"abc"]).unwrap());
+        assert!(!filter.match_any([]).unwrap());
+    }
+
+    #[test]
+    fn test_empty_filter_matches_nothing() {
+        let filter = BlockFilter::new(BASIC_FILTER_TYPE, Chain::Regtest.genesis_hash(), hex!("00").to_vec());
+        assert!(!filter.matches(&GENESIS_SCRIPT).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_filter_is_an_error() {
+        let filter = BlockFilter::new(BASIC_FILTER_TYPE, Chain::Regtest.genesis_hash(), hex!("05d92c826cdb751c1e44").to_vec());
+        assert!(filter.matches(&hex!("51")).is_err());
+        let unknown_type = BlockFilter { filter_type: 1, ..filter };
+        assert!(unknown_type.matches(&hex!("51")).is_err());
+    }
+}
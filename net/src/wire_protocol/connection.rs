@@ -1,75 +1,362 @@
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use tokio::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{self, MissedTickBehavior};
 
-use crate::conversation::ConversationTopicHandler;
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
 use crate::error::{PeerError, PeerResult};
-use crate::wire_protocol::buffer::IOBuffer;
+use crate::wire_protocol::messages::ProtocolMessage;
+use crate::wire_protocol::misbehavior::{Misbehavior, MisbehaviorEvent, MisbehaviorReporter};
+use crate::wire_protocol::negotiation::{Announcements, Negotiation};
 use crate::wire_protocol::node::Chain;
-use crate::wire_protocol::raw_message::{MessageParseOutcome, RawMessage};
+use crate::wire_protocol::peer::GarbagePolicy;
+use crate::wire_protocol::transport::{NegotiatedTransport, Transport, V1Transport, V2Transport};
 
-pub struct NodeConnection {
-    chain: Chain,
-    socket: TcpStream,
+/// How long an initiator waits for the public key of the responder, before it assumes that the responder only speaks v1
+const V2_KEY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs conversation topics with a remote node over a [Transport], by default a TCP connection speaking v1 or v2
+pub struct NodeConnection<T = NegotiatedTransport<TcpStream>> {
+    transport: T,
+    remote_addr: SocketAddr,
+    misbehavior_reporter: Option<MisbehaviorReporter>,
+    negotiation: Negotiation,
+    /// received after the announcements following the last topic, to be passed to the next topic
+    pending: Option<PeerResult<ProtocolMessage>>,
 }
 
 impl NodeConnection {
+    /// connects to the remote node at `addr` (outbound connection)
     pub async fn new(chain: Chain, addr: SocketAddr) -> io::Result<Self> {
         let socket = TcpStream::connect(addr).await?;
-        Ok(NodeConnection { chain, socket })
+        Ok(Self::with_transport(NegotiatedTransport::V1(V1Transport::new(chain, socket)), addr))
+    }
+
+    /// Connects to the remote node at `addr` (outbound connection) using the encrypted v2 transport (BIP324).
+    /// Falls back to an unencrypted v1 connection, if the remote node hangs up or doesn't answer our public key in time,
+    /// which is what nodes not supporting v2 do.
+    pub async fn new_v2(chain: Chain, addr: SocketAddr) -> PeerResult<Self> {
+        let socket = TcpStream::connect(addr).await?;
+        match V2Transport::initiate(chain, socket, V2_KEY_TIMEOUT).await? {
+            Some(transport) => Ok(Self::with_transport(NegotiatedTransport::V2(transport), addr)),
+            None => {
+                log::info!("{} does not support the v2 transport, reconnecting with v1", addr);
+                Ok(Self::new(chain, addr).await?)
+            }
+        }
+    }
+}
+
+impl<S> NodeConnection<NegotiatedTransport<S>> {
+    /// Maximum payload size of a received message. The connection is given up, when a remote node announces a larger one.
+    /// Defaults to [MAX_PROTOCOL_MESSAGE_LENGTH](crate::wire_protocol::raw_message::MAX_PROTOCOL_MESSAGE_LENGTH).
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.transport.set_max_message_size(max_message_size);
+    }
+
+    pub fn set_garbage_policy(&mut self, garbage_policy: GarbagePolicy) {
+        self.transport.set_garbage_policy(garbage_policy);
+    }
+
+    /// number of received bytes, which were dropped to resynchronize the stream (see [GarbagePolicy::Resync])
+    pub fn dropped_bytes(&self) -> u64 {
+        self.transport.dropped_bytes()
+    }
+
+    /// Session id of the v2 transport, which both nodes can compare out of band to rule out a man in the middle.
+    /// `None` for unencrypted v1 connections.
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        self.transport.session_id()
+    }
+}
+
+impl<T: Transport> NodeConnection<T> {
+    /// Conversations with the remote node at `remote_addr` over `transport`, e.g. a [V1Transport] over a unix socket,
+    /// a proxied connection or an in-memory stream. `remote_addr` identifies the remote node in misbehavior reports.
+    pub fn with_transport(transport: T, remote_addr: SocketAddr) -> Self {
+        NodeConnection {
+            transport,
+            remote_addr,
+            misbehavior_reporter: None,
+            negotiation: Negotiation::default(),
+            pending: None,
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Features announced by both nodes (e.g. __sendheaders__, __wtxidrelay__), as far as they were received so far.
+    /// Announcements, which the remote node sends right after a topic is finished (e.g. __sendheaders__ following
+    /// __verack__), are recorded without waiting for the next topic, as long as they are received already.
+    pub fn negotiation(&self) -> &Negotiation {
+        &self.negotiation
+    }
+
+    /// Misbehavior of the remote node gets reported to `reporter`
+    pub fn set_misbehavior_reporter(&mut self, reporter: MisbehaviorReporter) {
+        self.misbehavior_reporter = Some(reporter);
     }
 
     pub async fn proceed_conversation<H: ConversationTopicHandler>(&mut self, handler: H) -> PeerResult<H::Outcome> {
         let mut handler = handler;
         let initial_action = handler.initial_action();
-        if let Some(message) = initial_action.message {
-            log::debug!("sending {:?}", message);
-            self.socket.write_all(&message.to_bytes()).await?
-        }
-        if initial_action.topic_finished {
+        if self.perform(initial_action).await? {
+            self.record_announcements().await;
             return handler.outcome();
         }
 
-        'outer: loop {
-            let mut buffer = IOBuffer::default();
-            match self.socket.read(buffer.expose_writable_part()).await? {
-                0 => return Err(PeerError::from("Remote node hung up")),
-                n => {
-                    buffer.register_added_content(n);
-                    log::trace!("received {n} bytes, new buffer pos is {}", buffer.content().len());
-
-                    'inner: loop {
-                        log::trace!("trying to consume message, buffer pos is {}", buffer.content().len());
-                        match RawMessage::try_consume_message(&mut buffer, self.chain) {
-                            Ok(MessageParseOutcome::Message(raw_message)) => {
-                                let received_message = raw_message.to_protocol_message()?;
-
-                                log::debug!("received {:?}", received_message);
-                                let handler_response = handler.on_message(received_message)?;
-                                if let Some(response_message) = handler_response.message {
-                                    log::debug!("sending {:?}", response_message);
-                                    self.socket.write_all(&response_message.to_bytes()).await?;
-                                }
-                                if handler_response.topic_finished {
-                                    break 'outer;
-                                }
-                            }
-                            Ok(MessageParseOutcome::SkippedMessage) => {}
-                            Ok(MessageParseOutcome::NoMessage) => {
-                                // consistent state but no complete message available
-                                break 'inner;
-                            }
-                            Err(err) => {
-                                log::warn!("ignoring incoming message, because we couldn't decode it: {}", err)
-                            }
-                        }
+        let mut ticker = handler.tick_interval().map(|period| {
+            let mut ticker = time::interval_at(time::Instant::now() + period, period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+
+        loop {
+            let event = match (self.pending.take(), ticker.as_mut()) {
+                (Some(received), _) => ReadEvent::Received(received),
+                (None, None) => ReadEvent::Received(self.transport.receive().await),
+                (None, Some(ticker)) => tokio::select! {
+                    received = self.transport.receive() => ReadEvent::Received(received),
+                    now = ticker.tick() => ReadEvent::Tick(now.into_std()),
+                }
+            };
+            for misbehavior in self.transport.take_misbehavior() {
+                self.report(misbehavior);
+            }
+
+            let handler_response = match event {
+                ReadEvent::Received(Ok(received_message)) => {
+                    log::debug!("received {:?}", received_message);
+                    self.negotiation.remote.record(&received_message);
+                    handler.on_message(received_message)?
+                }
+                ReadEvent::Received(Err(err)) => {
+                    if let PeerError::PayloadTooLarge { command, len, max } = &err {
+                        self.report(Misbehavior::OversizedMessage { command: command.clone(), len: *len, max: *max });
                     }
+                    // the stream is in an unknown state now - we can't make any progress on it
+                    log::warn!("giving up connection, because we couldn't receive an incoming message: {}", err);
+                    return Err(err);
                 }
+                ReadEvent::Tick(now) => handler.on_tick(now)?,
+            };
+            if self.perform(handler_response).await? {
+                break;
             }
         }
 
+        self.record_announcements().await;
         handler.outcome()
     }
+
+    /// Records the announcements, which the remote node sent following the messages, that finished a topic, as far as
+    /// they are received already. The first other message (or error) is kept for the next topic.
+    async fn record_announcements(&mut self) {
+        while self.pending.is_none() {
+            // polls once, without waiting for further messages
+            let Ok(received) = time::timeout(Duration::ZERO, self.transport.receive()).await else {
+                break;
+            };
+            for misbehavior in self.transport.take_misbehavior() {
+                self.report(misbehavior);
+            }
+            match received {
+                Ok(message) if Announcements::is_announcement(&message) => {
+                    log::debug!("received {:?}", message);
+                    self.negotiation.remote.record(&message);
+                }
+                received => self.pending = Some(received),
+            }
+        }
+    }
+
+    fn report(&self, misbehavior: Misbehavior) {
+        log::warn!("misbehavior of {}: {:?}", self.remote_addr, misbehavior);
+        if let Some(reporter) = &self.misbehavior_reporter {
+            // the receiver may be gone already, which is fine
+            let _ = reporter.send(MisbehaviorEvent { peer: self.remote_addr, misbehavior });
+        }
+    }
+
+    /// sends the messages of `action` and returns, whether the topic is finished
+    async fn perform(&mut self, action: ConversationAction) -> PeerResult<bool> {
+        for message in action.messages {
+            log::debug!("sending {:?}", message);
+            self.negotiation.local.record(&message);
+            self.transport.send(message).await?;
+        }
+        Ok(action.topic_finished)
+    }
+}
+
+enum ReadEvent {
+    Received(PeerResult<ProtocolMessage>),
+    Tick(Instant),
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncWriteExt, duplex};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use crate::wire_protocol::handshake::{HandshakeInitConversationTopic, HandshakeRespondConversationTopic, LocalNonces};
+    use crate::wire_protocol::messages::{FeeFilterMessage, PingMessage, ProtocolMessage, SendCmpctMessage, SendHeadersMessage,
+                                         VerackMessage, VersionMessage, WtxidRelayMessage};
+    use crate::wire_protocol::negotiation::CompactBlocks;
+    use crate::wire_protocol::node::NodeDesc;
+    use crate::wire_protocol::raw_message::{Command, RawMessage};
+
+    use super::*;
+
+    /// runs a responder handshake against a remote node, which sends `bytes`
+    async fn respond_to_handshake(bytes: Vec<u8>, garbage_policy: GarbagePolicy) -> (PeerResult<NodeDesc>, NodeConnection, Vec<MisbehaviorEvent>) {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_addr = listener.local_addr().unwrap();
+        let remote = async {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(&bytes).await.unwrap();
+            socket
+        };
+
+        let (reporter, mut events) = mpsc::unbounded_channel();
+        let mut connection = NodeConnection::new(Chain::Regtest, remote_addr).await.unwrap();
+        connection.set_misbehavior_reporter(reporter);
+        connection.set_garbage_policy(garbage_policy);
+        let topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+
+        let (_socket, result) = tokio::join!(remote, connection.proceed_conversation(topic));
+        let mut received_events = vec![];
+        while let Ok(event) = events.try_recv() {
+            received_events.push(event);
+        }
+        (result, connection, received_events)
+    }
+
+    fn handshake_bytes() -> (Vec<u8>, Vec<u8>) {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let version = VersionMessage::new("127.0.0.1:18444".parse().unwrap(), &me);
+        let version = ProtocolMessage::Version(version).to_bytes();
+        let verack = ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest)).to_bytes();
+        (version, verack)
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_reported_as_misbehavior() {
+        // header of a version message announcing 1 MB payload
+        let mut header = RawMessage::new(Chain::Regtest, Command::Version, vec![]).to_bytes();
+        header[16..20].copy_from_slice(&1_000_000_u32.to_le_bytes());
+
+        let (result, connection, events) = respond_to_handshake(header, GarbagePolicy::Resync).await;
+        assert!(matches!(result, Err(PeerError::PayloadTooLarge { len: 1_000_000, .. })));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].peer, connection.remote_addr());
+        assert!(matches!(events[0].misbehavior, Misbehavior::OversizedMessage { len: 1_000_000, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_stream_is_resynchronized_after_garbage() {
+        let (version, verack) = handshake_bytes();
+        let mut corrupted_version = version.clone();
+        *corrupted_version.last_mut().unwrap() ^= 0xff;
+        let mut bytes = b"garbage".to_vec();
+        bytes.extend(&version);
+        bytes.extend(&corrupted_version);
+        bytes.extend(&verack);
+
+        let (result, connection, events) = respond_to_handshake(bytes, GarbagePolicy::Resync).await;
+        assert!(result.is_ok());
+        assert_eq!(connection.dropped_bytes(), (7 + corrupted_version.len()) as u64);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(events[0].misbehavior, Misbehavior::Garbage { dropped_bytes: 7, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_garbage_disconnects_by_policy() {
+        let (version, verack) = handshake_bytes();
+        let mut bytes = b"garbage".to_vec();
+        bytes.extend(&version);
+        bytes.extend(&verack);
+
+        let (result, connection, events) = respond_to_handshake(bytes, GarbagePolicy::Disconnect).await;
+        assert!(matches!(result, Err(PeerError::WrongNetwork { .. })));
+        assert_eq!(connection.dropped_bytes(), 0);
+        assert!(events.is_empty());
+    }
+
+
+    /// finishes with the first received message
+    struct FirstMessage(Option<ProtocolMessage>);
+
+    impl ConversationTopicHandler for FirstMessage {
+        type Outcome = ProtocolMessage;
+
+        fn initial_action(&mut self) -> ConversationAction {
+            ConversationAction::nop()
+        }
+
+        fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+            self.0 = Some(message);
+            Ok(ConversationAction { messages: vec![], topic_finished: true })
+        }
+
+        fn outcome(self) -> PeerResult<ProtocolMessage> {
+            Ok(self.0.unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_right_after_handshake() {
+        let announcements = Announcements {
+            wtxid_relay: true,
+            send_headers: true,
+            compact_blocks: vec![CompactBlocks { announce: false, version: 2 }],
+            fee_filter: Some(1000),
+        };
+        let addr: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        // as sent by bitcoin core: announcements right after verack, followed by other messages
+        let remote_messages = [
+            ProtocolMessage::Version(VersionMessage::new(addr, &NodeDesc::test_node("/remote/"))),
+            ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(Chain::Regtest)),
+            ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest)),
+            ProtocolMessage::SendHeaders(SendHeadersMessage::new(Chain::Regtest)),
+            ProtocolMessage::SendCmpct(SendCmpctMessage::new(Chain::Regtest, false, 2)),
+            ProtocolMessage::FeeFilter(FeeFilterMessage::new(Chain::Regtest, 1000)),
+            ProtocolMessage::Ping(PingMessage::new(Chain::Regtest)),
+        ];
+        let (local, mut remote) = duplex(64 * 1024);
+        for message in remote_messages {
+            remote.write_all(&message.to_bytes()).await.unwrap();
+        }
+
+        let mut connection = NodeConnection::with_transport(V1Transport::new(Chain::Regtest, local), addr);
+        let topic = HandshakeInitConversationTopic::new(&NodeDesc::test_node("/test:1.0/"), addr, &LocalNonces::default())
+            .with_announcements(announcements.clone());
+        connection.proceed_conversation(topic).await.unwrap();
+
+        let negotiation = connection.negotiation();
+        assert_eq!(negotiation.local, announcements);
+        assert!(negotiation.remote.wtxid_relay);
+        assert!(negotiation.remote.send_headers);
+        assert_eq!(negotiation.remote.compact_blocks, vec![CompactBlocks { announce: false, version: 2 }]);
+        assert_eq!(negotiation.remote.fee_filter, Some(1000));
+        assert_eq!(negotiation.compact_blocks_version(), Some(2));
+
+        // the message following the announcements is left to the next topic
+        let next = connection.proceed_conversation(FirstMessage(None)).await.unwrap();
+        assert!(matches!(next, ProtocolMessage::Ping(_)));
+    }
 }
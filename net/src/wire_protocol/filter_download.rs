@@ -0,0 +1,363 @@
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::display_hash;
+use crate::wire_protocol::compact_filter::{BASIC_FILTER_TYPE, BlockFilter, filter_header};
+use crate::wire_protocol::messages::{CFCHECKPT_INTERVAL, CFCheckptMessage, CFHeadersMessage, GetCFCheckptMessage, GetCFHeadersMessage,
+                                      GetCFiltersMessage, MAX_GETCFHEADERS_SIZE, MAX_GETCFILTERS_SIZE, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::Chain;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilterDownload {
+    /// basic filters of the requested blocks in the order of their height
+    pub filters: Vec<BlockFilter>,
+    /// filter headers of the requested blocks in the order of their height
+    pub filter_headers: Vec<[u8; 32]>,
+}
+
+/// Download of compact block filters (after the handshake), from a node serving them
+/// ([NodeCompactFilters](crate::wire_protocol::node::NodeService::NodeCompactFilters)):
+///
+/// - send __getcfcheckpt__ for the filter header checkpoints up to the last requested block
+/// - send __getcfheaders__ in batches of up to 2000 blocks and derive the filter header chain from the __cfheaders__
+///   answers
+/// - once the filter headers match the checkpoints, send __getcfilters__ in batches of up to 1000 blocks
+/// - expect a __cfilter__ message for every block, in order of height, matching the filter hash of its header
+///
+/// The filter header preceding the requested range is taken from the first __cfheaders__ answer, unless the range
+/// starts with the genesis block or right after a checkpoint.
+pub struct FilterDownloadConversationTopic {
+    chain: Chain,
+    start_height: u32,
+    /// hashes of the requested blocks
+    block_hashes: Vec<[u8; 32]>,
+    previous_filter_header: Option<[u8; 32]>,
+    filter_hashes: Vec<[u8; 32]>,
+    filter_headers: Vec<[u8; 32]>,
+    checkpoints: Option<Vec<[u8; 32]>>,
+    filters: Vec<BlockFilter>,
+}
+
+impl FilterDownloadConversationTopic {
+    /// Downloads the filters of the blocks with the given hashes, which start at `start_height` and follow each other
+    /// (e.g. taken from the outcome of a [HeadersSyncConversationTopic](crate::wire_protocol::headers_sync::HeadersSyncConversationTopic)).
+    pub fn new(chain: Chain, start_height: u32, block_hashes: Vec<[u8; 32]>) -> Self {
+        FilterDownloadConversationTopic {
+            chain,
+            start_height,
+            block_hashes,
+            previous_filter_header: match start_height {
+                0 => Some([0; 32]),
+                _ => None,
+            },
+            filter_hashes: vec![],
+            filter_headers: vec![],
+            checkpoints: None,
+            filters: vec![],
+        }
+    }
+
+    fn stop_height(&self) -> u32 {
+        self.start_height + self.block_hashes.len() as u32 - 1
+    }
+
+    /// __getcfheaders__ for the next batch of filter hashes
+    fn get_cf_headers(&self) -> ProtocolMessage {
+        let (start, end) = self.next_batch(self.filter_hashes.len(), MAX_GETCFHEADERS_SIZE);
+        ProtocolMessage::GetCFHeaders(
+            GetCFHeadersMessage::new(self.chain, BASIC_FILTER_TYPE, self.start_height + start as u32, self.block_hashes[end - 1])
+        )
+    }
+
+    /// __getcfilters__ for the next batch of filters
+    fn get_cfilters(&self) -> ProtocolMessage {
+        let (start, end) = self.next_batch(self.filters.len(), MAX_GETCFILTERS_SIZE);
+        ProtocolMessage::GetCFilters(
+            GetCFiltersMessage::new(self.chain, BASIC_FILTER_TYPE, self.start_height + start as u32, self.block_hashes[end - 1])
+        )
+    }
+
+    /// range of indexes into [Self::block_hashes] of the batch starting at `start`
+    fn next_batch(&self, start: usize, max_size: u32) -> (usize, usize) {
+        (start, self.block_hashes.len().min(start + max_size as usize))
+    }
+
+    fn on_cf_checkpt(&mut self, m: CFCheckptMessage) -> PeerResult<ConversationAction> {
+        if m.filter_type != BASIC_FILTER_TYPE || Some(&m.stop_hash) != self.block_hashes.last() || self.checkpoints.is_some() {
+            return Err(PeerError::ProtocolViolation(format!("unexpected cfcheckpt message up to block {}", display_hash(&m.stop_hash))));
+        }
+        let expected = (self.stop_height() + 1) / CFCHECKPT_INTERVAL;
+        if m.filter_headers.len() != expected as usize {
+            return Err(PeerError::ProtocolViolation(
+                format!("got {} filter header checkpoints instead of {}", m.filter_headers.len(), expected)
+            ));
+        }
+        // the filter header preceding the range is known, if it is a checkpoint
+        if self.start_height.is_multiple_of(CFCHECKPT_INTERVAL) && self.start_height > 0 && self.previous_filter_header.is_none() {
+            self.previous_filter_header = Some(m.filter_headers[(self.start_height / CFCHECKPT_INTERVAL - 1) as usize]);
+        }
+        self.checkpoints = Some(m.filter_headers);
+        self.request_filters()
+    }
+
+    fn on_cf_headers(&mut self, m: CFHeadersMessage) -> PeerResult<ConversationAction> {
+        let (start, end) = self.next_batch(self.filter_hashes.len(), MAX_GETCFHEADERS_SIZE);
+        if start == end || m.filter_type != BASIC_FILTER_TYPE || m.stop_hash != self.block_hashes[end - 1] {
+            return Err(PeerError::ProtocolViolation(format!("unexpected cfheaders message up to block {}", display_hash(&m.stop_hash))));
+        }
+        if m.filter_hashes.len() != end - start {
+            return Err(PeerError::ProtocolViolation(
+                format!("got {} filter hashes instead of {}", m.filter_hashes.len(), end - start)
+            ));
+        }
+        let previous = match self.filter_headers.last() {
+            Some(last) => *last,
+            None => *self.previous_filter_header.get_or_insert(m.previous_filter_header),
+        };
+        if m.previous_filter_header != previous {
+            return Err(self.invalid(start, "previous filter header does not match"));
+        }
+        let mut header = previous;
+        for hash in m.filter_hashes {
+            header = filter_header(&hash, &header);
+            self.filter_hashes.push(hash);
+            self.filter_headers.push(header);
+        }
+        match self.filter_hashes.len() < self.block_hashes.len() {
+            true => Ok(ConversationAction {
+                messages: vec![self.get_cf_headers()],
+                topic_finished: false,
+            }),
+            false => self.request_filters(),
+        }
+    }
+
+    fn on_cfilter(&mut self, filter: BlockFilter) -> PeerResult<ConversationAction> {
+        let index = self.filters.len();
+        if !self.filters_requested() || index == self.block_hashes.len() || filter.filter_type != BASIC_FILTER_TYPE {
+            return Err(PeerError::ProtocolViolation(format!("unexpected cfilter message for block {}", display_hash(&filter.block_hash))));
+        }
+        if filter.block_hash != self.block_hashes[index] {
+            return Err(PeerError::ProtocolViolation(format!(
+                "got the filter of block {} instead of {}", display_hash(&filter.block_hash), display_hash(&self.block_hashes[index])
+            )));
+        }
+        if filter.filter_hash() != self.filter_hashes[index] {
+            return Err(self.invalid(index, "filter does not match its filter hash"));
+        }
+        self.filters.push(filter);
+        let batch_finished = self.filters.len().is_multiple_of(MAX_GETCFILTERS_SIZE as usize);
+        Ok(ConversationAction {
+            messages: match batch_finished && self.filters.len() < self.block_hashes.len() {
+                true => vec![self.get_cfilters()],
+                false => vec![],
+            },
+            topic_finished: self.filters.len() == self.block_hashes.len(),
+        })
+    }
+
+    /// whether all filter headers and the checkpoints are known, which is when the filters are requested
+    fn filters_requested(&self) -> bool {
+        self.checkpoints.is_some() && self.filter_headers.len() == self.block_hashes.len()
+    }
+
+    /// requests the first batch of filters, once all filter headers are known and match the checkpoints
+    fn request_filters(&self) -> PeerResult<ConversationAction> {
+        if !self.filters_requested() {
+            return Ok(ConversationAction::nop());
+        }
+        self.verify_checkpoints()?;
+        Ok(ConversationAction {
+            messages: vec![self.get_cfilters()],
+            topic_finished: false,
+        })
+    }
+
+    fn verify_checkpoints(&self) -> PeerResult<()> {
+        let checkpoints = self.checkpoints.as_deref().unwrap_or_default();
+        for (i, checkpoint) in checkpoints.iter().enumerate() {
+            let height = (i as u32 + 1) * CFCHECKPT_INTERVAL - 1;
+            let header = match height.checked_sub(self.start_height) {
+                Some(index) => self.filter_headers.get(index as usize),
+                None if height + 1 == self.start_height => self.previous_filter_header.as_ref(),
+                None => None,
+            };
+            if header.is_some_and(|header| header != checkpoint) {
+                let index = height.saturating_sub(self.start_height) as usize;
+                return Err(self.invalid(index, "filter header does not match the checkpoint"));
+            }
+        }
+        Ok(())
+    }
+
+    fn invalid(&self, index: usize, reason: &str) -> PeerError {
+        PeerError::InvalidFilter { hash: display_hash(&self.block_hashes[index]), reason: reason.to_string() }
+    }
+}
+
+impl ConversationTopicHandler for FilterDownloadConversationTopic {
+    type Outcome = FilterDownload;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        match self.block_hashes.last() {
+            None => ConversationAction {
+                messages: vec![],
+                topic_finished: true,
+            },
+            Some(stop_hash) => ConversationAction {
+                messages: vec![
+                    ProtocolMessage::GetCFCheckpt(GetCFCheckptMessage::new(self.chain, BASIC_FILTER_TYPE, *stop_hash)),
+                    self.get_cf_headers(),
+                ],
+                topic_finished: false,
+            },
+        }
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::CFCheckpt(m) => self.on_cf_checkpt(m),
+            ProtocolMessage::CFHeaders(m) => self.on_cf_headers(m),
+            ProtocolMessage::CFilter(m) => self.on_cfilter(m.filter),
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn outcome(self) -> PeerResult<FilterDownload> {
+        Ok(FilterDownload { filters: self.filters, filter_headers: self.filter_headers })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::messages::CFilterMessage;
+
+    use super::*;
+
+    /// blocks with their filters (consisting of the block hash and height only)
+    fn blocks(count: u32) -> Vec<([u8; 32], BlockFilter)> {
+        (0..count)
+            .map(|height| {
+                let mut hash = [0; 32];
+                hash[..4].copy_from_slice(&height.to_le_bytes());
+                hash[31] = 0xff;
+                (hash, BlockFilter::new(BASIC_FILTER_TYPE, hash, height.to_le_bytes().to_vec()))
+            })
+            .collect()
+    }
+
+    fn headers(blocks: &[([u8; 32], BlockFilter)]) -> Vec<[u8; 32]> {
+        let mut previous = [0; 32];
+        blocks.iter()
+            .map(|(_, filter)| {
+                previous = filter.filter_header(&previous);
+                previous
+            })
+            .collect()
+    }
+
+    /// plays the serving node: answers the requests of an action
+    fn answer(action: ConversationAction, blocks: &[([u8; 32], BlockFilter)]) -> Vec<ProtocolMessage> {
+        let headers = headers(blocks);
+        let height_of = |hash: &[u8; 32]| blocks.iter().position(|(h, _)| h == hash).unwrap();
+        action.messages.into_iter()
+            .flat_map(|message| match message {
+                ProtocolMessage::GetCFCheckpt(m) => {
+                    let checkpoints = headers[..=height_of(&m.stop_hash)].iter()
+                        .skip(CFCHECKPT_INTERVAL as usize - 1)
+                        .step_by(CFCHECKPT_INTERVAL as usize)
+                        .copied()
+                        .collect();
+                    vec![ProtocolMessage::CFCheckpt(CFCheckptMessage::new(Chain::Regtest, 0, m.stop_hash, checkpoints))]
+                }
+                ProtocolMessage::GetCFHeaders(m) => {
+                    let (start, stop) = (m.start_height as usize, height_of(&m.stop_hash));
+                    let previous = match start {
+                        0 => [0; 32],
+                        _ => headers[start - 1],
+                    };
+                    let hashes = blocks[start..=stop].iter().map(|(_, f)| f.filter_hash()).collect();
+                    vec![ProtocolMessage::CFHeaders(CFHeadersMessage::new(Chain::Regtest, 0, m.stop_hash, previous, hashes))]
+                }
+                ProtocolMessage::GetCFilters(m) => {
+                    blocks[m.start_height as usize..=height_of(&m.stop_hash)].iter()
+                        .map(|(_, filter)| ProtocolMessage::CFilter(CFilterMessage::new(Chain::Regtest, filter.clone())))
+                        .collect()
+                }
+                other => panic!("unexpected request {:?}", other),
+            })
+            .collect()
+    }
+
+    /// runs the conversation, until the topic is finished
+    fn converse(topic: &mut FilterDownloadConversationTopic, blocks: &[([u8; 32], BlockFilter)]) -> PeerResult<()> {
+        let mut pending = answer(topic.initial_action(), blocks);
+        while !pending.is_empty() {
+            let action = topic.on_message(pending.remove(0))?;
+            if action.topic_finished {
+                return Ok(());
+            }
+            if !action.messages.is_empty() {
+                pending.extend(answer(action, blocks));
+            }
+        }
+        panic!("conversation stalled");
+    }
+
+    #[test]
+    fn test_filters_are_downloaded_in_batches() {
+        let blocks = blocks(2500);
+        let mut topic = FilterDownloadConversationTopic::new(Chain::Regtest, 0, blocks.iter().map(|(h, _)| *h).collect());
+        converse(&mut topic, &blocks).unwrap();
+
+        let download = topic.outcome().unwrap();
+        assert_eq!(download.filters, blocks.iter().map(|(_, f)| f.clone()).collect::<Vec<_>>());
+        assert_eq!(download.filter_headers, headers(&blocks));
+    }
+
+    #[test]
+    fn test_range_starting_after_a_checkpoint() {
+        let blocks = blocks(1500);
+        let hashes = blocks[1000..].iter().map(|(h, _)| *h).collect();
+        let mut topic = FilterDownloadConversationTopic::new(Chain::Regtest, 1000, hashes);
+        converse(&mut topic, &blocks).unwrap();
+
+        let download = topic.outcome().unwrap();
+        assert_eq!(download.filters.len(), 500);
+        assert_eq!(download.filter_headers[..], headers(&blocks)[1000..]);
+    }
+
+    #[test]
+    fn test_filter_not_matching_its_header_is_rejected() {
+        let mut blocks = blocks(10);
+        let mut topic = FilterDownloadConversationTopic::new(Chain::Regtest, 0, blocks.iter().map(|(h, _)| *h).collect());
+        let requests = topic.initial_action();
+        for message in answer(requests, &blocks) {
+            topic.on_message(message).unwrap();
+        }
+        blocks[3].1.content.push(0);
+        let filters = answer(ConversationAction { messages: vec![topic.get_cfilters()], topic_finished: false }, &blocks);
+        let mut results = filters.into_iter().map(|m| topic.on_message(m));
+        assert!(results.by_ref().take(3).all(|r| r.is_ok()));
+        assert!(matches!(results.next(), Some(Err(PeerError::InvalidFilter { .. }))));
+    }
+
+    #[test]
+    fn test_filter_headers_not_matching_the_checkpoint_are_rejected() {
+        let blocks = blocks(1200);
+        let mut topic = FilterDownloadConversationTopic::new(Chain::Regtest, 0, blocks.iter().map(|(h, _)| *h).collect());
+        let mut answers = answer(topic.initial_action(), &blocks);
+        match &mut answers[0] {
+            ProtocolMessage::CFCheckpt(m) => m.filter_headers[0] = [0; 32],
+            other => panic!("expected a cfcheckpt message, got {:?}", other),
+        }
+        topic.on_message(answers.remove(0)).unwrap();
+        let err = topic.on_message(answers.remove(0)).unwrap_err();
+        assert!(matches!(err, PeerError::InvalidFilter { .. }));
+    }
+}
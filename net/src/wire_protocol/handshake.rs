@@ -1,10 +1,35 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use crate::conversation::{ConversationAction, ConversationTopicHandler};
 use crate::error::{PeerError, PeerResult};
-use crate::wire_protocol::messages::{PongMessage, ProtocolMessage, VerackMessage, VersionMessage};
+use crate::wire_protocol::messages::{PongMessage, ProtocolMessage, SendAddrV2Message, VerackMessage, VersionMessage};
+use crate::wire_protocol::negotiation::Announcements;
 use crate::wire_protocol::node::NodeDesc;
 
+/// Nonces of all __version__ messages sent by our node, whose handshake is still in progress.
+///
+/// Receiving one of them in a __version__ message means, that we are connected to ourself
+/// (e.g. through NAT loopback or a misconfigured seed list).
+/// Clones share the same set, so one instance is meant to be shared by all connections of a node.
+#[derive(Clone, Debug, Default)]
+pub struct LocalNonces(Arc<Mutex<HashSet<u64>>>);
+
+impl LocalNonces {
+    pub fn register(&self, nonce: u64) {
+        self.0.lock().unwrap().insert(nonce);
+    }
+
+    pub fn unregister(&self, nonce: u64) {
+        self.0.lock().unwrap().remove(&nonce);
+    }
+
+    pub fn contains(&self, nonce: u64) -> bool {
+        self.0.lock().unwrap().contains(&nonce)
+    }
+}
+
 /// Handshake:
 ///
 /// NodeA <---> NodeB
@@ -14,36 +39,55 @@ use crate::wire_protocol::node::NodeDesc;
 /// - send __version__ message
 /// - expect __verack__ message
 /// - expect __version__ message
-/// - respond with __verack__ message
+/// - respond with __wtxidrelay__, __sendaddrv2__ (if supported by both) and __verack__ messages
+/// - once both __verack__ messages are exchanged, send our further announcements
+///   (__sendheaders__, __sendcmpct__, __feefilter__), see [Self::with_announcements]
+///
 /// => connected
 pub struct HandshakeInitConversationTopic {
     me: NodeDesc,
     remote_addr: SocketAddr,
+    local_nonces: LocalNonces,
+    announcements: Announcements,
+    /// nonce of our version message, once it was sent
+    version_msg_nonce: Option<u64>,
     version_msg_sent: bool,
     version_ack_msg_received: bool,
     version_msg_received: Option<VersionMessage>,
 }
 
 impl HandshakeInitConversationTopic {
-    pub fn new(me: &NodeDesc, remote_addr: SocketAddr) -> Self {
+    pub fn new(me: &NodeDesc, remote_addr: SocketAddr, local_nonces: &LocalNonces) -> Self {
         HandshakeInitConversationTopic {
             me: me.clone(),
             remote_addr,
+            local_nonces: local_nonces.clone(),
+            announcements: Announcements::default(),
+            version_msg_nonce: None,
             version_msg_sent: false,
             version_ack_msg_received: false,
             version_msg_received: None,
         }
     }
+
+    /// optional features to announce to the remote node (none by default)
+    pub fn with_announcements(mut self, announcements: Announcements) -> Self {
+        self.announcements = announcements;
+        self
+    }
 }
 
 impl ConversationTopicHandler for HandshakeInitConversationTopic {
     type Outcome = NodeDesc;
 
     fn initial_action(&mut self) -> ConversationAction {
-        let message = ProtocolMessage::Version(VersionMessage::new(self.remote_addr, &self.me));
+        let version_msg = VersionMessage::new(self.remote_addr, &self.me);
+        self.local_nonces.register(version_msg.nonce);
+        self.version_msg_nonce = Some(version_msg.nonce);
+        let message = ProtocolMessage::Version(version_msg);
         self.version_msg_sent = true;
         ConversationAction {
-            message: Some(message),
+            messages: vec![message],
             topic_finished: false,
         }
     }
@@ -51,50 +95,350 @@ impl ConversationTopicHandler for HandshakeInitConversationTopic {
     fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
         match message {
             ProtocolMessage::Version(m) => {
-                self.version_msg_received = Some(m);
-                let reply_msg = ProtocolMessage::Verack(VerackMessage::new(self.me.chain));
+                check_self_connection(&self.local_nonces, &m, self.remote_addr)?;
+                let mut messages = version_replies(&self.me, &self.announcements, &m);
                 let topic_finished = self.version_msg_sent && self.version_ack_msg_received;
+                if topic_finished {
+                    messages.extend(self.announcements.after_verack(&self.me, m.protocol_version));
+                }
+                self.version_msg_received = Some(m);
                 Ok(ConversationAction {
-                    message: Some(reply_msg),
+                    messages,
                     topic_finished,
                 })
             }
             ProtocolMessage::Verack(_) => {
                 self.version_ack_msg_received = true;
                 if !self.version_msg_sent {
-                    Err(PeerError::from("Protocol error: received a 'verack', but no 'version' was sent yet"))
+                    Err(PeerError::ProtocolViolation("received a 'verack', but no 'version' was sent yet".to_string()))
                 } else {
-                    let topic_finished = self.version_msg_received.is_some();
-                    Ok(ConversationAction {
-                        message: None,
-                        topic_finished,
-                    })
+                    match &self.version_msg_received {
+                        Some(m) => Ok(ConversationAction {
+                            messages: self.announcements.after_verack(&self.me, m.protocol_version),
+                            topic_finished: true,
+                        }),
+                        None => Ok(ConversationAction::nop()),
+                    }
                 }
             }
-            ProtocolMessage::Ping(_) => {
+            ProtocolMessage::Ping(ping) => {
                 Ok(ConversationAction {
-                    message: Some(ProtocolMessage::Pong(PongMessage::new(self.me.chain))),
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
                     topic_finished: false,
                 })
             }
-            ProtocolMessage::Pong(_) => {
+            ProtocolMessage::SendAddrV2(_) if self.version_ack_msg_received => {
+                Err(PeerError::ProtocolViolation("received a 'sendaddrv2' after 'verack'".to_string()))
+            }
+            ProtocolMessage::WtxidRelay(_) if self.version_ack_msg_received => {
+                Err(PeerError::ProtocolViolation("received a 'wtxidrelay' after 'verack'".to_string()))
+            }
+            _ => {
                 Ok(ConversationAction::nop())
             }
         }
     }
 
-    fn outcome(self) -> PeerResult<NodeDesc> {
-        match self.version_msg_received {
-            None => Err(PeerError::from("should have a version message from remote node")),
-            Some(msg) => Ok(
-                NodeDesc {
-                    chain: self.me.chain,
-                    protocol_version: msg.protocol_version,
-                    services: msg.services.clone(),
-                    sub_ver: msg.sub_ver.clone(),
-                    start_height: msg.start_height,
-                }
-            )
+    fn outcome(mut self) -> PeerResult<NodeDesc> {
+        remote_node_desc(&self.me, self.version_msg_received.take())
+    }
+}
+
+impl Drop for HandshakeInitConversationTopic {
+    fn drop(&mut self) {
+        if let Some(nonce) = self.version_msg_nonce {
+            self.local_nonces.unregister(nonce);
+        }
+    }
+}
+
+/// Handshake of the responding node (inbound connection):
+///
+/// NodeA ---> NodeB (we)
+///
+/// - wait for the __version__ message of the remote node
+/// - respond with __version__, __wtxidrelay__, __sendaddrv2__ (if supported by both) and __verack__ messages
+/// - expect __verack__ message (__wtxidrelay__ and __sendaddrv2__ messages may precede it)
+/// - send our further announcements (__sendheaders__, __sendcmpct__, __feefilter__), see [Self::with_announcements]
+///
+/// => connected
+///
+/// Any other message received before the handshake is complete is a protocol violation.
+pub struct HandshakeRespondConversationTopic {
+    me: NodeDesc,
+    remote_addr: SocketAddr,
+    local_nonces: LocalNonces,
+    announcements: Announcements,
+    version_msg_received: Option<VersionMessage>,
+}
+
+impl HandshakeRespondConversationTopic {
+    pub fn new(me: &NodeDesc, remote_addr: SocketAddr, local_nonces: &LocalNonces) -> Self {
+        HandshakeRespondConversationTopic {
+            me: me.clone(),
+            remote_addr,
+            local_nonces: local_nonces.clone(),
+            announcements: Announcements::default(),
+            version_msg_received: None,
         }
     }
+
+    /// optional features to announce to the remote node (none by default)
+    pub fn with_announcements(mut self, announcements: Announcements) -> Self {
+        self.announcements = announcements;
+        self
+    }
+}
+
+impl ConversationTopicHandler for HandshakeRespondConversationTopic {
+    type Outcome = NodeDesc;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        // the remote node starts
+        ConversationAction::nop()
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match (message, &self.version_msg_received) {
+            (ProtocolMessage::Version(m), None) => {
+                check_self_connection(&self.local_nonces, &m, self.remote_addr)?;
+                let mut messages = vec![ProtocolMessage::Version(VersionMessage::new(self.remote_addr, &self.me))];
+                messages.extend(version_replies(&self.me, &self.announcements, &m));
+                self.version_msg_received = Some(m);
+                Ok(ConversationAction {
+                    messages,
+                    topic_finished: false,
+                })
+            }
+            (ProtocolMessage::SendAddrV2(_) | ProtocolMessage::WtxidRelay(_), Some(_)) => {
+                Ok(ConversationAction::nop())
+            }
+            (ProtocolMessage::Verack(_), Some(m)) => {
+                Ok(ConversationAction {
+                    messages: self.announcements.after_verack(&self.me, m.protocol_version),
+                    topic_finished: true,
+                })
+            }
+            (message, _) => {
+                Err(PeerError::ProtocolViolation(format!("unexpected message before handshake completed: {:?}", message)))
+            }
+        }
+    }
+
+    fn outcome(mut self) -> PeerResult<NodeDesc> {
+        remote_node_desc(&self.me, self.version_msg_received.take())
+    }
+}
+
+/// __addrv2__ support is announced to peers of at least this protocol version (BIP155)
+const SENDADDRV2_MIN_VERSION: i32 = 70016;
+
+/// our messages in reply to the __version__ message of the remote node, finished by __verack__
+fn version_replies(me: &NodeDesc, announcements: &Announcements, version_msg: &VersionMessage) -> Vec<ProtocolMessage> {
+    let mut messages = announcements.before_verack(me, version_msg.protocol_version);
+    if me.protocol_version.min(version_msg.protocol_version) >= SENDADDRV2_MIN_VERSION {
+        messages.push(ProtocolMessage::SendAddrV2(SendAddrV2Message::new(me.chain)));
+    }
+    messages.push(ProtocolMessage::Verack(VerackMessage::new(me.chain)));
+    messages
+}
+
+fn check_self_connection(local_nonces: &LocalNonces, version_msg: &VersionMessage, remote_addr: SocketAddr) -> PeerResult<()> {
+    if local_nonces.contains(version_msg.nonce) {
+        log::warn!("connected to ourself through {}", remote_addr);
+        Err(PeerError::SelfConnection { nonce: version_msg.nonce })
+    } else {
+        Ok(())
+    }
+}
+
+fn remote_node_desc(me: &NodeDesc, version_msg: Option<VersionMessage>) -> PeerResult<NodeDesc> {
+    match version_msg {
+        None => Err(PeerError::ProtocolViolation("should have a version message from remote node".to_string())),
+        Some(msg) => Ok(
+            NodeDesc {
+                chain: me.chain,
+                protocol_version: msg.protocol_version,
+                sub_ver: msg.user_agent().into_owned(),
+                services: msg.services,
+                start_height: msg.start_height,
+                // peers without the relay field (before BIP37) relay transactions
+                relay: msg.relay.unwrap_or(true),
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::messages::{PingMessage, WtxidRelayMessage};
+    use crate::wire_protocol::negotiation::CompactBlocks;
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
+
+    fn sent_version(action: ConversationAction) -> VersionMessage {
+        match action.messages.into_iter().next() {
+            Some(ProtocolMessage::Version(m)) => m,
+            other => panic!("expected a version message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_self_connection_is_detected() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let nonces = LocalNonces::default();
+        let mut topic = HandshakeInitConversationTopic::new(&me, remote_addr, &nonces);
+        let version = sent_version(topic.initial_action());
+        assert!(nonces.contains(version.nonce));
+
+        let err = topic.on_message(ProtocolMessage::Version(version.clone())).unwrap_err();
+        assert!(matches!(err, PeerError::SelfConnection { nonce } if nonce == version.nonce));
+
+        drop(topic);
+        assert!(!nonces.contains(version.nonce));
+    }
+
+    #[test]
+    fn test_self_connection_is_detected_across_connections() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let nonces = LocalNonces::default();
+        let mut outbound = HandshakeInitConversationTopic::new(&me, remote_addr, &nonces);
+        let mut other = HandshakeInitConversationTopic::new(&me, remote_addr, &nonces);
+        let version = sent_version(outbound.initial_action());
+        other.initial_action();
+
+        let err = other.on_message(ProtocolMessage::Version(version.clone())).unwrap_err();
+        assert!(matches!(err, PeerError::SelfConnection { nonce } if nonce == version.nonce));
+    }
+
+    #[test]
+    fn test_handshake_with_remote_node() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let nonces = LocalNonces::default();
+        let mut topic = HandshakeInitConversationTopic::new(&me, remote_addr, &nonces);
+        topic.initial_action();
+
+        let mut remote_version = VersionMessage::new(remote_addr, &me);
+        remote_version.sub_ver = b"/Satoshi:24.0.1/".to_vec();
+        remote_version.start_height = 112;
+        let action = topic.on_message(ProtocolMessage::Version(remote_version)).unwrap();
+        assert!(matches!(action.messages[..], [ProtocolMessage::SendAddrV2(_), ProtocolMessage::Verack(_)]));
+        assert!(!action.topic_finished);
+        let action = topic.on_message(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).unwrap();
+        assert!(action.topic_finished);
+
+        let remote = topic.outcome().unwrap();
+        assert_eq!(remote.sub_ver, "/Satoshi:24.0.1/");
+        assert_eq!(remote.start_height, 112);
+        assert!(nonces.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_respond_to_handshake() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:50123".parse().unwrap();
+        let mut topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+        assert!(topic.initial_action().messages.is_empty());
+
+        let mut remote_version = VersionMessage::new("127.0.0.1:18444".parse().unwrap(), &me);
+        remote_version.start_height = 7;
+        let action = topic.on_message(ProtocolMessage::Version(remote_version)).unwrap();
+        match &action.messages[..] {
+            [ProtocolMessage::Version(v), ProtocolMessage::SendAddrV2(_), ProtocolMessage::Verack(_)] => assert_eq!(v.addr_recv, remote_addr),
+            other => panic!("expected version and verack, got {:?}", other),
+        }
+        assert!(!action.topic_finished);
+
+        let action = topic.on_message(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).unwrap();
+        assert!(action.topic_finished);
+        assert_eq!(topic.outcome().unwrap().start_height, 7);
+    }
+
+    #[test]
+    fn test_respond_rejects_messages_before_version() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:50123".parse().unwrap();
+        let mut topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+        topic.initial_action();
+        assert!(topic.on_message(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).is_err());
+    }
+
+    #[test]
+    fn test_respond_rejects_messages_before_verack() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:50123".parse().unwrap();
+        let mut topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+        topic.initial_action();
+        let remote_version = VersionMessage::new("127.0.0.1:18444".parse().unwrap(), &me);
+        topic.on_message(ProtocolMessage::Version(remote_version.clone())).unwrap();
+
+        assert!(topic.on_message(ProtocolMessage::Ping(PingMessage::new(Chain::Regtest))).is_err());
+    }
+
+    #[test]
+    fn test_respond_detects_self_connection() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let nonces = LocalNonces::default();
+        let mut outbound = HandshakeInitConversationTopic::new(&me, "127.0.0.1:18444".parse().unwrap(), &nonces);
+        let version = sent_version(outbound.initial_action());
+
+        let mut inbound = HandshakeRespondConversationTopic::new(&me, "127.0.0.1:50123".parse().unwrap(), &nonces);
+        let err = inbound.on_message(ProtocolMessage::Version(version.clone())).unwrap_err();
+        assert!(matches!(err, PeerError::SelfConnection { nonce } if nonce == version.nonce));
+    }
+
+    #[test]
+    fn test_no_sendaddrv2_for_old_peers() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let mut topic = HandshakeInitConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+        topic.initial_action();
+
+        let mut remote_version = VersionMessage::new(remote_addr, &me);
+        remote_version.protocol_version = 70015;
+        let action = topic.on_message(ProtocolMessage::Version(remote_version)).unwrap();
+        assert!(matches!(action.messages[..], [ProtocolMessage::Verack(_)]));
+    }
+
+    #[test]
+    fn test_sendaddrv2_after_verack_is_rejected() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let mut topic = HandshakeInitConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+        topic.initial_action();
+
+        topic.on_message(ProtocolMessage::SendAddrV2(SendAddrV2Message::new(Chain::Regtest))).unwrap();
+        topic.on_message(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).unwrap();
+        assert!(topic.on_message(ProtocolMessage::SendAddrV2(SendAddrV2Message::new(Chain::Regtest))).is_err());
+    }
+
+    #[test]
+    fn test_announcements_around_verack() {
+        let me = NodeDesc::test_node("/test:1.0/");
+        let remote_addr = "127.0.0.1:18444".parse().unwrap();
+        let announcements = Announcements {
+            wtxid_relay: true,
+            send_headers: true,
+            compact_blocks: vec![CompactBlocks { announce: false, version: 2 }],
+            fee_filter: None,
+        };
+        let mut topic = HandshakeInitConversationTopic::new(&me, remote_addr, &LocalNonces::default())
+            .with_announcements(announcements);
+        topic.initial_action();
+
+        topic.on_message(ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(Chain::Regtest))).unwrap();
+        let action = topic.on_message(ProtocolMessage::Version(VersionMessage::new(remote_addr, &me))).unwrap();
+        assert!(matches!(action.messages[..],
+            [ProtocolMessage::WtxidRelay(_), ProtocolMessage::SendAddrV2(_), ProtocolMessage::Verack(_)]));
+
+        let action = topic.on_message(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).unwrap();
+        assert!(action.topic_finished);
+        assert!(matches!(action.messages[..], [ProtocolMessage::SendHeaders(_), ProtocolMessage::SendCmpct(_)]));
+
+        assert!(topic.on_message(ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(Chain::Regtest))).is_err());
+    }
 }
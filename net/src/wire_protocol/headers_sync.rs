@@ -0,0 +1,204 @@
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::{BlockHeader, display_hash};
+use crate::wire_protocol::messages::{GetHeadersMessage, MAX_HEADERS_RESULTS, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::NodeDesc;
+
+/// Headers synchronization (after the handshake):
+///
+/// - send __getheaders__ with a block locator of the headers known so far
+/// - expect __headers__ answers, each one extending our chain; request more as long as an answer is full (2000 headers)
+///
+/// Every header has to connect to its predecessor and satisfy its own proof of work. Forks are not followed: headers,
+/// which do not connect to our tip, are a [PeerError::InvalidHeader].
+///
+/// The outcome is the header chain, starting with the genesis block, so that the index of a header is its height.
+pub struct HeadersSyncConversationTopic {
+    me: NodeDesc,
+    headers: Vec<BlockHeader>,
+    tip_hash: [u8; 32],
+}
+
+impl HeadersSyncConversationTopic {
+    pub fn new(me: &NodeDesc) -> Self {
+        let genesis = me.chain.genesis_header();
+        HeadersSyncConversationTopic {
+            me: me.clone(),
+            headers: vec![genesis],
+            tip_hash: genesis.block_hash(),
+        }
+    }
+
+    /// Hashes of the most recent headers one by one, then going back in exponentially growing steps, always ending with genesis
+    /// (see `CChain::GetLocator` in bitcoin core)
+    fn locator(&self) -> Vec<[u8; 32]> {
+        let mut locator = vec![];
+        let mut height = self.headers.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(self.headers[height].block_hash());
+            if height == 0 {
+                break;
+            }
+            height = height.saturating_sub(step);
+            if locator.len() > 10 {
+                step *= 2;
+            }
+        }
+        locator
+    }
+
+    fn get_headers(&self) -> ConversationAction {
+        let locator = self.locator();
+        ConversationAction {
+            messages: vec![ProtocolMessage::GetHeaders(
+                GetHeadersMessage::new(self.me.chain, self.me.protocol_version as u32, locator, [0; 32])
+            )],
+            topic_finished: false,
+        }
+    }
+
+    fn extend(&mut self, headers: Vec<BlockHeader>) -> PeerResult<()> {
+        for header in headers {
+            if header.prev_block != self.tip_hash {
+                return Err(PeerError::InvalidHeader {
+                    hash: display_hash(&header.block_hash()),
+                    reason: format!("does not connect to our tip {}", display_hash(&self.tip_hash)),
+                });
+            }
+            header.validate_pow(self.me.chain)?;
+            self.tip_hash = header.block_hash();
+            self.headers.push(header);
+        }
+        Ok(())
+    }
+}
+
+impl ConversationTopicHandler for HeadersSyncConversationTopic {
+    type Outcome = Vec<BlockHeader>;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        self.get_headers()
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::Headers(m) => {
+                let full = m.headers.len() == MAX_HEADERS_RESULTS;
+                self.extend(m.headers)?;
+                log::debug!("synchronized headers up to height {}", self.headers.len() - 1);
+                match full {
+                    true => Ok(self.get_headers()),
+                    false => Ok(ConversationAction {
+                        messages: vec![],
+                        topic_finished: true,
+                    }),
+                }
+            }
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn outcome(self) -> PeerResult<Vec<BlockHeader>> {
+        Ok(self.headers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::messages::HeadersMessage;
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
+
+    /// regtest headers on top of `prev`, mined with the minimal difficulty
+    fn mine(prev: &BlockHeader, count: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for i in 0..count {
+            let mut header = BlockHeader {
+                prev_block: headers.last().unwrap_or(prev).block_hash(),
+                time: prev.time + 1 + i as u32,
+                nonce: 0,
+                ..*prev
+            };
+            while header.validate_pow(Chain::Regtest).is_err() {
+                header.nonce += 1;
+            }
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn sent_locator(action: ConversationAction) -> Vec<[u8; 32]> {
+        match action.messages.into_iter().next() {
+            Some(ProtocolMessage::GetHeaders(m)) => m.locator,
+            other => panic!("expected a getheaders message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_headers_are_requested_until_answer_is_not_full() {
+        let mut topic = HeadersSyncConversationTopic::new(&NodeDesc::test_node("/test/"));
+        let genesis = Chain::Regtest.genesis_header();
+        assert_eq!(sent_locator(topic.initial_action()), vec![genesis.block_hash()]);
+
+        let first = mine(&genesis, MAX_HEADERS_RESULTS);
+        let tip = *first.last().unwrap();
+        let action = topic.on_message(ProtocolMessage::Headers(HeadersMessage::new(Chain::Regtest, first))).unwrap();
+        assert!(!action.topic_finished);
+        let locator = sent_locator(action);
+        assert_eq!(locator[0], tip.block_hash());
+        assert_eq!(*locator.last().unwrap(), genesis.block_hash());
+
+        let second = mine(&tip, 5);
+        let action = topic.on_message(ProtocolMessage::Headers(HeadersMessage::new(Chain::Regtest, second.clone()))).unwrap();
+        assert!(action.topic_finished);
+
+        let chain = topic.outcome().unwrap();
+        assert_eq!(chain.len(), 1 + MAX_HEADERS_RESULTS + 5);
+        assert_eq!(chain.last(), second.last());
+    }
+
+    #[test]
+    fn test_locator_is_dense_at_the_tip_and_sparse_towards_genesis() {
+        let mut topic = HeadersSyncConversationTopic::new(&NodeDesc::test_node("/test/"));
+        let headers = mine(&Chain::Regtest.genesis_header(), 100);
+        topic.extend(headers).unwrap();
+
+        let locator = topic.locator();
+        // as computed by `CChain::GetLocator` in bitcoin core
+        let expected_heights = [100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 90, 89, 87, 83, 75, 59, 27, 0];
+        assert_eq!(locator.len(), expected_heights.len());
+        for (hash, height) in locator.iter().zip(expected_heights) {
+            assert_eq!(*hash, topic.headers[height].block_hash());
+        }
+    }
+
+    #[test]
+    fn test_unconnected_header_is_rejected() {
+        let mut topic = HeadersSyncConversationTopic::new(&NodeDesc::test_node("/test/"));
+        topic.initial_action();
+        let mut headers = mine(&Chain::Regtest.genesis_header(), 3);
+        headers.remove(1);
+        let err = topic.on_message(ProtocolMessage::Headers(HeadersMessage::new(Chain::Regtest, headers))).unwrap_err();
+        assert!(matches!(err, PeerError::InvalidHeader { .. }));
+    }
+
+    #[test]
+    fn test_header_without_pow_is_rejected() {
+        let mut topic = HeadersSyncConversationTopic::new(&NodeDesc::test_node("/test/"));
+        topic.initial_action();
+        let mut headers = mine(&Chain::Regtest.genesis_header(), 1);
+        while headers[0].validate_pow(Chain::Regtest).is_ok() {
+            headers[0].nonce += 1;
+        }
+        let err = topic.on_message(ProtocolMessage::Headers(HeadersMessage::new(Chain::Regtest, headers))).unwrap_err();
+        assert!(matches!(err, PeerError::InvalidHeader { .. }));
+    }
+}
@@ -0,0 +1,166 @@
+use std::io;
+
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+
+/// Maximum number of entries in an __inv__, __getdata__ or __notfound__ message (see `MAX_INV_SZ` in bitcoin core)
+pub const MAX_INV_SZ: usize = 50_000;
+
+/// marks inventory types, which request objects including their witness data (BIP144)
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InventoryType {
+    /// MSG_TX: transaction, identified by its txid
+    Tx,
+    /// MSG_BLOCK: block, identified by its hash
+    Block,
+    /// MSG_FILTERED_BLOCK: block to be answered with a __merkleblock__ message (BIP37)
+    FilteredBlock,
+    /// MSG_CMPCT_BLOCK: block to be answered with a __cmpctblock__ message (BIP152)
+    CmpctBlock,
+    /// MSG_WTX: transaction, identified by its wtxid (BIP339)
+    WTx,
+    /// MSG_WITNESS_TX: transaction including witness data
+    WitnessTx,
+    /// MSG_WITNESS_BLOCK: block including witness data
+    WitnessBlock,
+    /// MSG_FILTERED_WITNESS_BLOCK
+    FilteredWitnessBlock,
+    /// any other type, including the undefined type 0 (ERROR)
+    Unknown(u32),
+}
+
+impl InventoryType {
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            InventoryType::Tx => 1,
+            InventoryType::Block => 2,
+            InventoryType::FilteredBlock => 3,
+            InventoryType::CmpctBlock => 4,
+            InventoryType::WTx => 5,
+            InventoryType::WitnessTx => MSG_WITNESS_FLAG | 1,
+            InventoryType::WitnessBlock => MSG_WITNESS_FLAG | 2,
+            InventoryType::FilteredWitnessBlock => MSG_WITNESS_FLAG | 3,
+            InventoryType::Unknown(value) => *value,
+        }
+    }
+
+    pub fn from_u32(value: u32) -> Self {
+        match value {
+            1 => InventoryType::Tx,
+            2 => InventoryType::Block,
+            3 => InventoryType::FilteredBlock,
+            4 => InventoryType::CmpctBlock,
+            5 => InventoryType::WTx,
+            v if v == MSG_WITNESS_FLAG | 1 => InventoryType::WitnessTx,
+            v if v == MSG_WITNESS_FLAG | 2 => InventoryType::WitnessBlock,
+            v if v == MSG_WITNESS_FLAG | 3 => InventoryType::FilteredWitnessBlock,
+            v => InventoryType::Unknown(v),
+        }
+    }
+
+    /// the type to request an announced object including its witness data
+    pub fn with_witness(&self) -> Self {
+        match self {
+            InventoryType::Tx => InventoryType::WitnessTx,
+            InventoryType::Block => InventoryType::WitnessBlock,
+            InventoryType::FilteredBlock => InventoryType::FilteredWitnessBlock,
+            other => *other,
+        }
+    }
+
+    pub fn is_tx(&self) -> bool {
+        matches!(self, InventoryType::Tx | InventoryType::WTx | InventoryType::WitnessTx)
+    }
+
+    pub fn is_block(&self) -> bool {
+        matches!(self, InventoryType::Block | InventoryType::FilteredBlock | InventoryType::CmpctBlock
+            | InventoryType::WitnessBlock | InventoryType::FilteredWitnessBlock)
+    }
+}
+
+/// Inventory vector, referencing an object (e.g. a transaction or block) by its hash
+///
+/// size | field | type     | description
+/// ---  | ----- | ----     | ------------
+/// 4    | type  | u32      | type of the object
+/// 32   | hash  | [u8; 32] | hash of the object (internal byte order)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub inv_type: InventoryType,
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn new(inv_type: InventoryType, hash: [u8; 32]) -> Self {
+        Inventory { inv_type, hash }
+    }
+
+    /// list of inventory vectors with CompactSize count prefix, limited to [MAX_INV_SZ] entries
+    pub(super) fn parse_list(parser: &mut ByteBufferParser) -> io::Result<Vec<Inventory>> {
+        let count = parser.read_length(MAX_INV_SZ)?;
+        (0..count)
+            .map(|_| {
+                let inv_type = InventoryType::from_u32(parser.read_u32_le()?);
+                let hash = parser.read(32)?.try_into().unwrap();
+                Ok(Inventory { inv_type, hash })
+            })
+            .collect()
+    }
+
+    pub(super) fn append_list(composer: &mut ByteBufferComposer, inventory: &[Inventory]) {
+        composer.append_compact_size(inventory.len() as u64);
+        for inv in inventory {
+            composer.append(&inv.inv_type.as_u32().to_le_bytes());
+            composer.append(&inv.hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use super::*;
+
+    #[rstest]
+    #[case(InventoryType::Tx, 1)]
+    #[case(InventoryType::Block, 2)]
+    #[case(InventoryType::FilteredBlock, 3)]
+    #[case(InventoryType::CmpctBlock, 4)]
+    #[case(InventoryType::WTx, 5)]
+    #[case(InventoryType::WitnessTx, 0x40000001)]
+    #[case(InventoryType::WitnessBlock, 0x40000002)]
+    #[case(InventoryType::FilteredWitnessBlock, 0x40000003)]
+    #[case(InventoryType::Unknown(0), 0)]
+    #[case(InventoryType::Unknown(0x40000005), 0x40000005)]
+    fn test_inventory_type_values(#[case] inv_type: InventoryType, #[case] value: u32) {
+        assert_eq!(inv_type.as_u32(), value);
+        assert_eq!(InventoryType::from_u32(value), inv_type);
+    }
+
+    #[test]
+    fn test_inventory_list_round_trip() {
+        let encoded = hex!("02"
+            "01000000" "0101010101010101010101010101010101010101010101010101010101010101"
+            "02000040" "0202020202020202020202020202020202020202020202020202020202020202");
+        let mut parser = ByteBufferParser::new(&encoded);
+        let inventory = Inventory::parse_list(&mut parser).unwrap();
+        assert_eq!(inventory, vec![
+            Inventory::new(InventoryType::Tx, [1; 32]),
+            Inventory::new(InventoryType::WitnessBlock, [2; 32]),
+        ]);
+        assert_eq!(parser.remaining(), 0);
+
+        let mut composer = ByteBufferComposer::new();
+        Inventory::append_list(&mut composer, &inventory);
+        assert_eq!(composer.result(), encoded);
+    }
+
+    #[test]
+    fn test_inventory_list_limit() {
+        // 50001 entries announced
+        assert!(Inventory::parse_list(&mut ByteBufferParser::new(&hex!("fd51c3"))).is_err());
+    }
+}
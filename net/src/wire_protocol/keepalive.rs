@@ -0,0 +1,246 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::messages::{PingMessage, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::Chain;
+
+#[derive(Clone, Debug)]
+pub struct KeepaliveConfig {
+    /// time between two pings (see `PING_INTERVAL` in bitcoin core)
+    pub ping_interval: Duration,
+    /// number of consecutive pings without a matching pong, after which the connection is given up
+    pub max_missed_pongs: u32,
+    /// number of answered pings, after which the topic is finished. `None` keeps it running until an error occurs.
+    pub rounds: Option<u32>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            ping_interval: Duration::from_secs(2 * 60),
+            max_missed_pongs: 10,
+            rounds: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    /// round-trip time of the latest answered ping
+    pub last: Option<Duration>,
+    /// lowest round-trip time seen so far
+    pub min: Option<Duration>,
+    pub pongs_received: u32,
+}
+
+/// Round-trip times measured by a [KeepaliveConversationTopic].
+///
+/// Clones share the same data, so the latency of a peer can be observed while its keepalive topic is running.
+#[derive(Clone, Debug, Default)]
+pub struct PeerLatency(Arc<Mutex<LatencyStats>>);
+
+impl PeerLatency {
+    pub fn stats(&self) -> LatencyStats {
+        *self.0.lock().unwrap()
+    }
+
+    fn record(&self, rtt: Duration) {
+        let mut stats = self.0.lock().unwrap();
+        stats.last = Some(rtt);
+        stats.min = Some(stats.min.map_or(rtt, |min| min.min(rtt)));
+        stats.pongs_received += 1;
+    }
+}
+
+/// Keepalive (BIP31):
+///
+/// - send a __ping__ message with a random nonce every `ping_interval`
+/// - expect a __pong__ message echoing that nonce and record the round-trip time
+/// - answer __ping__ messages of the remote node with a __pong__ message
+///
+/// Gives up with [PeerError::Timeout] after `max_missed_pongs` consecutive unanswered pings. A pong answering one of the
+/// last `max_missed_pongs` pings counts, even if it arrives after the next ping was sent.
+pub struct KeepaliveConversationTopic {
+    chain: Chain,
+    config: KeepaliveConfig,
+    latency: PeerLatency,
+    /// nonce and send time of the unanswered pings, oldest first, at most `max_missed_pongs`
+    outstanding_pings: VecDeque<(u64, Instant)>,
+    missed_pongs: u32,
+    rounds: u32,
+}
+
+impl KeepaliveConversationTopic {
+    pub fn new(chain: Chain, config: KeepaliveConfig, latency: &PeerLatency) -> Self {
+        KeepaliveConversationTopic {
+            chain,
+            config,
+            latency: latency.clone(),
+            outstanding_pings: VecDeque::new(),
+            missed_pongs: 0,
+            rounds: 0,
+        }
+    }
+
+    fn ping(&mut self, now: Instant) -> ConversationAction {
+        let ping = PingMessage::new(self.chain);
+        if self.outstanding_pings.len() >= self.config.max_missed_pongs.max(1) as usize {
+            self.outstanding_pings.pop_front();
+        }
+        self.outstanding_pings.push_back((ping.nonce, now));
+        ConversationAction {
+            messages: vec![ProtocolMessage::Ping(ping)],
+            topic_finished: false,
+        }
+    }
+}
+
+impl ConversationTopicHandler for KeepaliveConversationTopic {
+    type Outcome = LatencyStats;
+
+    fn initial_action(&mut self) -> ConversationAction {
+        self.ping(Instant::now())
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            ProtocolMessage::Pong(pong) => {
+                match self.outstanding_pings.iter().position(|(nonce, _)| *nonce == pong.nonce) {
+                    Some(pos) => {
+                        let rtt = self.outstanding_pings[pos].1.elapsed();
+                        log::debug!("ping round-trip time: {:?}", rtt);
+                        self.latency.record(rtt);
+                        // pongs arrive in order, pings sent before the answered one won't be answered anymore
+                        self.outstanding_pings.drain(..=pos);
+                        self.missed_pongs = 0;
+                        self.rounds += 1;
+                        Ok(ConversationAction {
+                            messages: vec![],
+                            topic_finished: self.config.rounds.is_some_and(|rounds| self.rounds >= rounds),
+                        })
+                    }
+                    None => {
+                        log::debug!("ignoring pong with unexpected nonce {}", pong.nonce);
+                        Ok(ConversationAction::nop())
+                    }
+                }
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.config.ping_interval)
+    }
+
+    fn on_tick(&mut self, now: Instant) -> PeerResult<ConversationAction> {
+        if !self.outstanding_pings.is_empty() {
+            self.missed_pongs += 1;
+            if self.missed_pongs >= self.config.max_missed_pongs {
+                return Err(PeerError::Timeout(format!("no pong received for {} consecutive pings", self.missed_pongs)));
+            }
+        }
+        Ok(self.ping(now))
+    }
+
+    fn outcome(self) -> PeerResult<LatencyStats> {
+        Ok(self.latency.stats())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sent_ping(action: ConversationAction) -> PingMessage {
+        match action.messages.into_iter().next() {
+            Some(ProtocolMessage::Ping(m)) => m,
+            other => panic!("expected a ping message, got {:?}", other),
+        }
+    }
+
+    fn pong(nonce: u64) -> ProtocolMessage {
+        ProtocolMessage::Pong(PongMessage::new(Chain::Regtest, nonce))
+    }
+
+    #[test]
+    fn test_round_trip_time_is_recorded() {
+        let latency = PeerLatency::default();
+        let config = KeepaliveConfig { rounds: Some(2), ..KeepaliveConfig::default() };
+        let mut topic = KeepaliveConversationTopic::new(Chain::Regtest, config, &latency);
+
+        let ping = sent_ping(topic.initial_action());
+        let action = topic.on_message(pong(ping.nonce.wrapping_add(1))).unwrap();
+        assert!(!action.topic_finished);
+        assert_eq!(latency.stats().pongs_received, 0);
+
+        let action = topic.on_message(pong(ping.nonce)).unwrap();
+        assert!(!action.topic_finished);
+        assert_eq!(latency.stats().pongs_received, 1);
+        assert!(latency.stats().last.is_some());
+
+        let ping = sent_ping(topic.on_tick(Instant::now()).unwrap());
+        let action = topic.on_message(pong(ping.nonce)).unwrap();
+        assert!(action.topic_finished);
+        assert_eq!(topic.outcome().unwrap().pongs_received, 2);
+    }
+
+    #[test]
+    fn test_remote_ping_is_answered_with_its_nonce() {
+        let mut topic = KeepaliveConversationTopic::new(Chain::Regtest, KeepaliveConfig::default(), &PeerLatency::default());
+        topic.initial_action();
+
+        let ping = PingMessage::new(Chain::Regtest);
+        let nonce = ping.nonce;
+        match topic.on_message(ProtocolMessage::Ping(ping)).unwrap().messages.into_iter().next() {
+            Some(ProtocolMessage::Pong(pong)) => assert_eq!(pong.nonce, nonce),
+            other => panic!("expected a pong message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_missed_pongs_time_out() {
+        let config = KeepaliveConfig { max_missed_pongs: 3, ..KeepaliveConfig::default() };
+        let mut topic = KeepaliveConversationTopic::new(Chain::Regtest, config, &PeerLatency::default());
+        topic.initial_action();
+
+        assert!(topic.on_tick(Instant::now()).is_ok());
+        let ping = sent_ping(topic.on_tick(Instant::now()).unwrap());
+        // an answer resets the counter
+        topic.on_message(pong(ping.nonce)).unwrap();
+
+        assert!(topic.on_tick(Instant::now()).is_ok());
+        assert!(topic.on_tick(Instant::now()).is_ok());
+        assert!(topic.on_tick(Instant::now()).is_ok());
+        let err = topic.on_tick(Instant::now()).unwrap_err();
+        assert!(matches!(err, PeerError::Timeout(_)));
+    }
+
+    #[test]
+    fn test_late_pong_of_previous_ping_counts() {
+        let latency = PeerLatency::default();
+        let config = KeepaliveConfig { max_missed_pongs: 2, ..KeepaliveConfig::default() };
+        let mut topic = KeepaliveConversationTopic::new(Chain::Regtest, config, &latency);
+        let first = sent_ping(topic.initial_action());
+        let second = sent_ping(topic.on_tick(Instant::now()).unwrap());
+
+        // the pong of the first ping arrives just after the second ping went out
+        topic.on_message(pong(first.nonce)).unwrap();
+        assert_eq!(latency.stats().pongs_received, 1);
+        topic.on_message(pong(second.nonce)).unwrap();
+        assert_eq!(latency.stats().pongs_received, 2);
+
+        // answered pings are forgotten
+        topic.on_message(pong(first.nonce)).unwrap();
+        assert_eq!(latency.stats().pongs_received, 2);
+    }
+}
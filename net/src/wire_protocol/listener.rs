@@ -0,0 +1,166 @@
+use std::net::SocketAddr;
+
+use tokio::io;
+use tokio::net::TcpListener;
+
+use crate::error::PeerResult;
+use crate::wire_protocol::connection::NodeConnection;
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::transport::{NegotiatedTransport, V1Transport, V2Transport};
+
+/// Accepts inbound connections of remote nodes.
+///
+/// An accepted connection is meant to start with a
+/// [HandshakeRespondConversationTopic](crate::wire_protocol::handshake::HandshakeRespondConversationTopic).
+pub struct NodeListener {
+    chain: Chain,
+    listener: TcpListener,
+}
+
+impl NodeListener {
+    pub async fn bind(chain: Chain, addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(NodeListener { chain, listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// waits for the next inbound connection
+    pub async fn accept(&self) -> io::Result<(NodeConnection, SocketAddr)> {
+        let (socket, remote_addr) = self.listener.accept().await?;
+        log::debug!("accepted inbound connection from {}", remote_addr);
+        let transport = NegotiatedTransport::V1(V1Transport::new(self.chain, socket));
+        Ok((NodeConnection::with_transport(transport, remote_addr), remote_addr))
+    }
+
+    /// Waits for the next inbound connection and performs the handshake of the encrypted v2 transport (BIP324), unless
+    /// the remote node starts with an unencrypted v1 __version__ message
+    pub async fn accept_v2(&self) -> PeerResult<(NodeConnection, SocketAddr)> {
+        let (socket, remote_addr) = self.listener.accept().await?;
+        log::debug!("accepted inbound connection from {}", remote_addr);
+        let transport = V2Transport::respond(self.chain, socket).await?;
+        Ok((NodeConnection::with_transport(transport, remote_addr), remote_addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::peer::GarbagePolicy;
+    use crate::wire_protocol::handshake::{HandshakeInitConversationTopic, HandshakeRespondConversationTopic, LocalNonces};
+    use crate::wire_protocol::node::NodeDesc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_over_loopback() {
+        let listener = NodeListener::bind(Chain::Regtest, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let responder = async {
+            let (mut connection, remote_addr) = listener.accept().await.unwrap();
+            let me = NodeDesc { start_height: 20, ..NodeDesc::test_node("/responder/") };
+            let topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+            connection.proceed_conversation(topic).await
+        };
+        let initiator = async {
+            let mut connection = NodeConnection::new(Chain::Regtest, listen_addr).await.unwrap();
+            let me = NodeDesc { start_height: 10, ..NodeDesc::test_node("/initiator/") };
+            let topic = HandshakeInitConversationTopic::new(&me, listen_addr, &LocalNonces::default());
+            connection.proceed_conversation(topic).await
+        };
+
+        let (seen_by_responder, seen_by_initiator) = tokio::join!(responder, initiator);
+        assert_eq!(seen_by_responder.unwrap().sub_ver, "/initiator/");
+        let seen_by_initiator = seen_by_initiator.unwrap();
+        assert_eq!(seen_by_initiator.sub_ver, "/responder/");
+        assert_eq!(seen_by_initiator.start_height, 20);
+    }
+
+    async fn handshake(connection: &mut NodeConnection, initiator: bool, remote_addr: SocketAddr) -> PeerResult<NodeDesc> {
+        match initiator {
+            true => {
+                let me = NodeDesc { start_height: 10, ..NodeDesc::test_node("/initiator/") };
+                let topic = HandshakeInitConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+                connection.proceed_conversation(topic).await
+            }
+            false => {
+                let me = NodeDesc { start_height: 20, ..NodeDesc::test_node("/responder/") };
+                let topic = HandshakeRespondConversationTopic::new(&me, remote_addr, &LocalNonces::default());
+                connection.proceed_conversation(topic).await
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v2_handshake_over_loopback() {
+        let listener = NodeListener::bind(Chain::Regtest, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let responder = async {
+            let (mut connection, remote_addr) = listener.accept_v2().await.unwrap();
+            let remote = handshake(&mut connection, false, remote_addr).await.unwrap();
+            (connection.session_id(), remote)
+        };
+        let initiator = async {
+            let mut connection = NodeConnection::new_v2(Chain::Regtest, listen_addr).await.unwrap();
+            let remote = handshake(&mut connection, true, listen_addr).await.unwrap();
+            (connection.session_id(), remote)
+        };
+
+        let ((responder_session, seen_by_responder), (initiator_session, seen_by_initiator)) = tokio::join!(responder, initiator);
+        assert!(responder_session.is_some());
+        assert_eq!(responder_session, initiator_session);
+        assert_eq!(seen_by_responder.sub_ver, "/initiator/");
+        assert_eq!(seen_by_initiator.sub_ver, "/responder/");
+    }
+
+    #[tokio::test]
+    async fn test_v2_responder_accepts_v1_initiator() {
+        let listener = NodeListener::bind(Chain::Regtest, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let responder = async {
+            let (mut connection, remote_addr) = listener.accept_v2().await.unwrap();
+            let remote = handshake(&mut connection, false, remote_addr).await.unwrap();
+            (connection.session_id(), remote)
+        };
+        let initiator = async {
+            let mut connection = NodeConnection::new(Chain::Regtest, listen_addr).await.unwrap();
+            handshake(&mut connection, true, listen_addr).await.unwrap()
+        };
+
+        let ((session_id, seen_by_responder), seen_by_initiator) = tokio::join!(responder, initiator);
+        assert_eq!(session_id, None);
+        assert_eq!(seen_by_responder.sub_ver, "/initiator/");
+        assert_eq!(seen_by_initiator.sub_ver, "/responder/");
+    }
+
+    #[tokio::test]
+    async fn test_v2_initiator_falls_back_to_v1() {
+        let listener = NodeListener::bind(Chain::Regtest, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        // a v1 responder, which hangs up on the public key of the v2 initiator
+        let responder = async {
+            let (mut connection, remote_addr) = listener.accept().await.unwrap();
+            connection.set_garbage_policy(GarbagePolicy::Disconnect);
+            assert!(handshake(&mut connection, false, remote_addr).await.is_err());
+            drop(connection);
+
+            let (mut connection, remote_addr) = listener.accept().await.unwrap();
+            handshake(&mut connection, false, remote_addr).await.unwrap()
+        };
+        let initiator = async {
+            let mut connection = NodeConnection::new_v2(Chain::Regtest, listen_addr).await.unwrap();
+            let remote = handshake(&mut connection, true, listen_addr).await.unwrap();
+            (connection.session_id(), remote)
+        };
+
+        let (seen_by_responder, (session_id, seen_by_initiator)) = tokio::join!(responder, initiator);
+        assert_eq!(session_id, None);
+        assert_eq!(seen_by_responder.sub_ver, "/initiator/");
+        assert_eq!(seen_by_initiator.sub_ver, "/responder/");
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::PeerResult;
+use crate::wire_protocol::block::display_hash;
+use crate::wire_protocol::inventory::{Inventory, InventoryType};
+use crate::wire_protocol::messages::{GetDataMessage, MempoolMessage, PongMessage, ProtocolMessage};
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::transaction::Transaction;
+
+/// interval, in which the topic checks whether the receiving side of the channel is still there
+const CANCELLATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// number of txids and wtxids of delivered transactions, which are remembered to suppress duplicates
+const MAX_SEEN: usize = 100_000;
+
+/// maximum number of requested, but not yet received transactions (see `MAX_PEER_TX_ANNOUNCEMENTS` in bitcoin core)
+const MAX_REQUESTED: usize = 5000;
+
+/// requests, which are not answered within this time, are forgotten, so that the transaction may be requested again
+/// (see `GETDATA_TX_INTERVAL` in bitcoin core)
+const REQUEST_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Mempool observer (after the handshake):
+///
+/// - optionally send a __mempool__ request (BIP35), so that the remote node announces its whole mempool. Bitcoin core
+///   disconnects peers sending it, unless it offers [NodeBloom](crate::wire_protocol::node::NodeService::NodeBloom)
+///   (or the peer has the mempool permission).
+/// - expect __inv__ announcements of transactions and request the unknown ones via __getdata__
+/// - decode the received __tx__ messages and stream them to the given channel
+///
+/// Each transaction is delivered once, no matter whether it was announced by txid or wtxid. The remote node only
+/// announces transactions, if we asked for it with the `relay` flag of our __version__ message.
+///
+/// The topic runs until it is cancelled by dropping the receiving side of the channel.
+pub struct MempoolObserverConversationTopic {
+    chain: Chain,
+    request_mempool: bool,
+    sender: mpsc::UnboundedSender<Transaction>,
+    /// txids and wtxids of delivered transactions, the most recent [MAX_SEEN] ones
+    seen: HashSet<[u8; 32]>,
+    /// [Self::seen] in the order of insertion, for eviction
+    seen_order: VecDeque<[u8; 32]>,
+    /// hashes requested by __getdata__, but not yet received, with the time of the request
+    requested: HashMap<[u8; 32], Instant>,
+}
+
+impl MempoolObserverConversationTopic {
+    pub fn new(chain: Chain, request_mempool: bool, sender: mpsc::UnboundedSender<Transaction>) -> Self {
+        MempoolObserverConversationTopic {
+            chain,
+            request_mempool,
+            sender,
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            requested: HashMap::new(),
+        }
+    }
+
+    fn request_announced(&mut self, inventory: Vec<Inventory>) -> ConversationAction {
+        let now = Instant::now();
+        let mut wanted = vec![];
+        for inv in inventory.into_iter().filter(|inv| inv.inv_type.is_tx()) {
+            if self.seen.contains(&inv.hash) || self.requested.contains_key(&inv.hash) {
+                continue;
+            }
+            if self.requested.len() >= MAX_REQUESTED {
+                log::debug!("too many pending requests, ignoring announced transaction {}", display_hash(&inv.hash));
+                continue;
+            }
+            self.requested.insert(inv.hash, now);
+            wanted.push(match inv.inv_type {
+                // announcements by wtxid have to be requested by wtxid
+                InventoryType::WTx => inv,
+                _ => Inventory::new(InventoryType::WitnessTx, inv.hash),
+            });
+        }
+        match wanted.is_empty() {
+            true => ConversationAction::nop(),
+            false => ConversationAction {
+                messages: vec![ProtocolMessage::GetData(GetDataMessage::new(self.chain, wanted))],
+                topic_finished: false,
+            },
+        }
+    }
+
+    fn deliver(&mut self, tx: Transaction) -> ConversationAction {
+        let (txid, wtxid) = (tx.txid(), tx.wtxid());
+        self.requested.remove(&txid);
+        self.requested.remove(&wtxid);
+        // txid and wtxid are the same for transactions without witness data
+        let new = self.remember(txid);
+        self.remember(wtxid);
+        if !new {
+            log::debug!("ignoring already delivered transaction {}", display_hash(&txid));
+            return ConversationAction::nop();
+        }
+        ConversationAction {
+            messages: vec![],
+            topic_finished: self.sender.send(tx).is_err(),
+        }
+    }
+
+    /// adds `hash` to the seen ones, evicting the oldest one if necessary; `false` if it was seen already
+    fn remember(&mut self, hash: [u8; 32]) -> bool {
+        if !self.seen.insert(hash) {
+            return false;
+        }
+        self.seen_order.push_back(hash);
+        if self.seen_order.len() > MAX_SEEN {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+impl ConversationTopicHandler for MempoolObserverConversationTopic {
+    type Outcome = ();
+
+    fn initial_action(&mut self) -> ConversationAction {
+        ConversationAction {
+            messages: match self.request_mempool {
+                true => vec![ProtocolMessage::Mempool(MempoolMessage::new(self.chain))],
+                false => vec![],
+            },
+            topic_finished: self.sender.is_closed(),
+        }
+    }
+
+    fn on_message(&mut self, message: ProtocolMessage) -> PeerResult<ConversationAction> {
+        match message {
+            ProtocolMessage::Inv(m) => Ok(self.request_announced(m.inventory)),
+            ProtocolMessage::Tx(m) => Ok(self.deliver(m.tx)),
+            ProtocolMessage::NotFound(m) => {
+                for inv in m.inventory {
+                    self.requested.remove(&inv.hash);
+                }
+                Ok(ConversationAction::nop())
+            }
+            ProtocolMessage::Ping(ping) => {
+                Ok(ConversationAction {
+                    messages: vec![ProtocolMessage::Pong(PongMessage::reply_to(&ping))],
+                    topic_finished: false,
+                })
+            }
+            _ => Ok(ConversationAction::nop())
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(CANCELLATION_CHECK_INTERVAL)
+    }
+
+    fn on_tick(&mut self, now: Instant) -> PeerResult<ConversationAction> {
+        self.requested.retain(|_, requested_at| now.saturating_duration_since(*requested_at) < REQUEST_EXPIRY);
+        Ok(ConversationAction {
+            messages: vec![],
+            topic_finished: self.sender.is_closed(),
+        })
+    }
+
+    fn outcome(self) -> PeerResult<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::messages::{InvMessage, NotFoundMessage, TxMessage};
+    use crate::wire_protocol::raw_message::sha256;
+    use crate::wire_protocol::transaction::{OutPoint, TxIn, TxOut};
+
+    use super::*;
+
+    fn tx(witness: bool) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint { txid: [1; 32], vout: 0 },
+                script_sig: vec![],
+                sequence: u32::MAX,
+                witness: match witness {
+                    true => vec![vec![0x01]],
+                    false => vec![],
+                },
+            }],
+            outputs: vec![TxOut { value: 1000, script_pubkey: vec![0x51] }],
+            lock_time: 0,
+        }
+    }
+
+    fn inv(inv_type: InventoryType, hash: [u8; 32]) -> ProtocolMessage {
+        ProtocolMessage::Inv(InvMessage::new(Chain::Regtest, vec![Inventory::new(inv_type, hash)]))
+    }
+
+    fn requested(action: ConversationAction) -> Vec<Inventory> {
+        match action.messages.into_iter().next() {
+            Some(ProtocolMessage::GetData(m)) => m.inventory,
+            None => vec![],
+            other => panic!("expected a getdata message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_announced_transactions_are_requested_and_delivered_once() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, true, sender);
+        assert!(matches!(topic.initial_action().messages[..], [ProtocolMessage::Mempool(_)]));
+
+        let tx = tx(true);
+        assert_eq!(requested(topic.on_message(inv(InventoryType::Tx, tx.txid())).unwrap()),
+                   vec![Inventory::new(InventoryType::WitnessTx, tx.txid())]);
+        // announced again, while the request is pending
+        assert!(requested(topic.on_message(inv(InventoryType::Tx, tx.txid())).unwrap()).is_empty());
+        assert_eq!(requested(topic.on_message(inv(InventoryType::WTx, tx.wtxid())).unwrap()),
+                   vec![Inventory::new(InventoryType::WTx, tx.wtxid())]);
+
+        topic.on_message(ProtocolMessage::Tx(TxMessage::new(Chain::Regtest, tx.clone()))).unwrap();
+        topic.on_message(ProtocolMessage::Tx(TxMessage::new(Chain::Regtest, tx.clone()))).unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), tx);
+        assert!(receiver.try_recv().is_err());
+
+        // known by now
+        assert!(requested(topic.on_message(inv(InventoryType::WTx, tx.wtxid())).unwrap()).is_empty());
+        // blocks are not of interest
+        assert!(requested(topic.on_message(inv(InventoryType::Block, [2; 32])).unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_not_found_transaction_may_be_requested_again() {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, false, sender);
+        assert!(topic.initial_action().messages.is_empty());
+
+        let txid = tx(false).txid();
+        assert_eq!(requested(topic.on_message(inv(InventoryType::Tx, txid)).unwrap()).len(), 1);
+        let not_found = NotFoundMessage::new(Chain::Regtest, vec![Inventory::new(InventoryType::WitnessTx, txid)]);
+        topic.on_message(ProtocolMessage::NotFound(not_found)).unwrap();
+        assert_eq!(requested(topic.on_message(inv(InventoryType::Tx, txid)).unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn test_dropped_receiver_cancels_the_topic() {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, false, sender);
+        topic.initial_action();
+        assert!(!topic.on_tick(Instant::now()).unwrap().topic_finished);
+
+        drop(receiver);
+        assert!(topic.on_tick(Instant::now()).unwrap().topic_finished);
+        assert!(topic.on_message(ProtocolMessage::Tx(TxMessage::new(Chain::Regtest, tx(false)))).unwrap().topic_finished);
+    }
+
+    #[test]
+    fn test_unanswered_requests_expire() {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, false, sender);
+        topic.initial_action();
+
+        let txid = tx(false).txid();
+        assert_eq!(requested(topic.on_message(inv(InventoryType::Tx, txid)).unwrap()).len(), 1);
+        topic.on_tick(Instant::now()).unwrap();
+        assert!(requested(topic.on_message(inv(InventoryType::Tx, txid)).unwrap()).is_empty());
+        topic.on_tick(Instant::now() + REQUEST_EXPIRY).unwrap();
+        assert_eq!(requested(topic.on_message(inv(InventoryType::Tx, txid)).unwrap()).len(), 1);
+    }
+
+    #[test]
+    fn test_pending_requests_are_capped() {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, false, sender);
+        topic.initial_action();
+
+        let inventory = (0..MAX_REQUESTED + 1)
+            .map(|i| Inventory::new(InventoryType::Tx, sha256(&i.to_le_bytes())))
+            .collect();
+        let action = topic.on_message(ProtocolMessage::Inv(InvMessage::new(Chain::Regtest, inventory))).unwrap();
+        assert_eq!(requested(action).len(), MAX_REQUESTED);
+    }
+
+    #[test]
+    fn test_oldest_seen_hashes_are_evicted() {
+        let (sender, _receiver) = mpsc::unbounded_channel();
+        let mut topic = MempoolObserverConversationTopic::new(Chain::Regtest, false, sender);
+        for i in 0..=MAX_SEEN {
+            assert!(topic.remember(sha256(&i.to_le_bytes())));
+        }
+        assert_eq!(topic.seen.len(), MAX_SEEN);
+        assert!(!topic.remember(sha256(&MAX_SEEN.to_le_bytes())));
+        // the first one was evicted
+        assert!(topic.remember(sha256(&0usize.to_le_bytes())));
+    }
+}
@@ -0,0 +1,418 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::{Block, BlockHeader, display_hash, merkle_parent, MAX_BLOCK_SERIALIZED_SIZE};
+use crate::wire_protocol::bloom::BloomFilter;
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::transaction::WITNESS_SCALE_FACTOR;
+
+/// Upper bound of the number of transactions in a block: the maximum block weight divided by the weight of the
+/// smallest transaction (see `MIN_TRANSACTION_WEIGHT` in bitcoin core)
+const MAX_BLOCK_TRANSACTIONS: u32 = (MAX_BLOCK_SERIALIZED_SIZE / (WITNESS_SCALE_FACTOR * 60)) as u32;
+
+/// Merkle tree of a block pruned to the branches leading to matched transactions (BIP37)
+///
+/// The tree is traversed depth-first. For each visited node, a flag bit tells whether it is the ancestor of a matched
+/// transaction (or the matched transaction itself). The traversal descends into such nodes, except for leaves. The
+/// hashes of all other visited nodes and of the matched leaves are stored in the order of traversal.
+///
+/// size | field              | type       | description
+/// ---  | -----              | ----       | ------------
+/// 4    | total_transactions | u32        | number of transactions in the block
+/// ?    | hashes length      | var_int    | number of hashes
+/// 32x? | hashes             | [u8; 32][] | hashes in depth-first order (internal byte order)
+/// ?    | flags              | var_bytes  | flag bits in depth-first order, starting with the least significant bit
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<[u8; 32]>,
+    /// flag bits padded to whole bytes
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    /// Builds the partial tree of the given txids, in which the txids marked by `matches` can be verified
+    /// (see `CPartialMerkleTree` in bitcoin core). A match flag is required for each txid.
+    pub fn new(txids: &[[u8; 32]], matches: &[bool]) -> PeerResult<Self> {
+        if txids.len() != matches.len() {
+            return Err(PeerError::MatchFlagCount { txids: txids.len(), matches: matches.len() });
+        }
+        Ok(Self::build(txids, matches))
+    }
+
+    /// see [Self::new]; `txids` and `matches` have the same length
+    fn build(txids: &[[u8; 32]], matches: &[bool]) -> Self {
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: vec![],
+            flags: vec![],
+        };
+        tree.traverse_and_build(tree.height(), 0, txids, matches);
+        tree.flags.resize(tree.flags.len().next_multiple_of(8), false);
+        tree
+    }
+
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let total_transactions = parser.read_u32_le()?;
+        let count = parser.read_length(parser.remaining() / 32)?;
+        let hashes = (0..count)
+            .map(|_| Ok(parser.read(32)?.try_into().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let flags = parser.read_var_bytes(parser.remaining())?
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0))
+            .collect();
+        Ok(PartialMerkleTree { total_transactions, hashes, flags })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        composer.append(&self.total_transactions.to_le_bytes());
+        composer.append_compact_size(self.hashes.len() as u64);
+        for hash in &self.hashes {
+            composer.append(hash);
+        }
+        let flag_bytes: Vec<u8> = self.flags
+            .chunks(8)
+            .map(|bits| bits.iter().enumerate().fold(0, |byte, (i, &bit)| byte | ((bit as u8) << i)))
+            .collect();
+        composer.append_var_bytes(&flag_bytes);
+    }
+
+    /// Merkle root of the tree together with the matched transactions. Fails, if the tree is malformed, i.e. it is not
+    /// exactly made up of the given hashes and flags, or it contains two identical siblings (CVE-2012-2459).
+    pub fn extract_matches(&self) -> Result<ExtractedMatches, MalformedTree> {
+        if self.total_transactions == 0 {
+            return Err(MalformedTree::NoTransactions);
+        }
+        if self.total_transactions > MAX_BLOCK_TRANSACTIONS {
+            return Err(MalformedTree::TooManyTransactions(self.total_transactions));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err(MalformedTree::MoreHashesThanTransactions(self.hashes.len()));
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err(MalformedTree::FewerFlagBitsThanHashes);
+        }
+        let mut extraction = Extraction { bits_used: 0, hashes_used: 0, matches: vec![] };
+        let root = self.traverse_and_extract(self.height(), 0, &mut extraction)?;
+        if extraction.bits_used.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err(MalformedTree::UnusedFlagBits);
+        }
+        if extraction.hashes_used != self.hashes.len() {
+            return Err(MalformedTree::UnusedHashes);
+        }
+        Ok(ExtractedMatches { merkle_root: root, matches: extraction.matches })
+    }
+
+    /// number of nodes at the given height, where the leaves are at height 0
+    fn tree_width(&self, height: u32) -> usize {
+        (self.total_transactions as usize + (1 << height) - 1) >> height
+    }
+
+    /// height of the root
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn calc_hash(&self, height: u32, pos: usize, txids: &[[u8; 32]]) -> [u8; 32] {
+        if height == 0 {
+            return txids[pos];
+        }
+        let left = self.calc_hash(height - 1, pos * 2, txids);
+        let right = match pos * 2 + 1 < self.tree_width(height - 1) {
+            true => self.calc_hash(height - 1, pos * 2 + 1, txids),
+            false => left,
+        };
+        merkle_parent(&left, &right)
+    }
+
+    fn traverse_and_build(&mut self, height: u32, pos: usize, txids: &[[u8; 32]], matches: &[bool]) {
+        let leaves = (pos << height)..((pos + 1) << height).min(txids.len());
+        let parent_of_match = matches[leaves].contains(&true);
+        self.flags.push(parent_of_match);
+        if height == 0 || !parent_of_match {
+            let hash = self.calc_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.tree_width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    fn traverse_and_extract(&self, height: u32, pos: usize, extraction: &mut Extraction) -> Result<[u8; 32], MalformedTree> {
+        let parent_of_match = *self.flags.get(extraction.bits_used).ok_or(MalformedTree::NotEnoughFlagBits)?;
+        extraction.bits_used += 1;
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(extraction.hashes_used).ok_or(MalformedTree::NotEnoughHashes)?;
+            extraction.hashes_used += 1;
+            if height == 0 && parent_of_match {
+                extraction.matches.push((pos, hash));
+            }
+            return Ok(hash);
+        }
+        let has_right = pos * 2 + 1 < self.tree_width(height - 1);
+        let left = self.traverse_and_extract(height - 1, pos * 2, extraction)?;
+        let right = match has_right {
+            true => self.traverse_and_extract(height - 1, pos * 2 + 1, extraction)?,
+            false => left,
+        };
+        if has_right && right == left {
+            return Err(MalformedTree::IdenticalSiblings);
+        }
+        Ok(merkle_parent(&left, &right))
+    }
+}
+
+/// Result of [PartialMerkleTree::extract_matches]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtractedMatches {
+    pub merkle_root: [u8; 32],
+    /// index within the block and txid of each matched transaction, in the order of the block
+    pub matches: Vec<(usize, [u8; 32])>,
+}
+
+/// Why [PartialMerkleTree::extract_matches] failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MalformedTree {
+    NoTransactions,
+    TooManyTransactions(u32),
+    MoreHashesThanTransactions(usize),
+    FewerFlagBitsThanHashes,
+    NotEnoughFlagBits,
+    NotEnoughHashes,
+    UnusedFlagBits,
+    UnusedHashes,
+    /// two identical siblings, which allow to fake a merkle root (CVE-2012-2459)
+    IdenticalSiblings,
+}
+
+impl Display for MalformedTree {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MalformedTree::NoTransactions => write!(f, "no transactions"),
+            MalformedTree::TooManyTransactions(count) => write!(f, "too many transactions: {}", count),
+            MalformedTree::MoreHashesThanTransactions(count) => write!(f, "more hashes than transactions: {}", count),
+            MalformedTree::FewerFlagBitsThanHashes => write!(f, "fewer flag bits than hashes"),
+            MalformedTree::NotEnoughFlagBits => write!(f, "not enough flag bits"),
+            MalformedTree::NotEnoughHashes => write!(f, "not enough hashes"),
+            MalformedTree::UnusedFlagBits => write!(f, "unused flag bits"),
+            MalformedTree::UnusedHashes => write!(f, "unused hashes"),
+            MalformedTree::IdenticalSiblings => write!(f, "identical siblings"),
+        }
+    }
+}
+
+/// progress of [PartialMerkleTree::extract_matches]
+struct Extraction {
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<(usize, [u8; 32])>,
+}
+
+/// Block header together with the partial merkle tree of the transactions matching a bloom filter (BIP37)
+///
+/// size | field  | type                | description
+/// ---  | -----  | ----                | ------------
+/// 80   | header | block_header        | the block header
+/// ?    | tree   | partial_merkle_tree | see [PartialMerkleTree]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    /// the merkle block of `block` as sent by a node, which has loaded `filter` (see `CMerkleBlock` in bitcoin core)
+    pub fn from_block(block: &Block, filter: &mut BloomFilter) -> Self {
+        let matches: Vec<bool> = block.transactions.iter().map(|tx| filter.is_relevant_and_update(tx)).collect();
+        let txids: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.txid()).collect();
+        MerkleBlock { header: block.header, tree: PartialMerkleTree::build(&txids, &matches) }
+    }
+
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        Ok(MerkleBlock {
+            header: BlockHeader::parse(parser)?,
+            tree: PartialMerkleTree::parse(parser)?,
+        })
+    }
+
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer) {
+        self.header.append(composer);
+        self.tree.append(composer);
+    }
+
+    pub fn block_hash(&self) -> [u8; 32] {
+        self.header.block_hash()
+    }
+
+    /// Txids of the matched transactions in the order of the block, once the partial merkle tree is verified against
+    /// the merkle root of the header. The transactions themselves are sent separately by __tx__ messages.
+    pub fn matched_txids(&self) -> PeerResult<Vec<[u8; 32]>> {
+        let invalid = |reason: String| PeerError::InvalidBlock { hash: display_hash(&self.block_hash()), reason };
+        let extracted = self.tree.extract_matches().map_err(|malformed| invalid(malformed.to_string()))?;
+        if extracted.merkle_root != self.header.merkle_root {
+            return Err(invalid("merkle root mismatch".to_string()));
+        }
+        Ok(extracted.matches.into_iter().map(|(_, txid)| txid).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use crate::wire_protocol::bloom::BloomUpdate;
+    use crate::wire_protocol::node::Chain;
+    use crate::wire_protocol::transaction::{OutPoint, Transaction, TxIn, TxOut};
+
+    use super::*;
+
+    /// example of the bitcoin developer reference: a block with 7 transactions, the fifth of which matches
+    const MERKLE_BLOCK: [u8; 215] = hex!(
+        "01000000" "82bb869cf3a793432a66e826e05a6fc37469f8efb7421dc88067010000000000"
+        "7f16c5962e8bd963659c793ce370d95f093bc7e367117b3c30c1f8fdd0d97287" "76381b4d" "4c86041b" "554b8529"
+        "07000000" "04"
+        "3612262624047ee87660be1a707519a443b1c1ce3d248cbfc6c15870f6c5daa2"
+        "019f5b01d4195ecbc9398fbf3c3b1fa9bb3183301d7a1fb3bd174fcfa40a2b65"
+        "41ed70551dd7e841883ab8f0b16bf04176b7d1480e4f0af9f3d4c3595768d068"
+        "20d2a7bc994987302e5b1ac80fc425fe25f8b63169ea78e68fbaaefa59379bbf"
+        "01" "1d");
+
+    fn parse(bytes: &[u8]) -> MerkleBlock {
+        let mut parser = ByteBufferParser::new(bytes);
+        let merkle_block = MerkleBlock::parse(&mut parser).unwrap();
+        assert_eq!(parser.remaining(), 0);
+        merkle_block
+    }
+
+    fn txids(count: usize) -> Vec<[u8; 32]> {
+        (0..count).map(|i| [i as u8 + 1; 32]).collect()
+    }
+
+    #[test]
+    fn test_reference_merkle_block() {
+        let merkle_block = parse(&MERKLE_BLOCK);
+        assert_eq!(display_hash(&merkle_block.block_hash()), "000000000000b731f2eef9e8c63173adfb07e41bd53eb0ef0a6b720d6cb6dea4");
+        merkle_block.header.validate_pow(Chain::Mainnet).unwrap();
+        assert_eq!(merkle_block.tree.total_transactions, 7);
+        assert_eq!(merkle_block.tree.flags, vec![true, false, true, true, true, false, false, false]);
+
+        let matched = merkle_block.matched_txids().unwrap();
+        assert_eq!(matched.iter().map(display_hash).collect::<Vec<_>>(),
+                   vec!["652b0aa4cf4f17bdb31f7a1d308331bba91f3b3cbf8f39c9cb5e19d4015b9f01"]);
+        assert_eq!(merkle_block.tree.extract_matches().unwrap().matches[0].0, 4);
+
+        let mut composer = ByteBufferComposer::new();
+        merkle_block.append(&mut composer);
+        assert_eq!(composer.result(), MERKLE_BLOCK);
+    }
+
+    #[rstest]
+    #[case(1, &[0])]
+    #[case(1, &[])]
+    #[case(2, &[1])]
+    #[case(7, &[4])]
+    #[case(7, &[0, 6])]
+    #[case(9, &[8])]
+    #[case(16, &[])]
+    #[case(17, &[0, 3, 7, 15, 16])]
+    #[case(100, &(0..100).collect::<Vec<_>>())]
+    fn test_build_and_extract(#[case] count: usize, #[case] matched: &[usize]) {
+        let txids = txids(count);
+        let matches: Vec<bool> = (0..count).map(|i| matched.contains(&i)).collect();
+        let tree = PartialMerkleTree::new(&txids, &matches).unwrap();
+
+        let mut composer = ByteBufferComposer::new();
+        tree.append(&mut composer);
+        let tree = PartialMerkleTree::parse(&mut ByteBufferParser::new(&composer.result())).unwrap();
+
+        let extracted = tree.extract_matches().unwrap();
+        let block_root = (0..).try_fold(txids.clone(), |level, _| match level.len() {
+            1 => Err(level[0]),
+            _ => Ok(level.chunks(2).map(|pair| merkle_parent(&pair[0], pair.last().unwrap())).collect()),
+        });
+        assert_eq!(Err(extracted.merkle_root), block_root);
+        assert_eq!(extracted.matches, matched.iter().map(|&i| (i, txids[i])).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_malformed_trees_are_rejected() {
+        let tree = PartialMerkleTree::new(&txids(7), &[false, false, false, false, true, false, false]).unwrap();
+        tree.extract_matches().unwrap();
+
+        let mut too_few_hashes = tree.clone();
+        too_few_hashes.hashes.pop();
+        assert_eq!(too_few_hashes.extract_matches(), Err(MalformedTree::NotEnoughHashes));
+
+        let mut too_many_hashes = tree.clone();
+        too_many_hashes.hashes.push([0; 32]);
+        assert_eq!(too_many_hashes.extract_matches(), Err(MalformedTree::UnusedHashes));
+
+        let mut too_many_flags = tree.clone();
+        too_many_flags.flags.extend([false; 8]);
+        assert_eq!(too_many_flags.extract_matches(), Err(MalformedTree::UnusedFlagBits));
+
+        let mut too_few_flags = tree.clone();
+        too_few_flags.flags.truncate(6);
+        assert_eq!(too_few_flags.extract_matches(), Err(MalformedTree::NotEnoughFlagBits));
+
+        let empty = PartialMerkleTree { total_transactions: 0, hashes: vec![], flags: vec![] };
+        assert_eq!(empty.extract_matches(), Err(MalformedTree::NoTransactions));
+        let huge = PartialMerkleTree { total_transactions: u32::MAX, ..tree };
+        assert_eq!(huge.extract_matches(), Err(MalformedTree::TooManyTransactions(u32::MAX)));
+    }
+
+    #[test]
+    fn test_match_flag_is_required_for_each_txid() {
+        assert!(matches!(PartialMerkleTree::new(&txids(3), &[true, false]),
+            Err(PeerError::MatchFlagCount { txids: 3, matches: 2 })));
+        assert!(matches!(PartialMerkleTree::new(&txids(3), &[true, false, false, true]),
+            Err(PeerError::MatchFlagCount { txids: 3, matches: 4 })));
+    }
+
+    #[test]
+    fn test_identical_siblings_are_rejected() {
+        // the last transaction duplicated, which yields the same merkle root (CVE-2012-2459)
+        let mut txids = txids(3);
+        txids.push(txids[2]);
+        let tree = PartialMerkleTree::new(&txids, &[false, false, true, true]).unwrap();
+        assert_eq!(tree.extract_matches(), Err(MalformedTree::IdenticalSiblings));
+    }
+
+    #[test]
+    fn test_merkle_root_mismatch_is_rejected() {
+        let mut merkle_block = parse(&MERKLE_BLOCK);
+        merkle_block.tree.hashes.swap(0, 3);
+        assert!(matches!(merkle_block.matched_txids(), Err(PeerError::InvalidBlock { .. })));
+    }
+
+    #[test]
+    fn test_merkle_block_from_block() {
+        let tx = |script_pubkey: Vec<u8>| Transaction {
+            version: 2,
+            inputs: vec![TxIn { previous_output: OutPoint::NULL, script_sig: vec![0x51], sequence: u32::MAX, witness: vec![] }],
+            outputs: vec![TxOut { value: 1000, script_pubkey }],
+            lock_time: 0,
+        };
+        let watched = [&hex!("0014")[..], &[7; 20]].concat();
+        let mut block = Block {
+            header: Chain::Regtest.genesis_header(),
+            transactions: vec![tx(vec![0x51]), tx(vec![0x52]), tx(watched.clone()), tx(vec![0x53])],
+        };
+        block.header.merkle_root = block.merkle_root().unwrap();
+
+        let mut filter = BloomFilter::new(1, 0.000001, 0, BloomUpdate::All);
+        filter.insert(&[7; 20]);
+        let merkle_block = MerkleBlock::from_block(&block, &mut filter);
+        assert_eq!(merkle_block.matched_txids().unwrap(), vec![block.transactions[2].txid()]);
+        assert!(filter.contains_outpoint(&OutPoint { txid: block.transactions[2].txid(), vout: 0 }));
+    }
+}
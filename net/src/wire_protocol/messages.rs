@@ -1,12 +1,22 @@
-use std::net::SocketAddr;
+use std::borrow::Cow;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use rand::{Rng, RngCore, thread_rng};
+use rand::{Rng, thread_rng};
 
 use crate::error::PeerResult;
+use crate::wire_protocol::address::{MAX_ADDRV2_SIZE, PeerAddress, TimestampedNetAddr};
+use crate::wire_protocol::block::{Block, BLOCK_HEADER_SIZE, BlockHeader};
+use crate::wire_protocol::bloom::{BloomFilter, MAX_BLOOM_FILTER_SIZE, MAX_SCRIPT_ELEMENT_SIZE};
 use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser};
+use crate::wire_protocol::compact_block::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds};
+use crate::wire_protocol::compact_filter::BlockFilter;
+use crate::wire_protocol::inventory::{Inventory, MAX_INV_SZ};
+use crate::wire_protocol::merkle_block::MerkleBlock;
 use crate::wire_protocol::node::{Chain, NodeDesc, NodeServiceSet};
 use crate::wire_protocol::raw_message::{Command, RawMessage};
+use crate::wire_protocol::transaction::Transaction;
 
 #[derive(Debug)]
 pub enum ProtocolMessage {
@@ -14,6 +24,35 @@ pub enum ProtocolMessage {
     Verack(VerackMessage),
     Ping(PingMessage),
     Pong(PongMessage),
+    GetAddr(GetAddrMessage),
+    Addr(AddrMessage),
+    AddrV2(AddrV2Message),
+    SendAddrV2(SendAddrV2Message),
+    Inv(InvMessage),
+    GetData(GetDataMessage),
+    NotFound(NotFoundMessage),
+    GetHeaders(GetHeadersMessage),
+    Headers(HeadersMessage),
+    Block(BlockMessage),
+    Tx(TxMessage),
+    Mempool(MempoolMessage),
+    SendHeaders(SendHeadersMessage),
+    SendCmpct(SendCmpctMessage),
+    FeeFilter(FeeFilterMessage),
+    WtxidRelay(WtxidRelayMessage),
+    CmpctBlock(CmpctBlockMessage),
+    GetBlockTxn(GetBlockTxnMessage),
+    BlockTxn(BlockTxnMessage),
+    GetCFilters(GetCFiltersMessage),
+    CFilter(CFilterMessage),
+    GetCFHeaders(GetCFHeadersMessage),
+    CFHeaders(CFHeadersMessage),
+    GetCFCheckpt(GetCFCheckptMessage),
+    CFCheckpt(CFCheckptMessage),
+    FilterLoad(FilterLoadMessage),
+    FilterAdd(FilterAddMessage),
+    FilterClear(FilterClearMessage),
+    MerkleBlock(MerkleBlockMessage),
 }
 
 impl ProtocolMessage {
@@ -22,6 +61,12 @@ impl ProtocolMessage {
     }
 }
 
+/// Maximum length of the user agent (see `MAX_SUBVERSION_LENGTH` in bitcoin core)
+const MAX_SUBVERSION_LENGTH: usize = 256;
+
+/// Maximum payload length of a __version__ message: all fixed size fields plus a user agent of [MAX_SUBVERSION_LENGTH]
+pub(super) const MAX_VERSION_PAYLOAD_LENGTH: usize = 4 + 8 + 8 + 26 + 26 + 8 + 3 + MAX_SUBVERSION_LENGTH + 4 + 1;
+
 /// https://en.bitcoin.it/wiki/Protocol_documentation#version
 ///
 /// size | field        | type     | description
@@ -35,15 +80,23 @@ impl ProtocolMessage {
 /// ?    | user_agent   | var_str  | User Agent (0x00 if string is 0 bytes long)
 /// 4    | start_height | i32      | The last block received by the emitting node
 /// 1    | relay        | bool     | Whether the remote peer should announce relayed transactions or not, see BIP 0037
-#[derive(Clone, Debug)]
+///
+/// The `relay` field exists since protocol version 70001 and is absent in messages of older peers.
+#[derive(Clone, Debug, PartialEq)]
 pub struct VersionMessage {
     pub chain: Chain,
     pub protocol_version: i32,
     pub services: NodeServiceSet,
     pub timestamp: i64,
+    pub addr_recv_services: NodeServiceSet,
     pub addr_recv: SocketAddr,
-    pub sub_ver: String,
+    pub addr_from_services: NodeServiceSet,
+    pub addr_from: SocketAddr,
+    pub nonce: u64,
+    /// user agent as received. Bitcoin core accepts any bytes here, so they are kept as they are, see [Self::user_agent].
+    pub sub_ver: Vec<u8>,
     pub start_height: i32,
+    pub relay: Option<bool>,
 }
 
 impl VersionMessage {
@@ -58,12 +111,22 @@ impl VersionMessage {
             protocol_version: me.protocol_version,
             services: me.services.clone(),
             timestamp,
+            addr_recv_services: NodeServiceSet::default(),
             addr_recv,
-            sub_ver: me.sub_ver.clone(),
+            addr_from_services: me.services.clone(),
+            addr_from: SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+            nonce: thread_rng().gen(),
+            sub_ver: me.sub_ver.as_bytes().to_vec(),
             start_height: me.start_height,
+            relay: Some(me.relay),
         }
     }
 
+    /// the user agent for display, invalid UTF-8 replaced
+    pub fn user_agent(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.sub_ver)
+    }
+
     pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
         let mut parser = ByteBufferParser::new(&raw.payload);
 
@@ -71,34 +134,52 @@ impl VersionMessage {
         let services_mask = parser.read_u64_le()?;
         let services = NodeServiceSet::from_bitmask(services_mask);
         let timestamp = parser.read_i64_le()?;
-        let (_, addr_recv) = parser.parse_net_addr()?;
-        parser.skip_bytes(26)?;
-        parser.skip_bytes(8)?;
+        let (addr_recv_services, addr_recv) = parser.parse_net_addr()?;
+        let (addr_from_services, addr_from) = parser.parse_net_addr()?;
+        let nonce = parser.read_u64_le()?;
+        let sub_ver = parser.read_var_bytes(MAX_SUBVERSION_LENGTH)?.to_vec();
+        let start_height = parser.read_i32_le()?;
+        let relay = match parser.remaining() {
+            0 => None,
+            _ => Some(match parser.read(1)?[0] {
+                0 => false,
+                1 => true,
+                // would not be re-encoded identically
+                other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid relay flag {}", other)).into()),
+            }),
+        };
+        check_consumed(&parser, "version")?;
 
         Ok(VersionMessage {
             chain: raw.chain,
             protocol_version,
             services,
             timestamp,
+            addr_recv_services,
             addr_recv,
-            sub_ver: "".to_string(), // TODO let sub_ver = parser.read_var_string()?;
-            start_height: 1, // TODO let start_height = parser.read_i32_le()?;
+            addr_from_services,
+            addr_from,
+            nonce,
+            sub_ver,
+            start_height,
+            relay,
         })
     }
 
-    pub(super) fn to_raw_message(self) -> RawMessage {
-        let mut rng = thread_rng();
+    pub(super) fn to_raw_message(&self) -> RawMessage {
         let mut composer = ByteBufferComposer::new();
 
         composer.append(&self.protocol_version.to_le_bytes());
         composer.append(&self.services.as_bitmask().to_le_bytes());
         composer.append(&self.timestamp.to_le_bytes());
-        composer.append_net_addr(&self.services, &self.addr_recv);
-        composer.append(&[0x0_u8; 26]);
-        composer.append(&rng.gen::<u64>().to_le_bytes());
-        composer.append(&[0]);  // TODO add own version string in ASCII var_string format
+        composer.append_net_addr(&self.addr_recv_services, &self.addr_recv);
+        composer.append_net_addr(&self.addr_from_services, &self.addr_from);
+        composer.append(&self.nonce.to_le_bytes());
+        composer.append_var_bytes(&self.sub_ver);
         composer.append(&self.start_height.to_le_bytes());
-        composer.append(&[0]);
+        if let Some(relay) = self.relay {
+            composer.append(&[relay as u8]);
+        }
 
         RawMessage::new(self.chain, Command::Version, composer.result())
     }
@@ -114,37 +195,1378 @@ impl VerackMessage {
     pub fn new(chain: Chain) -> Self {
         VerackMessage { chain }
     }
-    pub fn to_raw_message(self) -> RawMessage {
+    pub fn to_raw_message(&self) -> RawMessage {
         RawMessage::new(self.chain, Command::Verack, vec![])
     }
 }
 
-#[allow(dead_code)]
+/// _The ping message is sent primarily to confirm that the TCP/IP connection is still valid._
+///
+/// Since BIP31 it carries a random nonce, which has to be echoed by the __pong__ reply.
 #[derive(Debug)]
 pub struct PingMessage {
     chain: Chain,
+    pub nonce: u64,
 }
 
 impl PingMessage {
+    /// ping with a random, non-zero nonce (a zero nonce is not tracked by bitcoin core)
     pub fn new(chain: Chain) -> Self {
-        PingMessage { chain }
+        let nonce = thread_rng().gen_range(1..=u64::MAX);
+        PingMessage { chain, nonce }
     }
-    pub fn to_raw_message(self) -> RawMessage {
-        unimplemented!() // not needed for handshake
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let nonce = parser.read_u64_le()?;
+        check_consumed(&parser, "nonce")?;
+        Ok(PingMessage { chain: raw.chain, nonce })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::Ping, self.nonce.to_le_bytes().to_vec())
     }
 }
 
+/// _The pong message is sent in response to a ping message._ It echoes the nonce of the ping (BIP31).
 #[derive(Debug)]
 pub struct PongMessage {
     chain: Chain,
+    pub nonce: u64,
 }
 
 impl PongMessage {
+    pub fn new(chain: Chain, nonce: u64) -> Self {
+        PongMessage { chain, nonce }
+    }
+
+    pub fn reply_to(ping: &PingMessage) -> Self {
+        PongMessage::new(ping.chain, ping.nonce)
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let nonce = parser.read_u64_le()?;
+        check_consumed(&parser, "nonce")?;
+        Ok(PongMessage { chain: raw.chain, nonce })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::Pong, self.nonce.to_le_bytes().to_vec())
+    }
+}
+
+/// _The getaddr message sends a request to a node asking for information about known active peers._
+#[derive(Debug)]
+pub struct GetAddrMessage {
+    chain: Chain,
+}
+
+impl GetAddrMessage {
+    pub fn new(chain: Chain) -> Self {
+        GetAddrMessage { chain }
+    }
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::GetAddr, vec![])
+    }
+}
+
+/// Maximum number of addresses in an __addr__ or __addrv2__ message (see `MAX_ADDR_TO_SEND` in bitcoin core)
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+pub(super) const MAX_ADDR_PAYLOAD_LENGTH: usize = 3 + MAX_ADDR_TO_SEND * 30;
+
+/// time, services (CompactSize), network ID, address (var_bytes) and port of each entry
+pub(super) const MAX_ADDRV2_PAYLOAD_LENGTH: usize = 3 + MAX_ADDR_TO_SEND * (4 + 9 + 1 + 3 + MAX_ADDRV2_SIZE + 2);
+
+/// _Provide information on known nodes of the network._
+///
+/// size | field | type                    | description
+/// ---  | ----- | ----                    | ------------
+/// ?    | count | var_int                 | Number of address entries (max: 1000)
+/// 30x? | addrs | (u32, net_addr)[]       | Address of other nodes on the network, with time field
+#[derive(Debug)]
+pub struct AddrMessage {
+    chain: Chain,
+    pub addresses: Vec<TimestampedNetAddr>,
+}
+
+impl AddrMessage {
+    pub fn new(chain: Chain, addresses: Vec<TimestampedNetAddr>) -> Self {
+        AddrMessage { chain, addresses }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let count = parser.read_length(MAX_ADDR_TO_SEND)?;
+        let addresses = (0..count)
+            .map(|_| TimestampedNetAddr::parse(&mut parser))
+            .collect::<io::Result<Vec<_>>>()?;
+        check_consumed(&parser, "addresses")?;
+        Ok(AddrMessage { chain: raw.chain, addresses })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_compact_size(self.addresses.len() as u64);
+        for addr in &self.addresses {
+            addr.append(&mut composer);
+        }
+        RawMessage::new(self.chain, Command::Addr, composer.result())
+    }
+}
+
+/// Address gossip supporting all networks of BIP155 (Tor v3, I2P, CJDNS besides IPv4 and IPv6).
+///
+/// Entries of networks unknown to us are skipped while decoding.
+#[derive(Debug)]
+pub struct AddrV2Message {
+    chain: Chain,
+    pub addresses: Vec<PeerAddress>,
+}
+
+impl AddrV2Message {
+    pub fn new(chain: Chain, addresses: Vec<PeerAddress>) -> Self {
+        AddrV2Message { chain, addresses }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let count = parser.read_length(MAX_ADDR_TO_SEND)?;
+        let mut addresses = Vec::with_capacity(count);
+        for _ in 0..count {
+            if let Some(addr) = PeerAddress::parse_v2(&mut parser)? {
+                addresses.push(addr);
+            }
+        }
+        check_consumed(&parser, "addresses")?;
+        Ok(AddrV2Message { chain: raw.chain, addresses })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_compact_size(self.addresses.len() as u64);
+        for addr in &self.addresses {
+            addr.append_v2(&mut composer);
+        }
+        RawMessage::new(self.chain, Command::AddrV2, composer.result())
+    }
+}
+
+/// Signals the support of __addrv2__ messages (BIP155). Only allowed between __version__ and __verack__.
+#[derive(Debug)]
+pub struct SendAddrV2Message {
+    chain: Chain,
+}
+
+impl SendAddrV2Message {
+    pub fn new(chain: Chain) -> Self {
+        SendAddrV2Message { chain }
+    }
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::SendAddrV2, vec![])
+    }
+}
+
+/// _Allows a node to advertise its knowledge of one or more objects._
+///
+/// size  | field     | type        | description
+/// ---   | -----     | ----        | ------------
+/// ?     | count     | var_int     | Number of inventory entries (max: 50000)
+/// 36x?  | inventory | inv_vect[]  | Inventory vectors
+#[derive(Debug)]
+pub struct InvMessage {
+    chain: Chain,
+    pub inventory: Vec<Inventory>,
+}
+
+impl InvMessage {
+    pub fn new(chain: Chain, inventory: Vec<Inventory>) -> Self {
+        InvMessage { chain, inventory }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let inventory = Inventory::parse_list(&mut parser)?;
+        check_consumed(&parser, "inventory")?;
+        Ok(InvMessage { chain: raw.chain, inventory })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        Inventory::append_list(&mut composer, &self.inventory);
+        RawMessage::new(self.chain, Command::Inv, composer.result())
+    }
+}
+
+/// _Used to retrieve the content of specific objects_, usually after receiving an __inv__ message.
+///
+/// size  | field     | type        | description
+/// ---   | -----     | ----        | ------------
+/// ?     | count     | var_int     | Number of inventory entries (max: 50000)
+/// 36x?  | inventory | inv_vect[]  | Inventory vectors
+#[derive(Debug)]
+pub struct GetDataMessage {
+    chain: Chain,
+    pub inventory: Vec<Inventory>,
+}
+
+impl GetDataMessage {
+    pub fn new(chain: Chain, inventory: Vec<Inventory>) -> Self {
+        GetDataMessage { chain, inventory }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let inventory = Inventory::parse_list(&mut parser)?;
+        check_consumed(&parser, "inventory")?;
+        Ok(GetDataMessage { chain: raw.chain, inventory })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        Inventory::append_list(&mut composer, &self.inventory);
+        RawMessage::new(self.chain, Command::GetData, composer.result())
+    }
+}
+
+/// _A response to a __getdata__, sent if any requested data items could not be relayed._
+///
+/// size  | field     | type        | description
+/// ---   | -----     | ----        | ------------
+/// ?     | count     | var_int     | Number of inventory entries (max: 50000)
+/// 36x?  | inventory | inv_vect[]  | Inventory vectors
+#[derive(Debug)]
+pub struct NotFoundMessage {
+    chain: Chain,
+    pub inventory: Vec<Inventory>,
+}
+
+impl NotFoundMessage {
+    pub fn new(chain: Chain, inventory: Vec<Inventory>) -> Self {
+        NotFoundMessage { chain, inventory }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let inventory = Inventory::parse_list(&mut parser)?;
+        check_consumed(&parser, "inventory")?;
+        Ok(NotFoundMessage { chain: raw.chain, inventory })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        Inventory::append_list(&mut composer, &self.inventory);
+        RawMessage::new(self.chain, Command::NotFound, composer.result())
+    }
+}
+
+pub(super) const MAX_INV_PAYLOAD_LENGTH: usize = 3 + MAX_INV_SZ * 36;
+
+/// Maximum number of hashes in a block locator (see `MAX_LOCATOR_SZ` in bitcoin core)
+pub const MAX_LOCATOR_SZ: usize = 101;
+
+pub(super) const MAX_GETHEADERS_PAYLOAD_LENGTH: usize = 4 + 3 + MAX_LOCATOR_SZ * 32 + 32;
+
+/// _Return a headers packet containing the headers of blocks starting right after the last known hash in the block
+/// locator object, up to hash_stop or 2000 blocks, whichever comes first._
+///
+/// size | field          | type       | description
+/// ---  | -----          | ----       | ------------
+/// 4    | version        | u32        | the protocol version
+/// ?    | hash count     | var_int    | number of block locator hash entries (max: 101)
+/// 32x? | locator hashes | [u8; 32][] | block locator object; newest back to genesis block (dense to start, but then sparse)
+/// 32   | hash_stop      | [u8; 32]   | hash of the last desired block header; set to zero to get as many blocks as possible
+#[derive(Debug)]
+pub struct GetHeadersMessage {
+    chain: Chain,
+    pub version: u32,
+    pub locator: Vec<[u8; 32]>,
+    pub stop_hash: [u8; 32],
+}
+
+impl GetHeadersMessage {
+    pub fn new(chain: Chain, version: u32, locator: Vec<[u8; 32]>, stop_hash: [u8; 32]) -> Self {
+        GetHeadersMessage { chain, version, locator, stop_hash }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let version = parser.read_u32_le()?;
+        let count = parser.read_length(MAX_LOCATOR_SZ)?;
+        let locator = (0..count)
+            .map(|_| Ok(parser.read(32)?.try_into().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        check_consumed(&parser, "stop hash")?;
+        Ok(GetHeadersMessage { chain: raw.chain, version, locator, stop_hash })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&self.version.to_le_bytes());
+        composer.append_compact_size(self.locator.len() as u64);
+        for hash in &self.locator {
+            composer.append(hash);
+        }
+        composer.append(&self.stop_hash);
+        RawMessage::new(self.chain, Command::GetHeaders, composer.result())
+    }
+}
+
+/// Maximum number of headers in a __headers__ message (see `MAX_HEADERS_RESULTS` in bitcoin core)
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+pub(super) const MAX_HEADERS_PAYLOAD_LENGTH: usize = 3 + MAX_HEADERS_RESULTS * (BLOCK_HEADER_SIZE + 1);
+
+/// _The headers packet returns block headers in response to a getheaders packet._
+///
+/// size | field   | type             | description
+/// ---  | -----   | ----             | ------------
+/// ?    | count   | var_int          | number of block headers (max: 2000)
+/// 81x? | headers | block_header[]   | block headers, each followed by a transaction count, which is always 0
+#[derive(Debug)]
+pub struct HeadersMessage {
+    chain: Chain,
+    pub headers: Vec<BlockHeader>,
+}
+
+impl HeadersMessage {
+    pub fn new(chain: Chain, headers: Vec<BlockHeader>) -> Self {
+        HeadersMessage { chain, headers }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let count = parser.read_length(MAX_HEADERS_RESULTS)?;
+        let headers = (0..count)
+            .map(|_| {
+                let header = BlockHeader::parse(&mut parser)?;
+                match parser.read_compact_size()? {
+                    0 => Ok(header),
+                    n => Err(io::Error::new(io::ErrorKind::InvalidData, format!("header with transaction count {}", n))),
+                }
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        check_consumed(&parser, "headers")?;
+        Ok(HeadersMessage { chain: raw.chain, headers })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_compact_size(self.headers.len() as u64);
+        for header in &self.headers {
+            header.append(&mut composer);
+            composer.append_compact_size(0);
+        }
+        RawMessage::new(self.chain, Command::Headers, composer.result())
+    }
+}
+
+/// _The block message is sent in response to a getdata message which requests transaction information from a block hash._
+///
+/// size | field        | type          | description
+/// ---  | -----        | ----          | ------------
+/// 80   | header       | block_header  | the block header
+/// ?    | transactions | Transaction[] | transactions, including witness data if requested by MSG_WITNESS_BLOCK
+#[derive(Debug)]
+pub struct BlockMessage {
+    chain: Chain,
+    pub block: Block,
+}
+
+impl BlockMessage {
+    pub fn new(chain: Chain, block: Block) -> Self {
+        BlockMessage { chain, block }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let block = Block::parse(&mut parser)?;
+        check_consumed(&parser, "block")?;
+        Ok(BlockMessage { chain: raw.chain, block })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.block.append(&mut composer);
+        RawMessage::new(self.chain, Command::Block, composer.result())
+    }
+}
+
+/// _tx describes a bitcoin transaction, in reply to getdata._
+///
+/// The transaction is serialized including its witness data (BIP144), if it has any and was requested by
+/// MSG_WITNESS_TX or MSG_WTX.
+#[derive(Debug)]
+pub struct TxMessage {
+    chain: Chain,
+    pub tx: Transaction,
+}
+
+impl TxMessage {
+    pub fn new(chain: Chain, tx: Transaction) -> Self {
+        TxMessage { chain, tx }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let tx = Transaction::from_bytes(&raw.payload)?;
+        Ok(TxMessage { chain: raw.chain, tx })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::Tx, self.tx.to_bytes())
+    }
+}
+
+/// _The mempool message sends a request to a node asking for information about transactions it has verified but which
+/// have not yet confirmed._ The node answers with __inv__ messages (BIP35).
+#[derive(Debug)]
+pub struct MempoolMessage {
+    chain: Chain,
+}
+
+impl MempoolMessage {
     pub fn new(chain: Chain) -> Self {
-        PongMessage { chain }
+        MempoolMessage { chain }
     }
-    pub fn to_raw_message(self) -> RawMessage {
-        let mut rng = thread_rng();
-        RawMessage::new(self.chain, Command::Pong, rng.next_u64().to_le_bytes().to_vec())
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::Mempool, vec![])
+    }
+}
+
+/// Request to announce new blocks by __headers__ instead of __inv__ messages (BIP130)
+#[derive(Debug)]
+pub struct SendHeadersMessage {
+    chain: Chain,
+}
+
+impl SendHeadersMessage {
+    pub fn new(chain: Chain) -> Self {
+        SendHeadersMessage { chain }
+    }
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::SendHeaders, vec![])
+    }
+}
+
+/// Announces the support of compact block relay (BIP152)
+///
+/// size | field    | type | description
+/// ---  | -----    | ---- | ------------
+/// 1    | announce | bool | whether new blocks shall be announced by __cmpctblock__ right away (high-bandwidth mode)
+/// 8    | version  | u64  | compact block version (1: without, 2: with witness data)
+#[derive(Debug)]
+pub struct SendCmpctMessage {
+    chain: Chain,
+    pub announce: bool,
+    pub version: u64,
+}
+
+impl SendCmpctMessage {
+    pub fn new(chain: Chain, announce: bool, version: u64) -> Self {
+        SendCmpctMessage { chain, announce, version }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let announce = parser.read(1)?[0] != 0;
+        let version = parser.read_u64_le()?;
+        check_consumed(&parser, "version")?;
+        Ok(SendCmpctMessage { chain: raw.chain, announce, version })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.announce as u8]);
+        composer.append(&self.version.to_le_bytes());
+        RawMessage::new(self.chain, Command::SendCmpct, composer.result())
+    }
+}
+
+/// Asks not to announce transactions below the given fee rate (BIP133)
+///
+/// size | field    | type | description
+/// ---  | -----    | ---- | ------------
+/// 8    | fee_rate | i64  | minimal fee rate in satoshis per 1000 vbytes
+#[derive(Debug)]
+pub struct FeeFilterMessage {
+    chain: Chain,
+    pub fee_rate: i64,
+}
+
+impl FeeFilterMessage {
+    pub fn new(chain: Chain, fee_rate: i64) -> Self {
+        FeeFilterMessage { chain, fee_rate }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let fee_rate = parser.read_i64_le()?;
+        check_consumed(&parser, "fee rate")?;
+        Ok(FeeFilterMessage { chain: raw.chain, fee_rate })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::FeeFilter, self.fee_rate.to_le_bytes().to_vec())
+    }
+}
+
+/// Announces transactions by wtxid instead of txid (BIP339). Only allowed between __version__ and __verack__.
+#[derive(Debug)]
+pub struct WtxidRelayMessage {
+    chain: Chain,
+}
+
+impl WtxidRelayMessage {
+    pub fn new(chain: Chain) -> Self {
+        WtxidRelayMessage { chain }
+    }
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::WtxidRelay, vec![])
+    }
+}
+
+/// _A cmpctblock message announces a block in compact form_ (BIP152): its header together with short ids of its
+/// transactions, some of which may be prefilled (see [HeaderAndShortIds])
+#[derive(Debug)]
+pub struct CmpctBlockMessage {
+    chain: Chain,
+    pub compact_block: HeaderAndShortIds,
+}
+
+impl CmpctBlockMessage {
+    pub fn new(chain: Chain, compact_block: HeaderAndShortIds) -> Self {
+        CmpctBlockMessage { chain, compact_block }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let compact_block = HeaderAndShortIds::parse(&mut parser)?;
+        check_consumed(&parser, "compact block")?;
+        Ok(CmpctBlockMessage { chain: raw.chain, compact_block })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.compact_block.append(&mut composer);
+        RawMessage::new(self.chain, Command::CmpctBlock, composer.result())
+    }
+}
+
+/// _The getblocktxn message requests the transactions of a block, which could not be reconstructed from a compact
+/// block_ (BIP152, see [BlockTransactionsRequest])
+#[derive(Debug)]
+pub struct GetBlockTxnMessage {
+    chain: Chain,
+    pub request: BlockTransactionsRequest,
+}
+
+impl GetBlockTxnMessage {
+    pub fn new(chain: Chain, request: BlockTransactionsRequest) -> Self {
+        GetBlockTxnMessage { chain, request }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let request = BlockTransactionsRequest::parse(&mut parser)?;
+        check_consumed(&parser, "block transactions request")?;
+        Ok(GetBlockTxnMessage { chain: raw.chain, request })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.request.append(&mut composer);
+        RawMessage::new(self.chain, Command::GetBlockTxn, composer.result())
+    }
+}
+
+/// _The blocktxn message answers a getblocktxn message with the requested transactions_ (BIP152, see [BlockTransactions])
+#[derive(Debug)]
+pub struct BlockTxnMessage {
+    chain: Chain,
+    pub transactions: BlockTransactions,
+}
+
+impl BlockTxnMessage {
+    pub fn new(chain: Chain, transactions: BlockTransactions) -> Self {
+        BlockTxnMessage { chain, transactions }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let transactions = BlockTransactions::parse(&mut parser)?;
+        check_consumed(&parser, "block transactions")?;
+        Ok(BlockTxnMessage { chain: raw.chain, transactions })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.transactions.append(&mut composer);
+        RawMessage::new(self.chain, Command::BlockTxn, composer.result())
+    }
+}
+
+/// Maximum number of filters requested by a single __getcfilters__ message (BIP157)
+pub const MAX_GETCFILTERS_SIZE: u32 = 1000;
+
+/// Maximum number of filter hashes requested by a single __getcfheaders__ message (BIP157)
+pub const MAX_GETCFHEADERS_SIZE: u32 = 2000;
+
+/// Distance of the filter headers in a __cfcheckpt__ message (BIP157)
+pub const CFCHECKPT_INTERVAL: u32 = 1000;
+
+pub(super) const MAX_CFHEADERS_PAYLOAD_LENGTH: usize = 1 + 32 + 32 + 3 + MAX_GETCFHEADERS_SIZE as usize * 32;
+
+/// _Request compact filters of a particular type for a range of blocks_ (BIP157). The answer is a __cfilter__ message
+/// for each block, in order of their height.
+///
+/// size | field        | type     | description
+/// ---  | -----        | ----     | ------------
+/// 1    | filter_type  | u8       | filter type (0: basic filter)
+/// 4    | start_height | u32      | height of the first block of the range
+/// 32   | stop_hash    | [u8; 32] | hash of the last block of the range (max: 1000 blocks)
+#[derive(Debug)]
+pub struct GetCFiltersMessage {
+    chain: Chain,
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+impl GetCFiltersMessage {
+    pub fn new(chain: Chain, filter_type: u8, start_height: u32, stop_hash: [u8; 32]) -> Self {
+        GetCFiltersMessage { chain, filter_type, start_height, stop_hash }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let start_height = parser.read_u32_le()?;
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        check_consumed(&parser, "stop hash")?;
+        Ok(GetCFiltersMessage { chain: raw.chain, filter_type, start_height, stop_hash })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter_type]);
+        composer.append(&self.start_height.to_le_bytes());
+        composer.append(&self.stop_hash);
+        RawMessage::new(self.chain, Command::GetCFilters, composer.result())
+    }
+}
+
+/// _Compact filter of a block_, in reply to __getcfilters__ (BIP157)
+///
+/// size | field         | type     | description
+/// ---  | -----         | ----     | ------------
+/// 1    | filter_type   | u8       | filter type (0: basic filter)
+/// 32   | block_hash    | [u8; 32] | hash of the block the filter belongs to
+/// ?    | filter_length | var_int  | length of the filter in bytes
+/// ?    | filter_bytes  | [u8]     | the serialized filter (see [BlockFilter])
+#[derive(Debug)]
+pub struct CFilterMessage {
+    chain: Chain,
+    pub filter: BlockFilter,
+}
+
+impl CFilterMessage {
+    pub fn new(chain: Chain, filter: BlockFilter) -> Self {
+        CFilterMessage { chain, filter }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let block_hash = parser.read(32)?.try_into().unwrap();
+        let content = parser.read_var_bytes(parser.remaining())?.to_vec();
+        check_consumed(&parser, "filter")?;
+        Ok(CFilterMessage { chain: raw.chain, filter: BlockFilter::new(filter_type, block_hash, content) })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter.filter_type]);
+        composer.append(&self.filter.block_hash);
+        composer.append_var_bytes(&self.filter.content);
+        RawMessage::new(self.chain, Command::CFilter, composer.result())
+    }
+}
+
+/// _Request verifiable filter headers for a range of blocks_ (BIP157). The answer is a __cfheaders__ message.
+///
+/// size | field        | type     | description
+/// ---  | -----        | ----     | ------------
+/// 1    | filter_type  | u8       | filter type (0: basic filter)
+/// 4    | start_height | u32      | height of the first block of the range
+/// 32   | stop_hash    | [u8; 32] | hash of the last block of the range (max: 2000 blocks)
+#[derive(Debug)]
+pub struct GetCFHeadersMessage {
+    chain: Chain,
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: [u8; 32],
+}
+
+impl GetCFHeadersMessage {
+    pub fn new(chain: Chain, filter_type: u8, start_height: u32, stop_hash: [u8; 32]) -> Self {
+        GetCFHeadersMessage { chain, filter_type, start_height, stop_hash }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let start_height = parser.read_u32_le()?;
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        check_consumed(&parser, "stop hash")?;
+        Ok(GetCFHeadersMessage { chain: raw.chain, filter_type, start_height, stop_hash })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter_type]);
+        composer.append(&self.start_height.to_le_bytes());
+        composer.append(&self.stop_hash);
+        RawMessage::new(self.chain, Command::GetCFHeaders, composer.result())
+    }
+}
+
+/// _Filter hashes of a range of blocks together with the filter header preceding the range_, in reply to
+/// __getcfheaders__ (BIP157). The filter headers of the range follow from them (see
+/// [filter_header](crate::wire_protocol::compact_filter::filter_header)).
+///
+/// size | field                  | type       | description
+/// ---  | -----                  | ----       | ------------
+/// 1    | filter_type            | u8         | filter type (0: basic filter)
+/// 32   | stop_hash              | [u8; 32]   | hash of the last block of the range
+/// 32   | previous_filter_header | [u8; 32]   | filter header of the block preceding the range
+/// ?    | filter_hashes length   | var_int    | number of filter hashes (max: 2000)
+/// 32x? | filter_hashes          | [u8; 32][] | filter hashes of the blocks in the range
+#[derive(Debug)]
+pub struct CFHeadersMessage {
+    chain: Chain,
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub previous_filter_header: [u8; 32],
+    pub filter_hashes: Vec<[u8; 32]>,
+}
+
+impl CFHeadersMessage {
+    pub fn new(chain: Chain, filter_type: u8, stop_hash: [u8; 32], previous_filter_header: [u8; 32], filter_hashes: Vec<[u8; 32]>) -> Self {
+        CFHeadersMessage { chain, filter_type, stop_hash, previous_filter_header, filter_hashes }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        let previous_filter_header = parser.read(32)?.try_into().unwrap();
+        let count = parser.read_length(MAX_GETCFHEADERS_SIZE as usize)?;
+        let filter_hashes = (0..count)
+            .map(|_| Ok(parser.read(32)?.try_into().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        check_consumed(&parser, "filter hashes")?;
+        Ok(CFHeadersMessage { chain: raw.chain, filter_type, stop_hash, previous_filter_header, filter_hashes })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter_type]);
+        composer.append(&self.stop_hash);
+        composer.append(&self.previous_filter_header);
+        composer.append_compact_size(self.filter_hashes.len() as u64);
+        for hash in &self.filter_hashes {
+            composer.append(hash);
+        }
+        RawMessage::new(self.chain, Command::CFHeaders, composer.result())
+    }
+}
+
+/// _Request filter headers at evenly spaced intervals over a range of blocks_, starting at the genesis block
+/// (BIP157). The answer is a __cfcheckpt__ message.
+///
+/// size | field       | type     | description
+/// ---  | -----       | ----     | ------------
+/// 1    | filter_type | u8       | filter type (0: basic filter)
+/// 32   | stop_hash   | [u8; 32] | hash of the last block of the range
+#[derive(Debug)]
+pub struct GetCFCheckptMessage {
+    chain: Chain,
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+}
+
+impl GetCFCheckptMessage {
+    pub fn new(chain: Chain, filter_type: u8, stop_hash: [u8; 32]) -> Self {
+        GetCFCheckptMessage { chain, filter_type, stop_hash }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        check_consumed(&parser, "stop hash")?;
+        Ok(GetCFCheckptMessage { chain: raw.chain, filter_type, stop_hash })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter_type]);
+        composer.append(&self.stop_hash);
+        RawMessage::new(self.chain, Command::GetCFCheckpt, composer.result())
+    }
+}
+
+/// _Filter headers of every 1000th block_ up to the stop hash, in reply to __getcfcheckpt__ (BIP157).
+/// The first one is the filter header at height 999.
+///
+/// size | field                 | type       | description
+/// ---  | -----                 | ----       | ------------
+/// 1    | filter_type           | u8         | filter type (0: basic filter)
+/// 32   | stop_hash             | [u8; 32]   | hash of the last block of the range
+/// ?    | filter_headers length | var_int    | number of filter headers
+/// 32x? | filter_headers        | [u8; 32][] | filter headers at the heights 999, 1999, ...
+#[derive(Debug)]
+pub struct CFCheckptMessage {
+    chain: Chain,
+    pub filter_type: u8,
+    pub stop_hash: [u8; 32],
+    pub filter_headers: Vec<[u8; 32]>,
+}
+
+impl CFCheckptMessage {
+    pub fn new(chain: Chain, filter_type: u8, stop_hash: [u8; 32], filter_headers: Vec<[u8; 32]>) -> Self {
+        CFCheckptMessage { chain, filter_type, stop_hash, filter_headers }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter_type = parser.read(1)?[0];
+        let stop_hash = parser.read(32)?.try_into().unwrap();
+        let count = parser.read_length(parser.remaining() / 32)?;
+        let filter_headers = (0..count)
+            .map(|_| Ok(parser.read(32)?.try_into().unwrap()))
+            .collect::<io::Result<Vec<_>>>()?;
+        check_consumed(&parser, "filter headers")?;
+        Ok(CFCheckptMessage { chain: raw.chain, filter_type, stop_hash, filter_headers })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append(&[self.filter_type]);
+        composer.append(&self.stop_hash);
+        composer.append_compact_size(self.filter_headers.len() as u64);
+        for header in &self.filter_headers {
+            composer.append(header);
+        }
+        RawMessage::new(self.chain, Command::CFCheckpt, composer.result())
+    }
+}
+
+/// Maximum payload length of a __filterload__ message: a filter of [MAX_BLOOM_FILTER_SIZE] bytes with its length prefix,
+/// the number of hash functions, the tweak and the flags
+pub(super) const MAX_FILTERLOAD_PAYLOAD_LENGTH: usize = 3 + MAX_BLOOM_FILTER_SIZE + 4 + 4 + 1;
+
+/// Maximum payload length of a __filteradd__ message: an element of [MAX_SCRIPT_ELEMENT_SIZE] bytes with its length
+/// prefix
+pub(super) const MAX_FILTERADD_PAYLOAD_LENGTH: usize = 3 + MAX_SCRIPT_ELEMENT_SIZE;
+
+/// _Sets a bloom filter on the connection_ (BIP37, see [BloomFilter]). From now on, only transactions matching the
+/// filter are announced, and blocks requested as filtered blocks are answered by __merkleblock__ messages.
+#[derive(Debug)]
+pub struct FilterLoadMessage {
+    chain: Chain,
+    pub filter: BloomFilter,
+}
+
+impl FilterLoadMessage {
+    pub fn new(chain: Chain, filter: BloomFilter) -> Self {
+        FilterLoadMessage { chain, filter }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let filter = BloomFilter::parse(&mut parser)?;
+        check_consumed(&parser, "bloom filter")?;
+        Ok(FilterLoadMessage { chain: raw.chain, filter })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.filter.append(&mut composer);
+        RawMessage::new(self.chain, Command::FilterLoad, composer.result())
+    }
+}
+
+/// _Adds an element to the bloom filter of the connection_ (BIP37)
+///
+/// size | field   | type      | description
+/// ---  | -----   | ----      | ------------
+/// ?    | element | var_bytes | the element to be added (max: 520 bytes)
+#[derive(Debug)]
+pub struct FilterAddMessage {
+    chain: Chain,
+    pub element: Vec<u8>,
+}
+
+impl FilterAddMessage {
+    pub fn new(chain: Chain, element: Vec<u8>) -> Self {
+        FilterAddMessage { chain, element }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let element = parser.read_var_bytes(MAX_SCRIPT_ELEMENT_SIZE)?.to_vec();
+        check_consumed(&parser, "filter element")?;
+        Ok(FilterAddMessage { chain: raw.chain, element })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_var_bytes(&self.element);
+        RawMessage::new(self.chain, Command::FilterAdd, composer.result())
+    }
+}
+
+/// _Removes the bloom filter of the connection_ (BIP37), so that all transactions are announced again
+#[derive(Debug)]
+pub struct FilterClearMessage {
+    chain: Chain,
+}
+
+impl FilterClearMessage {
+    pub fn new(chain: Chain) -> Self {
+        FilterClearMessage { chain }
+    }
+    pub fn to_raw_message(&self) -> RawMessage {
+        RawMessage::new(self.chain, Command::FilterClear, vec![])
+    }
+}
+
+/// _Block header with the partial merkle tree of the transactions matching the bloom filter_, in reply to __getdata__
+/// for a filtered block (BIP37, see [MerkleBlock]). The matching transactions follow as __tx__ messages.
+#[derive(Debug)]
+pub struct MerkleBlockMessage {
+    chain: Chain,
+    pub merkle_block: MerkleBlock,
+}
+
+impl MerkleBlockMessage {
+    pub fn new(chain: Chain, merkle_block: MerkleBlock) -> Self {
+        MerkleBlockMessage { chain, merkle_block }
+    }
+
+    pub(super) fn from_raw_message(raw: RawMessage) -> PeerResult<Self> {
+        let mut parser = ByteBufferParser::new(&raw.payload);
+        let merkle_block = MerkleBlock::parse(&mut parser)?;
+        check_consumed(&parser, "merkle block")?;
+        Ok(MerkleBlockMessage { chain: raw.chain, merkle_block })
+    }
+
+    pub fn to_raw_message(&self) -> RawMessage {
+        let mut composer = ByteBufferComposer::new();
+        self.merkle_block.append(&mut composer);
+        RawMessage::new(self.chain, Command::MerkleBlock, composer.result())
+    }
+}
+
+/// rejects bytes left in the payload after its content `what`
+fn check_consumed(parser: &ByteBufferParser, what: &str) -> io::Result<()> {
+    match parser.remaining() {
+        0 => Ok(()),
+        n => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} bytes left after the {}", n, what))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use hex_literal::hex;
+
+    use crate::error::PeerError;
+    use crate::wire_protocol::bloom::BloomUpdate;
+    use crate::wire_protocol::inventory::InventoryType;
+    use crate::wire_protocol::merkle_block::PartialMerkleTree;
+    use crate::wire_protocol::node::NodeService;
+
+    use super::*;
+
+    const VERSION_PAYLOAD: [u8; 102] = hex!("801101000100000000000000f2ff116400000000000000000000000000000000000000000000ffff7f000001480c0100000000000000000000000000000000000000000000000000efcdab8967452301102f5361746f7368693a32342e302e312f7000000001");
+
+    #[test]
+    fn test_version_message_decoding() {
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, VERSION_PAYLOAD.to_vec());
+        let m = VersionMessage::from_raw_message(raw).unwrap();
+
+        assert_eq!(m.protocol_version, 70016);
+        assert_eq!(m.services, NodeServiceSet::new(&[NodeService::NodeNetwork]));
+        assert_eq!(m.timestamp, 1678901234);
+        assert_eq!(m.addr_recv_services, NodeServiceSet::default());
+        assert_eq!(m.addr_recv, SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 18444));
+        assert_eq!(m.addr_from_services, NodeServiceSet::new(&[NodeService::NodeNetwork]));
+        assert_eq!(m.addr_from, SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)));
+        assert_eq!(m.nonce, 0x0123456789abcdef);
+        assert_eq!(m.sub_ver, b"/Satoshi:24.0.1/");
+        assert_eq!(m.start_height, 112);
+        assert_eq!(m.relay, Some(true));
+    }
+
+    #[test]
+    fn test_version_message_round_trip() {
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, VERSION_PAYLOAD.to_vec());
+        let m = VersionMessage::from_raw_message(raw).unwrap();
+        assert_eq!(m.to_raw_message().payload, VERSION_PAYLOAD);
+    }
+
+    #[test]
+    fn test_version_message_without_relay_field() {
+        let payload = &VERSION_PAYLOAD[..VERSION_PAYLOAD.len() - 1];
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, payload.to_vec());
+        let m = VersionMessage::from_raw_message(raw).unwrap();
+
+        assert_eq!(m.relay, None);
+        assert_eq!(m.to_raw_message().payload, payload);
+    }
+
+    #[test]
+    fn test_version_message_relay_flag_round_trip() {
+        for (flag, relay) in [(0, false), (1, true)] {
+            let mut payload = VERSION_PAYLOAD.to_vec();
+            *payload.last_mut().unwrap() = flag;
+            let m = VersionMessage::from_raw_message(RawMessage::new(Chain::Regtest, Command::Version, payload.clone())).unwrap();
+            assert_eq!(m.relay, Some(relay));
+            assert_eq!(m.to_raw_message().payload, payload);
+        }
+    }
+
+    #[test]
+    fn test_version_message_not_re_encodable_is_malformed() {
+        let mut invalid_relay = VERSION_PAYLOAD.to_vec();
+        *invalid_relay.last_mut().unwrap() = 2;
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, invalid_relay);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+
+        let mut trailing_bytes = VERSION_PAYLOAD.to_vec();
+        trailing_bytes.push(0);
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, trailing_bytes);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_version_message_with_non_utf8_user_agent() {
+        // "/Satoshi:24.0.1/" with an invalid UTF-8 byte in place of the ':'
+        let mut payload = VERSION_PAYLOAD.to_vec();
+        payload[89] = 0xff;
+        let raw = RawMessage::new(Chain::Regtest, Command::Version, payload.clone());
+        let m = VersionMessage::from_raw_message(raw).unwrap();
+
+        assert_eq!(m.sub_ver, b"/Satoshi\xff24.0.1/");
+        assert_eq!(m.user_agent(), "/Satoshi\u{fffd}24.0.1/");
+        assert_eq!(m.start_height, 112);
+        assert_eq!(m.to_raw_message().payload, payload);
+    }
+
+    #[test]
+    fn test_pong_echoes_ping_nonce() {
+        let ping = PingMessage::new(Chain::Regtest);
+        assert_ne!(ping.nonce, 0);
+        let raw = ping.to_raw_message();
+        assert_eq!(raw.payload, ping.nonce.to_le_bytes());
+
+        let received = PingMessage::from_raw_message(raw).unwrap();
+        let pong = PongMessage::reply_to(&received).to_raw_message();
+        assert!(matches!(pong.command, Command::Pong));
+        assert_eq!(PongMessage::from_raw_message(pong).unwrap().nonce, ping.nonce);
+    }
+
+    /// bytes following the fields of a message make it malformed
+    fn assert_malformed_with_trailing_byte(message: ProtocolMessage) {
+        let mut raw = RawMessage::from(message);
+        raw.payload.push(0);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_ping_and_pong_payload_is_exactly_the_nonce() {
+        let raw = RawMessage::new(Chain::Regtest, Command::Ping, vec![]);
+        assert!(PingMessage::from_raw_message(raw).is_err());
+
+        let ping = PingMessage::new(Chain::Regtest);
+        assert_malformed_with_trailing_byte(ProtocolMessage::Pong(PongMessage::reply_to(&ping)));
+        assert_malformed_with_trailing_byte(ProtocolMessage::Ping(ping));
+    }
+
+    #[test]
+    fn test_addr_message_round_trip() {
+        let payload = hex!("02" "f2ff1164" "0100000000000000" "00000000000000000000ffff7f000001" "480c"
+                           "f3ff1164" "0900000000000000" "20010db8000000000000000000000001" "208d");
+        let m = AddrMessage::from_raw_message(RawMessage::new(Chain::Regtest, Command::Addr, payload.to_vec())).unwrap();
+
+        assert_eq!(m.addresses.len(), 2);
+        assert_eq!(m.addresses[1].time, 1678901235);
+        assert_eq!(m.addresses[1].addr, "[2001:db8::1]:8333".parse().unwrap());
+        assert_eq!(m.to_raw_message().payload, payload);
+    }
+
+    #[test]
+    fn test_addr_message_limit() {
+        // 1001 entries announced
+        let raw = RawMessage::new(Chain::Regtest, Command::Addr, hex!("fde903").to_vec());
+        assert!(AddrMessage::from_raw_message(raw).is_err());
+    }
+
+    #[test]
+    fn test_addr_v2_message_skips_unknown_networks() {
+        let payload = hex!("03" "f2ff1164" "01" "01" "04" "01020304" "208d"
+                           "f2ff1164" "01" "07" "02" "0102" "208d"
+                           "f2ff1164" "01" "02" "10" "20010db8000000000000000000000001" "208d");
+        let m = AddrV2Message::from_raw_message(RawMessage::new(Chain::Regtest, Command::AddrV2, payload.to_vec())).unwrap();
+
+        assert_eq!(m.addresses.len(), 2);
+        assert_eq!(m.addresses[0].socket_addr(), Some("1.2.3.4:8333".parse().unwrap()));
+        assert_eq!(m.addresses[1].socket_addr(), Some("[2001:db8::1]:8333".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_getdata_message_round_trip() {
+        let inventory = vec![
+            Inventory::new(InventoryType::WitnessTx, [1; 32]),
+            Inventory::new(InventoryType::WitnessBlock, [2; 32]),
+        ];
+        let raw = GetDataMessage::new(Chain::Regtest, inventory.clone()).to_raw_message();
+        assert!(matches!(raw.command, Command::GetData));
+        assert_eq!(raw.payload.len(), 1 + 2 * 36);
+
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::GetData(m) => assert_eq!(m.inventory, inventory),
+            other => panic!("expected a getdata message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_getheaders_message_round_trip() {
+        let locator = vec![[3; 32], Chain::Regtest.genesis_hash()];
+        let raw = GetHeadersMessage::new(Chain::Regtest, 70016, locator.clone(), [0; 32]).to_raw_message();
+        assert_eq!(raw.payload.len(), 4 + 1 + 2 * 32 + 32);
+        assert_eq!(&raw.payload[..5], &hex!("80110100" "02"));
+
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::GetHeaders(m) => {
+                assert_eq!(m.version, 70016);
+                assert_eq!(m.locator, locator);
+                assert_eq!(m.stop_hash, [0; 32]);
+            }
+            other => panic!("expected a getheaders message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_headers_message_round_trip() {
+        let headers = vec![Chain::Mainnet.genesis_header(), Chain::Testnet3.genesis_header()];
+        let raw = HeadersMessage::new(Chain::Mainnet, headers.clone()).to_raw_message();
+        assert_eq!(raw.payload.len(), 1 + 2 * 81);
+
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::Headers(m) => assert_eq!(m.headers, headers),
+            other => panic!("expected a headers message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_headers_with_transactions_are_malformed() {
+        let mut payload = vec![1];
+        payload.extend(Chain::Mainnet.genesis_header().to_bytes());
+        payload.push(1);
+        let raw = RawMessage::new(Chain::Mainnet, Command::Headers, payload);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_block_message_with_trailing_bytes_is_malformed() {
+        let mut payload = Chain::Regtest.genesis_header().to_bytes();
+        payload.extend(hex!("00" "00"));
+        let raw = RawMessage::new(Chain::Regtest, Command::Block, payload);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_sendcmpct_message_round_trip() {
+        let raw = SendCmpctMessage::new(Chain::Regtest, false, 2).to_raw_message();
+        assert_eq!(raw.payload, hex!("00" "0200000000000000"));
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::SendCmpct(m) => {
+                assert!(!m.announce);
+                assert_eq!(m.version, 2);
+            }
+            other => panic!("expected a sendcmpct message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_feefilter_message_round_trip() {
+        let raw = FeeFilterMessage::new(Chain::Regtest, 1000).to_raw_message();
+        assert_eq!(raw.payload, hex!("e803000000000000"));
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::FeeFilter(m) => assert_eq!(m.fee_rate, 1000),
+            other => panic!("expected a feefilter message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_getblocktxn_message_round_trip() {
+        let request = BlockTransactionsRequest { block_hash: Chain::Regtest.genesis_hash(), indexes: vec![1, 2, 5] };
+        let raw = GetBlockTxnMessage::new(Chain::Regtest, request.clone()).to_raw_message();
+        assert_eq!(&raw.payload[32..], &hex!("03" "01" "00" "02"));
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::GetBlockTxn(m) => assert_eq!(m.request, request),
+            other => panic!("expected a getblocktxn message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cmpctblock_message_with_trailing_bytes_is_malformed() {
+        let mut payload = Chain::Regtest.genesis_header().to_bytes();
+        payload.extend(hex!("0000000000000000" "00" "00" "00"));
+        let raw = RawMessage::new(Chain::Regtest, Command::CmpctBlock, payload);
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_getcfilters_message_round_trip() {
+        let raw = GetCFiltersMessage::new(Chain::Regtest, 0, 1000, [7; 32]).to_raw_message();
+        assert_eq!(&raw.payload[..5], &hex!("00" "e8030000"));
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::GetCFilters(m) => {
+                assert_eq!((m.filter_type, m.start_height, m.stop_hash), (0, 1000, [7; 32]));
+            }
+            other => panic!("expected a getcfilters message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cfilter_message_round_trip() {
+        let filter = BlockFilter::new(0, Chain::Testnet3.genesis_hash(), hex!("019dfca8").to_vec());
+        let raw = CFilterMessage::new(Chain::Testnet3, filter.clone()).to_raw_message();
+        assert_eq!(&raw.payload[33..], &hex!("04" "019dfca8"));
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::CFilter(m) => assert_eq!(m.filter, filter),
+            other => panic!("expected a cfilter message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cfheaders_message_round_trip() {
+        let raw = CFHeadersMessage::new(Chain::Regtest, 0, [7; 32], [1; 32], vec![[2; 32], [3; 32]]).to_raw_message();
+        assert_eq!(raw.payload.len(), 1 + 32 + 32 + 1 + 2 * 32);
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::CFHeaders(m) => {
+                assert_eq!(m.stop_hash, [7; 32]);
+                assert_eq!(m.previous_filter_header, [1; 32]);
+                assert_eq!(m.filter_hashes, vec![[2; 32], [3; 32]]);
+            }
+            other => panic!("expected a cfheaders message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cfcheckpt_message_round_trip() {
+        let raw = CFCheckptMessage::new(Chain::Regtest, 0, [7; 32], vec![[2; 32]]).to_raw_message();
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::CFCheckpt(m) => {
+                assert_eq!(m.stop_hash, [7; 32]);
+                assert_eq!(m.filter_headers, vec![[2; 32]]);
+            }
+            other => panic!("expected a cfcheckpt message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_filterload_message_round_trip() {
+        let mut filter = BloomFilter::new(3, 0.01, 0, BloomUpdate::All);
+        filter.insert(&hex!("99108ad8ed9bb6274d3980bab5a85c048f0950c8"));
+        let raw = FilterLoadMessage::new(Chain::Regtest, filter.clone()).to_raw_message();
+        assert_eq!(raw.payload.len(), 1 + 3 + 4 + 4 + 1);
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::FilterLoad(m) => assert_eq!(m.filter, filter),
+            other => panic!("expected a filterload message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_oversized_filters_are_malformed() {
+        let filter = BloomFilter { data: vec![0; MAX_BLOOM_FILTER_SIZE + 1], hash_funcs: 1, tweak: 0, flags: BloomUpdate::None as u8 };
+        let raw = FilterLoadMessage::new(Chain::Regtest, filter).to_raw_message();
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+
+        let raw = FilterAddMessage::new(Chain::Regtest, vec![0; MAX_SCRIPT_ELEMENT_SIZE + 1]).to_raw_message();
+        assert!(matches!(raw.into_protocol_message(), Err(PeerError::Malformed { .. })));
+    }
+
+    #[test]
+    fn test_filteradd_message_round_trip() {
+        let raw = FilterAddMessage::new(Chain::Regtest, vec![7; 20]).to_raw_message();
+        assert_eq!(raw.payload[0], 20);
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::FilterAdd(m) => assert_eq!(m.element, vec![7; 20]),
+            other => panic!("expected a filteradd message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_merkleblock_message_round_trip() {
+        let merkle_block = MerkleBlock {
+            header: Chain::Regtest.genesis_header(),
+            tree: PartialMerkleTree::new(&[[1; 32], [2; 32], [3; 32]], &[false, true, false]).unwrap(),
+        };
+        let raw = MerkleBlockMessage::new(Chain::Regtest, merkle_block.clone()).to_raw_message();
+        match raw.into_protocol_message().unwrap() {
+            ProtocolMessage::MerkleBlock(m) => assert_eq!(m.merkle_block, merkle_block),
+            other => panic!("expected a merkleblock message, got {:?}", other),
+        }
+    }
+
+
+    #[test]
+    fn test_addr_messages_with_trailing_bytes_are_malformed() {
+        assert_malformed_with_trailing_byte(ProtocolMessage::Addr(AddrMessage::new(Chain::Regtest, vec![])));
+        assert_malformed_with_trailing_byte(ProtocolMessage::AddrV2(AddrV2Message::new(Chain::Regtest, vec![])));
+    }
+
+
+    #[test]
+    fn test_inventory_messages_with_trailing_bytes_are_malformed() {
+        let inventory = vec![Inventory { inv_type: InventoryType::Tx, hash: [1; 32] }];
+        assert_malformed_with_trailing_byte(ProtocolMessage::Inv(InvMessage::new(Chain::Regtest, inventory.clone())));
+        assert_malformed_with_trailing_byte(ProtocolMessage::GetData(GetDataMessage::new(Chain::Regtest, inventory.clone())));
+        assert_malformed_with_trailing_byte(ProtocolMessage::NotFound(NotFoundMessage::new(Chain::Regtest, inventory)));
+    }
+
+
+    #[test]
+    fn test_header_messages_with_trailing_bytes_are_malformed() {
+        let genesis = Chain::Regtest.genesis_header();
+        let get_headers = GetHeadersMessage::new(Chain::Regtest, 70016, vec![genesis.block_hash()], [0; 32]);
+        assert_malformed_with_trailing_byte(ProtocolMessage::GetHeaders(get_headers));
+        assert_malformed_with_trailing_byte(ProtocolMessage::Headers(HeadersMessage::new(Chain::Regtest, vec![genesis])));
+    }
+
+
+    #[test]
+    fn test_negotiation_messages_with_trailing_bytes_are_malformed() {
+        assert_malformed_with_trailing_byte(ProtocolMessage::SendCmpct(SendCmpctMessage::new(Chain::Regtest, false, 2)));
+        assert_malformed_with_trailing_byte(ProtocolMessage::FeeFilter(FeeFilterMessage::new(Chain::Regtest, 1000)));
+    }
+
+
+    #[test]
+    fn test_filter_messages_with_trailing_bytes_are_malformed() {
+        assert_malformed_with_trailing_byte(ProtocolMessage::GetCFilters(GetCFiltersMessage::new(Chain::Regtest, 0, 1000, [7; 32])));
+        assert_malformed_with_trailing_byte(ProtocolMessage::GetCFHeaders(GetCFHeadersMessage::new(Chain::Regtest, 0, 1000, [7; 32])));
+        let cfheaders = CFHeadersMessage::new(Chain::Regtest, 0, [7; 32], [1; 32], vec![[2; 32], [3; 32]]);
+        assert_malformed_with_trailing_byte(ProtocolMessage::CFHeaders(cfheaders));
+        assert_malformed_with_trailing_byte(ProtocolMessage::GetCFCheckpt(GetCFCheckptMessage::new(Chain::Regtest, 0, [7; 32])));
+        assert_malformed_with_trailing_byte(ProtocolMessage::CFCheckpt(CFCheckptMessage::new(Chain::Regtest, 0, [7; 32], vec![[2; 32]])));
     }
 }
@@ -0,0 +1,23 @@
+use std::net::SocketAddr;
+
+#[cfg(feature = "tokio")]
+use tokio::sync::mpsc;
+
+/// Misbehavior of a remote node, which is reported by its [NodeConnection](crate::wire_protocol::connection::NodeConnection)
+/// (see [NodeConnection::set_misbehavior_reporter](crate::wire_protocol::connection::NodeConnection::set_misbehavior_reporter)).
+#[derive(Clone, Debug, PartialEq)]
+pub enum Misbehavior {
+    /// a message announced a payload larger than allowed for its command. The connection is given up.
+    OversizedMessage { command: String, len: usize, max: usize },
+    /// bytes, which do not form a valid message (unknown magic, checksum mismatch), were dropped to resynchronize the stream
+    Garbage { dropped_bytes: usize, reason: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MisbehaviorEvent {
+    pub peer: SocketAddr,
+    pub misbehavior: Misbehavior,
+}
+
+#[cfg(feature = "tokio")]
+pub type MisbehaviorReporter = mpsc::UnboundedSender<MisbehaviorEvent>;
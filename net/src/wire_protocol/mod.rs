@@ -1,6 +1,30 @@
+pub mod address;
+pub mod addr_discovery;
+pub mod block;
+pub mod block_download;
+pub mod bloom;
+pub mod compact_block;
+pub mod compact_filter;
+pub mod filter_download;
 pub mod handshake;
+pub mod headers_sync;
+pub mod inventory;
+pub mod keepalive;
+#[cfg(feature = "tokio")]
 pub mod connection;
+#[cfg(feature = "tokio")]
+pub mod listener;
+#[cfg(feature = "tokio")]
+pub mod mempool_observer;
+pub mod merkle_block;
 pub mod node;
+pub mod peer;
 pub mod messages;
+pub mod misbehavior;
+pub mod negotiation;
+pub mod transaction;
+#[cfg(feature = "tokio")]
+pub mod transport;
+pub mod v2_transport;
 mod buffer;
 mod raw_message;
@@ -0,0 +1,181 @@
+use crate::wire_protocol::messages::{FeeFilterMessage, ProtocolMessage, SendCmpctMessage, SendHeadersMessage, WtxidRelayMessage};
+use crate::wire_protocol::node::NodeDesc;
+
+/// __wtxidrelay__ is only sent to peers of at least this protocol version (BIP339); it has to precede __verack__
+const WTXID_RELAY_VERSION: i32 = 70016;
+/// __sendheaders__ is only sent to peers of at least this protocol version (BIP130)
+const SENDHEADERS_VERSION: i32 = 70012;
+/// __feefilter__ is only sent to peers of at least this protocol version (BIP133)
+const FEEFILTER_VERSION: i32 = 70013;
+/// __sendcmpct__ is only sent to peers of at least this protocol version (BIP152)
+const SHORT_IDS_BLOCKS_VERSION: i32 = 70014;
+
+/// Compact block relay as announced by a __sendcmpct__ message (BIP152)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompactBlocks {
+    /// high-bandwidth mode: new blocks shall be announced by __cmpctblock__ right away
+    pub announce: bool,
+    pub version: u64,
+}
+
+/// Optional protocol features a node announces around __verack__
+///
+/// Used to configure the announcements of our node in the handshake topics, as well as to record the announcements of
+/// the remote node.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Announcements {
+    /// transactions shall be announced by wtxid (__wtxidrelay__, BIP339)
+    pub wtxid_relay: bool,
+    /// new blocks shall be announced by __headers__ instead of __inv__ (__sendheaders__, BIP130)
+    pub send_headers: bool,
+    /// compact block relay (__sendcmpct__, BIP152), one entry per supported version
+    pub compact_blocks: Vec<CompactBlocks>,
+    /// minimal fee rate in satoshis per 1000 vbytes of transactions to be announced (__feefilter__, BIP133)
+    pub fee_filter: Option<i64>,
+}
+
+impl Announcements {
+    /// whether `message` is one of the announcements
+    pub fn is_announcement(message: &ProtocolMessage) -> bool {
+        matches!(message, ProtocolMessage::WtxidRelay(_) | ProtocolMessage::SendHeaders(_)
+            | ProtocolMessage::SendCmpct(_) | ProtocolMessage::FeeFilter(_))
+    }
+
+    /// takes note of `message`, if it is one of the announcements
+    pub fn record(&mut self, message: &ProtocolMessage) {
+        match message {
+            ProtocolMessage::WtxidRelay(_) => self.wtxid_relay = true,
+            ProtocolMessage::SendHeaders(_) => self.send_headers = true,
+            // several versions may be announced, each one is supported; announcing a version again updates its mode
+            ProtocolMessage::SendCmpct(m) => {
+                self.compact_blocks.retain(|c| c.version != m.version);
+                self.compact_blocks.push(CompactBlocks { announce: m.announce, version: m.version });
+            }
+            ProtocolMessage::FeeFilter(m) => self.fee_filter = Some(m.fee_rate),
+            _ => {}
+        }
+    }
+
+    /// our announcements to be sent before __verack__, if the remote node supports them
+    pub(super) fn before_verack(&self, me: &NodeDesc, remote_version: i32) -> Vec<ProtocolMessage> {
+        let version = me.protocol_version.min(remote_version);
+        let mut messages = vec![];
+        if self.wtxid_relay && version >= WTXID_RELAY_VERSION {
+            messages.push(ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(me.chain)));
+        }
+        messages
+    }
+
+    /// our announcements to be sent once the handshake is complete, if the remote node supports them
+    pub(super) fn after_verack(&self, me: &NodeDesc, remote_version: i32) -> Vec<ProtocolMessage> {
+        let chain = me.chain;
+        let version = me.protocol_version.min(remote_version);
+        let mut messages = vec![];
+        if self.send_headers && version >= SENDHEADERS_VERSION {
+            messages.push(ProtocolMessage::SendHeaders(SendHeadersMessage::new(chain)));
+        }
+        if version >= SHORT_IDS_BLOCKS_VERSION {
+            for c in &self.compact_blocks {
+                messages.push(ProtocolMessage::SendCmpct(SendCmpctMessage::new(chain, c.announce, c.version)));
+            }
+        }
+        if let Some(fee_rate) = self.fee_filter.filter(|_| version >= FEEFILTER_VERSION) {
+            messages.push(ProtocolMessage::FeeFilter(FeeFilterMessage::new(chain, fee_rate)));
+        }
+        messages
+    }
+}
+
+/// Features announced on a connection by both sides
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Negotiation {
+    /// announced by our node
+    pub local: Announcements,
+    /// announced by the remote node
+    pub remote: Announcements,
+}
+
+impl Negotiation {
+    /// transactions are announced by wtxid in both directions, if both nodes sent __wtxidrelay__
+    pub fn wtxid_relay(&self) -> bool {
+        self.local.wtxid_relay && self.remote.wtxid_relay
+    }
+
+    /// compact block version usable in both directions, i.e. the highest one announced by both nodes
+    pub fn compact_blocks_version(&self) -> Option<u64> {
+        self.local.compact_blocks.iter()
+            .map(|local| local.version)
+            .filter(|&version| self.remote.compact_blocks.iter().any(|remote| remote.version == version))
+            .max()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
+
+    fn me(protocol_version: i32) -> NodeDesc {
+        NodeDesc { protocol_version, ..NodeDesc::test_node("/test/") }
+    }
+
+    fn all() -> Announcements {
+        Announcements {
+            wtxid_relay: true,
+            send_headers: true,
+            compact_blocks: vec![CompactBlocks { announce: false, version: 2 }],
+            fee_filter: Some(1000),
+        }
+    }
+
+    #[test]
+    fn test_announcements_depend_on_protocol_version() {
+        assert_eq!(all().before_verack(&me(70016), 70016).len(), 1);
+        assert_eq!(all().after_verack(&me(70016), 70016).len(), 3);
+
+        assert!(all().before_verack(&me(70016), 70015).is_empty());
+        assert!(matches!(all().after_verack(&me(70016), 70013)[..],
+            [ProtocolMessage::SendHeaders(_), ProtocolMessage::FeeFilter(_)]));
+        assert!(all().after_verack(&me(70011), 70016).is_empty());
+        assert!(Announcements::default().after_verack(&me(70016), 70016).is_empty());
+    }
+
+    #[test]
+    fn test_recorded_announcements() {
+        let mut negotiation = Negotiation::default();
+        for message in all().before_verack(&me(70016), 70016).into_iter().chain(all().after_verack(&me(70016), 70016)) {
+            negotiation.local.record(&message);
+        }
+        assert_eq!(negotiation.local, all());
+
+        let remote = &mut negotiation.remote;
+        remote.record(&ProtocolMessage::SendCmpct(SendCmpctMessage::new(Chain::Regtest, false, 2)));
+        remote.record(&ProtocolMessage::SendCmpct(SendCmpctMessage::new(Chain::Regtest, true, 1)));
+        remote.record(&ProtocolMessage::SendCmpct(SendCmpctMessage::new(Chain::Regtest, true, 2)));
+        assert_eq!(remote.compact_blocks, vec![
+            CompactBlocks { announce: true, version: 1 },
+            CompactBlocks { announce: true, version: 2 },
+        ]);
+        assert!(!negotiation.wtxid_relay());
+
+        negotiation.remote.record(&ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(Chain::Regtest)));
+        assert!(negotiation.wtxid_relay());
+        assert_eq!(negotiation.compact_blocks_version(), Some(2));
+    }
+
+
+    #[test]
+    fn test_compact_blocks_version_is_announced_by_both_nodes() {
+        let announced = |versions: &[u64]| Announcements {
+            compact_blocks: versions.iter().map(|&version| CompactBlocks { announce: false, version }).collect(),
+            ..Announcements::default()
+        };
+        let negotiation = |local: &[u64], remote: &[u64]| Negotiation { local: announced(local), remote: announced(remote) };
+
+        assert_eq!(negotiation(&[2], &[1]).compact_blocks_version(), None);
+        assert_eq!(negotiation(&[2, 1], &[1]).compact_blocks_version(), Some(1));
+        assert_eq!(negotiation(&[1, 2], &[2, 1]).compact_blocks_version(), Some(2));
+        assert_eq!(negotiation(&[], &[2]).compact_blocks_version(), None);
+    }
+}
@@ -1,8 +1,15 @@
-use std::ops::BitAnd;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use hex_literal::hex;
 
 use strum::{EnumIter, IntoEnumIterator};
 
 use crate::error::PeerError;
+use crate::wire_protocol::block::{BlockHeader, Target};
+use crate::wire_protocol::buffer::ByteBufferComposer;
+use crate::wire_protocol::raw_message::sha256;
 
 #[derive(Clone, Debug)]
 pub struct NodeDesc {
@@ -11,68 +18,342 @@ pub struct NodeDesc {
     pub services: NodeServiceSet,
     pub sub_ver: String,
     pub start_height: i32,
+    /// whether the node wants to be informed about transactions (the `relay` field of the __version__ message)
+    pub relay: bool,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, EnumIter)]
+#[cfg(test)]
+impl NodeDesc {
+    /// regtest node for tests; other fields can be set by struct update syntax, e.g. `NodeDesc { start_height: 10, ..node }`
+    pub(crate) fn test_node(sub_ver: &str) -> Self {
+        NodeDesc {
+            chain: Chain::Regtest,
+            protocol_version: 70016,
+            services: NodeServiceSet::default(),
+            sub_ver: sub_ver.to_string(),
+            start_height: 1,
+            relay: false,
+        }
+    }
+}
+
+/// The network a node belongs to. Every network has its own magic value, which starts each message on the wire.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Chain {
-    Regtest,
+    Mainnet,
     Testnet3,
+    Testnet4,
+    /// Signet network. Each signet is identified by its block challenge script, which determines the magic value.
+    /// See [Chain::signet] and [Chain::custom_signet].
+    Signet { magic: u32 },
+    Regtest,
+    /// user-defined network, see [Chain::register_custom]
+    Custom(&'static CustomChain),
+}
+
+/// Parameters of a user-defined network
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomChain {
+    pub name: String,
+    pub magic: u32,
+    pub default_port: u16,
+    pub genesis_header: BlockHeader,
+    pub dns_seeds: Vec<String>,
 }
 
+/// block challenge script of the default signet
+const DEFAULT_SIGNET_CHALLENGE: [u8; 71] = hex!("512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae");
+
+/// merkle root of the genesis block of mainnet, testnet3, signet and regtest, which all contain the same coinbase
+const GENESIS_MERKLE_ROOT: [u8; 32] = hex!("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+
+/// custom networks and custom signets, which are known to [Chain::try_from]
+static REGISTERED_CHAINS: Mutex<Vec<Chain>> = Mutex::new(Vec::new());
+
 impl Chain {
+    const WELL_KNOWN: [Chain; 5] = [
+        Chain::Mainnet,
+        Chain::Testnet3,
+        Chain::Testnet4,
+        Chain::Signet { magic: 0x40CF030A },
+        Chain::Regtest,
+    ];
+
+    /// the default (public) signet
+    pub fn signet() -> Self {
+        Chain::Signet { magic: Self::signet_magic(&DEFAULT_SIGNET_CHALLENGE) }
+    }
+
+    /// A signet with its own block challenge script. The chain gets registered, so that [Chain::try_from] knows it.
+    pub fn custom_signet(challenge: &[u8]) -> Self {
+        Self::register(Chain::Signet { magic: Self::signet_magic(challenge) })
+    }
+
+    /// Makes a user-defined network available. The chain gets registered, so that [Chain::try_from] knows it.
+    /// Registering the same parameters again returns the existing chain.
+    pub fn register_custom(params: CustomChain) -> Self {
+        let mut registered = REGISTERED_CHAINS.lock().unwrap();
+        if let Some(chain) = registered.iter().find(|chain| matches!(chain, Chain::Custom(existing) if **existing == params)) {
+            return *chain;
+        }
+        // leaked once per distinct network, so that chains stay `Copy`
+        let chain = Chain::Custom(Box::leak(Box::new(params)));
+        registered.push(chain);
+        chain
+    }
+
+    fn register(chain: Chain) -> Self {
+        let mut registered = REGISTERED_CHAINS.lock().unwrap();
+        if !registered.contains(&chain) {
+            registered.push(chain);
+        }
+        chain
+    }
+
+    /// The magic of a signet consists of the first 4 bytes of sha256(sha256(challenge)),
+    /// with the challenge serialized as script (i.e. with CompactSize length prefix)
+    fn signet_magic(challenge: &[u8]) -> u32 {
+        let mut composer = ByteBufferComposer::new();
+        composer.append_var_bytes(challenge);
+        let hash = sha256(&sha256(&composer.result()));
+        u32::from_le_bytes(hash[..4].try_into().unwrap())
+    }
+
     pub fn magic_value(&self) -> u32 {
         match self {
+            Chain::Mainnet => 0xD9B4BEF9,
+            Chain::Testnet3 => 0x0709110B,
+            Chain::Testnet4 => 0x283F161C,
+            Chain::Signet { magic } => *magic,
             Chain::Regtest => 0xDAB5BFFA,
-            Chain::Testnet3 => 0x0709110B
+            Chain::Custom(params) => params.magic,
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Chain::Mainnet => 8333,
+            Chain::Testnet3 => 18333,
+            Chain::Testnet4 => 48333,
+            Chain::Signet { .. } => 38333,
+            Chain::Regtest => 18444,
+            Chain::Custom(params) => params.default_port,
+        }
+    }
+
+    /// hash of the genesis block in internal byte order (as transmitted on the wire)
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        reversed(match self {
+            Chain::Mainnet => hex!("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"),
+            Chain::Testnet3 => hex!("000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943"),
+            Chain::Testnet4 => hex!("00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"),
+            // all signets share the same genesis block
+            Chain::Signet { .. } => hex!("00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"),
+            Chain::Regtest => hex!("0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206"),
+            Chain::Custom(params) => return params.genesis_header.block_hash(),
+        })
+    }
+
+    /// Highest target a block header may have (`powLimit` in bitcoin core). Custom networks use the target of their
+    /// genesis header.
+    pub fn pow_limit(&self) -> Target {
+        Target(match self {
+            Chain::Mainnet | Chain::Testnet3 | Chain::Testnet4 => {
+                hex!("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
+            }
+            Chain::Signet { .. } => hex!("00000377ae000000000000000000000000000000000000000000000000000000"),
+            Chain::Regtest => hex!("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+            Chain::Custom(params) => return params.genesis_header.target().unwrap_or(Target([0; 32])),
+        })
+    }
+
+    pub fn genesis_header(&self) -> BlockHeader {
+        let (merkle_root, time, bits, nonce) = match self {
+            Chain::Mainnet => (GENESIS_MERKLE_ROOT, 1231006505, 0x1d00ffff, 2083236893),
+            Chain::Testnet3 => (GENESIS_MERKLE_ROOT, 1296688602, 0x1d00ffff, 414098458),
+            Chain::Testnet4 => (hex!("7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e"), 1714777860, 0x1d00ffff, 393743547),
+            Chain::Signet { .. } => (GENESIS_MERKLE_ROOT, 1598918400, 0x1e0377ae, 52613770),
+            Chain::Regtest => (GENESIS_MERKLE_ROOT, 1296688602, 0x207fffff, 2),
+            Chain::Custom(params) => return params.genesis_header,
+        };
+        BlockHeader {
+            version: 1,
+            prev_block: [0; 32],
+            merkle_root: reversed(merkle_root),
+            time,
+            bits,
+            nonce,
+        }
+    }
+
+    pub fn dns_seeds(&self) -> Vec<&'static str> {
+        match self {
+            Chain::Mainnet => vec![
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+            ],
+            Chain::Testnet3 => vec![
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+            ],
+            Chain::Testnet4 => vec![
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            Chain::Signet { .. } if *self == Chain::signet() => vec![
+                "seed.signet.bitcoin.sprovoost.nl",
+            ],
+            Chain::Signet { .. } | Chain::Regtest => vec![],
+            Chain::Custom(params) => params.dns_seeds.iter().map(|s| s.as_str()).collect(),
         }
     }
 }
 
+/// hashes are displayed in reversed byte order
+fn reversed(mut hash: [u8; 32]) -> [u8; 32] {
+    hash.reverse();
+    hash
+}
+
 impl TryFrom<u32> for Chain {
     type Error = PeerError;
 
     fn try_from(magic_value: u32) -> Result<Self, Self::Error> {
-        for c in Self::iter() {
+        let registered = REGISTERED_CHAINS.lock().unwrap();
+        for c in Self::WELL_KNOWN.iter().chain(registered.iter()) {
             if c.magic_value() == magic_value {
-                return Ok(c);
+                return Ok(*c);
             }
         }
-        Err(PeerError::from(format!("No chain known having magic value {}", magic_value)))
+        Err(PeerError::UnknownNetwork { magic: magic_value })
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct NodeServiceSet(pub Vec<NodeService>);
+/// Well-known networks by name, as used by bitcoin core's `-chain` option (`main`, `test`, `testnet4`, `signet`, `regtest`)
+impl FromStr for Chain {
+    type Err = PeerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "main" | "mainnet" => Ok(Chain::Mainnet),
+            "test" | "testnet" | "testnet3" => Ok(Chain::Testnet3),
+            "testnet4" => Ok(Chain::Testnet4),
+            "signet" => Ok(Chain::signet()),
+            "regtest" => Ok(Chain::Regtest),
+            _ => Err(PeerError::UnknownChain(s.to_string())),
+        }
+    }
+}
+
+/// Service flags of a node, as announced in the `services` field of __version__ messages and network addresses.
+///
+/// Bits without a defined [NodeService] (e.g. experimental ones) are preserved, so that a set survives a round trip
+/// through [Self::from_bitmask] and [Self::as_bitmask] unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct NodeServiceSet(u64);
 
 impl NodeServiceSet {
+    pub fn new(services: &[NodeService]) -> Self {
+        NodeServiceSet(services.iter().fold(0, |mask, s| mask | s.as_u64()))
+    }
+
     pub fn as_bitmask(&self) -> u64 {
-        let mut bitset = 0x0_u64;
-        for bit in self.0.iter() {
-            bitset = bitset.bitand(bit.as_u64());
-        }
-        bitset
+        self.0
     }
 
     pub fn from_bitmask(mask: u64) -> Self {
-        let mut services = vec![];
+        NodeServiceSet(mask)
+    }
 
-        for e in NodeService::iter() {
-            if mask.bitand(e.as_u64()) != 0 {
-                services.push(e);
-            }
-        }
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, service: NodeService) -> bool {
+        self.0 & service.as_u64() != 0
+    }
+
+    /// whether all services of `other` are contained in this set
+    pub fn contains_all(&self, other: &NodeServiceSet) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, service: NodeService) {
+        self.0 |= service.as_u64();
+    }
+
+    pub fn union(&self, other: &NodeServiceSet) -> NodeServiceSet {
+        NodeServiceSet(self.0 | other.0)
+    }
+
+    pub fn difference(&self, other: &NodeServiceSet) -> NodeServiceSet {
+        NodeServiceSet(self.0 & !other.0)
+    }
 
-        NodeServiceSet(services)
+    /// the known services contained in this set
+    pub fn services(&self) -> Vec<NodeService> {
+        NodeService::iter().filter(|s| self.contains(*s)).collect()
+    }
+
+    /// bits of this set, which do not belong to a known [NodeService]
+    pub fn unknown_bits(&self) -> u64 {
+        NodeService::iter().fold(self.0, |mask, s| mask & !s.as_u64())
     }
 }
 
+impl From<NodeService> for NodeServiceSet {
+    fn from(service: NodeService) -> Self {
+        NodeServiceSet(service.as_u64())
+    }
+}
+
+/// e.g. `NETWORK | WITNESS | UNKNOWN[1<<24]`, or `NONE` for an empty set
+impl Display for NodeServiceSet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "NONE");
+        }
+        let mut names: Vec<String> = self.services().iter().map(|s| s.to_string()).collect();
+        let unknown = self.unknown_bits();
+        for bit in 0..64 {
+            if unknown & (1 << bit) != 0 {
+                names.push(format!("UNKNOWN[1<<{}]", bit));
+            }
+        }
+        write!(f, "{}", names.join(" | "))
+    }
+}
 
+/// Service bits as defined in bitcoin core's protocol.h
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(u64)]
 #[derive(EnumIter)]
 pub enum NodeService {
-    NodeNetwork = 0x1, // bit mask value
-    // ...
+    /// can serve the full block chain
+    NodeNetwork = 1 << 0,
+    /// can respond to `getutxo` requests (BIP64)
+    NodeGetUtxo = 1 << 1,
+    /// supports bloom filtered connections (BIP111)
+    NodeBloom = 1 << 2,
+    /// can serve blocks and transactions including witness data (BIP144)
+    NodeWitness = 1 << 3,
+    /// supports Xtreme Thinblocks (never supported by bitcoin core)
+    NodeXthin = 1 << 4,
+    /// can serve basic compact block filters (BIP157)
+    NodeCompactFilters = 1 << 6,
+    /// can serve the last 288 blocks only (BIP159)
+    NodeNetworkLimited = 1 << 10,
+    /// supports the v2 encrypted transport (BIP324)
+    NodeP2pV2 = 1 << 11,
 }
 
 impl NodeService {
@@ -81,3 +362,120 @@ impl NodeService {
     }
 }
 
+impl Display for NodeService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NodeService::NodeNetwork => "NETWORK",
+            NodeService::NodeGetUtxo => "GETUTXO",
+            NodeService::NodeBloom => "BLOOM",
+            NodeService::NodeWitness => "WITNESS",
+            NodeService::NodeXthin => "XTHIN",
+            NodeService::NodeCompactFilters => "COMPACT_FILTERS",
+            NodeService::NodeNetworkLimited => "NETWORK_LIMITED",
+            NodeService::NodeP2pV2 => "P2P_V2",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_well_known_chains_by_magic() {
+        for chain in Chain::WELL_KNOWN {
+            assert_eq!(Chain::try_from(chain.magic_value()).unwrap(), chain);
+        }
+        assert!(Chain::try_from(0x12345678).is_err());
+    }
+
+    #[test]
+    fn test_signet_magic_is_derived_from_challenge() {
+        assert_eq!(Chain::signet().magic_value().to_le_bytes(), hex!("0a03cf40"));
+        assert_eq!(Chain::signet(), "signet".parse().unwrap());
+    }
+
+    #[test]
+    fn test_unknown_chain_name_is_rejected() {
+        assert_eq!("testnet".parse::<Chain>().unwrap(), Chain::Testnet3);
+        assert!(matches!("mainnet3".parse::<Chain>(), Err(PeerError::UnknownChain(name)) if name == "mainnet3"));
+    }
+
+    #[test]
+    fn test_custom_signet_is_registered() {
+        let challenge = hex!("51");
+        let chain = Chain::custom_signet(&challenge);
+        assert_ne!(chain, Chain::signet());
+        assert_eq!(Chain::try_from(chain.magic_value()).unwrap(), chain);
+        assert_eq!(chain.genesis_hash(), Chain::signet().genesis_hash());
+        assert!(chain.dns_seeds().is_empty());
+    }
+
+    #[test]
+    fn test_custom_chain_is_registered() {
+        let chain = Chain::register_custom(CustomChain {
+            name: "mynet".to_string(),
+            magic: 0xAABBCCDD,
+            default_port: 9333,
+            genesis_header: BlockHeader { nonce: 7, ..Chain::Regtest.genesis_header() },
+            dns_seeds: vec!["seed.mynet.example".to_string()],
+        });
+        assert_eq!(Chain::try_from(0xAABBCCDD).unwrap(), chain);
+        assert_eq!(chain.default_port(), 9333);
+        assert_eq!(chain.genesis_header().nonce, 7);
+        assert_eq!(chain.genesis_hash(), chain.genesis_header().block_hash());
+        assert_eq!(chain.dns_seeds(), vec!["seed.mynet.example"]);
+    }
+
+    #[test]
+    fn test_custom_chain_is_registered_once() {
+        let params = CustomChain {
+            name: "othernet".to_string(),
+            magic: 0xAABBCCEE,
+            default_port: 9334,
+            genesis_header: Chain::Regtest.genesis_header(),
+            dns_seeds: vec![],
+        };
+        let Chain::Custom(first) = Chain::register_custom(params.clone()) else { panic!("expected a custom chain") };
+        let Chain::Custom(second) = Chain::register_custom(params) else { panic!("expected a custom chain") };
+        assert!(std::ptr::eq(first, second));
+    }
+
+    #[test]
+    fn test_genesis_hash_byte_order() {
+        assert_eq!(&Chain::Mainnet.genesis_hash()[..4], &hex!("6fe28c0a")[..]);
+        assert_eq!(&Chain::Mainnet.genesis_hash()[26..], &hex!("190000000000")[..]);
+    }
+
+    #[test]
+    fn test_service_bitmask_round_trip() {
+        let services = NodeServiceSet::new(&[NodeService::NodeNetwork, NodeService::NodeWitness, NodeService::NodeNetworkLimited]);
+        assert_eq!(services.as_bitmask(), 0x409);
+        assert_eq!(NodeServiceSet::from_bitmask(0x409), services);
+        assert_eq!(services.services(), vec![NodeService::NodeNetwork, NodeService::NodeWitness, NodeService::NodeNetworkLimited]);
+    }
+
+    #[test]
+    fn test_unknown_service_bits_are_preserved() {
+        let mask = (1 << 24) | (1 << 3) | 1;
+        let services = NodeServiceSet::from_bitmask(mask);
+        assert_eq!(services.as_bitmask(), mask);
+        assert_eq!(services.unknown_bits(), 1 << 24);
+        assert_eq!(services.to_string(), "NETWORK | WITNESS | UNKNOWN[1<<24]");
+    }
+
+    #[test]
+    fn test_service_set_operations() {
+        let full = NodeServiceSet::new(&[NodeService::NodeNetwork, NodeService::NodeWitness, NodeService::NodeCompactFilters]);
+        let wanted = NodeServiceSet::new(&[NodeService::NodeWitness, NodeService::NodeCompactFilters]);
+
+        assert!(full.contains(NodeService::NodeCompactFilters));
+        assert!(!full.contains(NodeService::NodeBloom));
+        assert!(full.contains_all(&wanted));
+        assert!(!wanted.contains_all(&full));
+        assert_eq!(full.difference(&wanted), NodeServiceSet::from(NodeService::NodeNetwork));
+        assert_eq!(wanted.union(&NodeService::NodeNetwork.into()), full);
+        assert_eq!(NodeServiceSet::default().to_string(), "NONE");
+    }
+}
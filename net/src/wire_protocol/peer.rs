@@ -0,0 +1,371 @@
+use std::time::{Duration, Instant};
+
+use crate::conversation::{ConversationAction, ConversationTopicHandler};
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::buffer::IOBuffer;
+use crate::wire_protocol::messages::ProtocolMessage;
+use crate::wire_protocol::misbehavior::Misbehavior;
+use crate::wire_protocol::negotiation::{Announcements, Negotiation};
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::raw_message::{MAX_PROTOCOL_MESSAGE_LENGTH, MessageParseOutcome, RawMessage};
+
+/// How to deal with received bytes, which do not form a valid message (unknown magic, checksum mismatch)
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum GarbagePolicy {
+    /// drop bytes up to the next occurrence of the network's magic value and continue from there
+    #[default]
+    Resync,
+    /// give up the connection
+    Disconnect,
+}
+
+/// Outcome of processing received bytes, see [Peer::poll_event]
+#[derive(Debug)]
+pub enum PeerEvent {
+    Message(ProtocolMessage),
+    /// misbehavior of the remote node, which does not make the connection fail
+    Misbehavior(Misbehavior),
+}
+
+/// Sans-IO state of an unencrypted (v1) connection: bytes received from the remote node go in, decoded messages and the
+/// bytes to send come out. How the bytes get transferred (a blocking socket, an event loop, an async runtime or a test
+/// shuffling bytes between two peers) is up to the caller.
+///
+/// Conversation topics are run with [Peer::converse].
+pub struct Peer {
+    chain: Chain,
+    /// survives across topics, so that no partially received message gets lost
+    receive_buffer: IOBuffer,
+    /// encoded messages, which are yet to be sent
+    outgoing: Vec<u8>,
+    max_message_size: usize,
+    garbage_policy: GarbagePolicy,
+    /// number of bytes dropped to resynchronize the stream
+    dropped_bytes: u64,
+    negotiation: Negotiation,
+    /// received after the announcements following the last topic, to be passed on next
+    pending: Option<Box<PeerResult<PeerEvent>>>,
+}
+
+impl Peer {
+    pub fn new(chain: Chain) -> Self {
+        Peer {
+            chain,
+            receive_buffer: IOBuffer::default(),
+            outgoing: vec![],
+            max_message_size: MAX_PROTOCOL_MESSAGE_LENGTH,
+            garbage_policy: GarbagePolicy::default(),
+            dropped_bytes: 0,
+            negotiation: Negotiation::default(),
+            pending: None,
+        }
+    }
+
+    pub fn chain(&self) -> Chain {
+        self.chain
+    }
+
+    /// Maximum payload size of a received message. Receiving fails, when a remote node announces a larger one.
+    /// Defaults to [MAX_PROTOCOL_MESSAGE_LENGTH].
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    pub fn set_garbage_policy(&mut self, garbage_policy: GarbagePolicy) {
+        self.garbage_policy = garbage_policy;
+    }
+
+    /// number of received bytes, which were dropped to resynchronize the stream (see [GarbagePolicy::Resync])
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes
+    }
+
+    /// Features announced by both nodes, as far as their messages were processed by [Self::converse] so far. This
+    /// includes announcements, which the remote node sent right after the message finishing a topic (e.g.
+    /// __sendheaders__ following __verack__), as long as they were received along with it.
+    ///
+    /// Messages passed by [Self::send] and [Self::poll_event] directly are not recorded. This is how a `V1Transport`
+    /// uses its peer, so its negotiation stays empty; the negotiation of such a connection is kept by the
+    /// `NodeConnection` running the conversation topics.
+    pub fn negotiation(&self) -> &Negotiation {
+        &self.negotiation
+    }
+
+    /// queues `message` for sending, see [Self::take_outgoing]
+    pub fn send(&mut self, message: ProtocolMessage) {
+        log::debug!("sending {:?}", message);
+        self.outgoing.extend(message.to_bytes());
+    }
+
+    /// the bytes to be written to the remote node, in order
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    /// Takes bytes received from the remote node. They may be chunked arbitrarily.
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.receive_buffer.append(bytes);
+    }
+
+    /// Next event from the bytes received so far, `None` if more bytes are needed. Messages of unknown commands are
+    /// skipped. After an error, the stream is in an unknown state and the connection should be given up.
+    pub fn poll_event(&mut self) -> PeerResult<Option<PeerEvent>> {
+        if let Some(event) = self.pending.take() {
+            return (*event).map(Some);
+        }
+        loop {
+            log::trace!("trying to consume message, buffer pos is {}", self.receive_buffer.content().len());
+            match RawMessage::try_consume_message(&mut self.receive_buffer, self.chain, self.max_message_size) {
+                Ok(MessageParseOutcome::Message(raw_message)) => {
+                    return raw_message.into_protocol_message().map(|message| Some(PeerEvent::Message(message)));
+                }
+                Ok(MessageParseOutcome::SkippedMessage) => {}
+                // consistent state but no complete message available
+                Ok(MessageParseOutcome::NoMessage) => return Ok(None),
+                Err(err @ (PeerError::WrongNetwork { .. } | PeerError::Checksum { .. })) if self.garbage_policy == GarbagePolicy::Resync => {
+                    let dropped_bytes = RawMessage::skip_to_next_magic(&mut self.receive_buffer, self.chain);
+                    self.dropped_bytes += dropped_bytes as u64;
+                    return Ok(Some(PeerEvent::Misbehavior(Misbehavior::Garbage { dropped_bytes, reason: err.to_string() })));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Lets a transport read directly into the receive buffer, instead of going through [Self::receive]
+    #[cfg(feature = "tokio")]
+    pub(super) fn receive_buffer(&mut self) -> &mut IOBuffer {
+        &mut self.receive_buffer
+    }
+
+    /// Starts the conversation topic `handler`. Its initial messages are queued and messages received beforehand,
+    /// but not consumed by a previous topic, are passed to it.
+    pub fn converse<H: ConversationTopicHandler>(&mut self, handler: H) -> PeerResult<Conversation<'_, H>> {
+        let mut conversation = Conversation { peer: self, handler, finished: false, misbehavior: vec![] };
+        let initial_action = conversation.handler.initial_action();
+        conversation.perform(initial_action);
+        conversation.process()?;
+        Ok(conversation)
+    }
+}
+
+/// Conversation topic running on a [Peer]. The caller feeds received bytes and ticks in, writes out the outgoing bytes
+/// and takes the outcome, once the topic is finished.
+pub struct Conversation<'p, H> {
+    peer: &'p mut Peer,
+    handler: H,
+    finished: bool,
+    misbehavior: Vec<Misbehavior>,
+}
+
+impl<H: ConversationTopicHandler> Conversation<'_, H> {
+    /// Takes bytes received from the remote node and passes the completed messages to the topic. Bytes following
+    /// the message, which finished the topic, are kept for the next topic, except for announcements right after it,
+    /// which are recorded (see [Peer::negotiation]).
+    pub fn receive(&mut self, bytes: &[u8]) -> PeerResult<()> {
+        self.peer.receive(bytes);
+        self.process()
+    }
+
+    /// interval, in which [Self::tick] shall be called (see [ConversationTopicHandler::tick_interval])
+    pub fn tick_interval(&self) -> Option<Duration> {
+        self.handler.tick_interval()
+    }
+
+    pub fn tick(&mut self, now: Instant) -> PeerResult<()> {
+        if !self.finished {
+            let action = self.handler.on_tick(now)?;
+            self.perform(action);
+        }
+        Ok(())
+    }
+
+    /// the bytes to be written to the remote node, in order
+    pub fn take_outgoing(&mut self) -> Vec<u8> {
+        self.peer.take_outgoing()
+    }
+
+    /// misbehavior of the remote node noticed since the last call, which did not make the conversation fail
+    pub fn take_misbehavior(&mut self) -> Vec<Misbehavior> {
+        std::mem::take(&mut self.misbehavior)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// The result of the topic, once it's finished. Write out [Self::take_outgoing] beforehand, the final messages of the
+    /// topic might be pending.
+    pub fn outcome(self) -> PeerResult<H::Outcome> {
+        self.handler.outcome()
+    }
+
+    fn process(&mut self) -> PeerResult<()> {
+        while !self.finished {
+            match self.peer.poll_event()? {
+                Some(PeerEvent::Message(message)) => {
+                    log::debug!("received {:?}", message);
+                    self.peer.negotiation.remote.record(&message);
+                    let action = self.handler.on_message(message)?;
+                    self.perform(action);
+                }
+                Some(PeerEvent::Misbehavior(misbehavior)) => self.misbehavior.push(misbehavior),
+                None => break,
+            }
+        }
+        if self.finished {
+            self.record_announcements();
+        }
+        Ok(())
+    }
+
+    /// Records the announcements, which the remote node sent following the message, that finished the topic, as far
+    /// as they are received already. The first other message (or error) is kept for the next topic.
+    fn record_announcements(&mut self) {
+        while self.peer.pending.is_none() {
+            match self.peer.poll_event() {
+                Ok(Some(PeerEvent::Message(message))) if Announcements::is_announcement(&message) => {
+                    log::debug!("received {:?}", message);
+                    self.peer.negotiation.remote.record(&message);
+                }
+                Ok(Some(PeerEvent::Misbehavior(misbehavior))) => self.misbehavior.push(misbehavior),
+                Ok(Some(event)) => self.peer.pending = Some(Box::new(Ok(event))),
+                Ok(None) => break,
+                Err(err) => self.peer.pending = Some(Box::new(Err(err))),
+            }
+        }
+    }
+
+    fn perform(&mut self, action: ConversationAction) {
+        for message in action.messages {
+            self.peer.negotiation.local.record(&message);
+            self.peer.send(message);
+        }
+        self.finished |= action.topic_finished;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::wire_protocol::handshake::{HandshakeInitConversationTopic, HandshakeRespondConversationTopic, LocalNonces};
+    use crate::wire_protocol::keepalive::{KeepaliveConfig, KeepaliveConversationTopic, PeerLatency};
+    use crate::wire_protocol::messages::{PongMessage, SendHeadersMessage, VerackMessage};
+    use crate::wire_protocol::node::NodeDesc;
+
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:18444".parse().unwrap()
+    }
+
+    /// drives `conversation` over a blocking socket
+    fn converse_blocking<H: ConversationTopicHandler>(stream: &mut TcpStream, mut conversation: Conversation<H>) -> PeerResult<H::Outcome> {
+        let mut chunk = [0; 4096];
+        loop {
+            stream.write_all(&conversation.take_outgoing())?;
+            if conversation.is_finished() {
+                return conversation.outcome();
+            }
+            match stream.read(&mut chunk)? {
+                0 => return Err(PeerError::Disconnected),
+                n => conversation.receive(&chunk[..n])?,
+            }
+        }
+    }
+
+    #[test]
+    fn test_handshake_in_memory_byte_by_byte() {
+        let mut initiator = Peer::new(Chain::Regtest);
+        let mut responder = Peer::new(Chain::Regtest);
+        let mut initiating = initiator.converse(HandshakeInitConversationTopic::new(&NodeDesc::test_node("/initiator/"), addr(), &LocalNonces::default())).unwrap();
+        let mut responding = responder.converse(HandshakeRespondConversationTopic::new(&NodeDesc::test_node("/responder/"), addr(), &LocalNonces::default())).unwrap();
+
+        while !(initiating.is_finished() && responding.is_finished()) {
+            let to_responder = initiating.take_outgoing();
+            let to_initiator = responding.take_outgoing();
+            assert!(!to_responder.is_empty() || !to_initiator.is_empty(), "conversation stalled");
+            for byte in to_responder {
+                responding.receive(&[byte]).unwrap();
+            }
+            for byte in to_initiator {
+                initiating.receive(&[byte]).unwrap();
+            }
+        }
+        assert_eq!(initiating.outcome().unwrap().sub_ver, "/responder/");
+        assert_eq!(responding.outcome().unwrap().sub_ver, "/initiator/");
+    }
+
+    #[test]
+    fn test_handshake_over_blocking_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (mut stream, remote_addr) = listener.accept().unwrap();
+            let mut peer = Peer::new(Chain::Regtest);
+            let topic = HandshakeRespondConversationTopic::new(&NodeDesc::test_node("/responder/"), remote_addr, &LocalNonces::default());
+            converse_blocking(&mut stream, peer.converse(topic).unwrap())
+        });
+
+        let mut stream = TcpStream::connect(listen_addr).unwrap();
+        let mut peer = Peer::new(Chain::Regtest);
+        let topic = HandshakeInitConversationTopic::new(&NodeDesc::test_node("/initiator/"), listen_addr, &LocalNonces::default());
+        let seen_by_initiator = converse_blocking(&mut stream, peer.converse(topic).unwrap()).unwrap();
+
+        assert_eq!(seen_by_initiator.sub_ver, "/responder/");
+        assert_eq!(responder.join().unwrap().unwrap().sub_ver, "/initiator/");
+    }
+
+    /// next ping received by `peer`
+    fn received_ping(peer: &mut Peer) -> u64 {
+        match peer.poll_event().unwrap() {
+            Some(PeerEvent::Message(ProtocolMessage::Ping(ping))) => ping.nonce,
+            event => panic!("expected a ping, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_ticks_and_plain_messages() {
+        let mut local = Peer::new(Chain::Regtest);
+        let mut remote = Peer::new(Chain::Regtest);
+        let config = KeepaliveConfig { ping_interval: Duration::from_secs(1), max_missed_pongs: 3, rounds: Some(1) };
+        let mut keepalive = local.converse(KeepaliveConversationTopic::new(Chain::Regtest, config, &PeerLatency::default())).unwrap();
+        assert_eq!(keepalive.tick_interval(), Some(Duration::from_secs(1)));
+
+        // the first ping stays unanswered, the tick sends another one
+        remote.receive(&keepalive.take_outgoing());
+        received_ping(&mut remote);
+        keepalive.tick(Instant::now()).unwrap();
+        remote.receive(&keepalive.take_outgoing());
+        let nonce = received_ping(&mut remote);
+        assert!(remote.poll_event().unwrap().is_none());
+
+        // the pong is followed by an announcement, which is recorded, and a verack, which is left for the next topic
+        remote.send(ProtocolMessage::Pong(PongMessage::new(Chain::Regtest, nonce)));
+        remote.send(ProtocolMessage::SendHeaders(SendHeadersMessage::new(Chain::Regtest)));
+        remote.send(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest)));
+        keepalive.receive(&remote.take_outgoing()).unwrap();
+        assert!(keepalive.is_finished());
+        assert!(keepalive.outcome().unwrap().last.is_some());
+        assert!(local.negotiation().remote.send_headers);
+        assert!(matches!(local.poll_event().unwrap(), Some(PeerEvent::Message(ProtocolMessage::Verack(_)))));
+    }
+
+    #[test]
+    fn test_garbage_is_an_event() {
+        let mut peer = Peer::new(Chain::Regtest);
+        peer.receive(b"garbage");
+        peer.receive(&ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest)).to_bytes());
+        assert!(matches!(peer.poll_event().unwrap(), Some(PeerEvent::Misbehavior(Misbehavior::Garbage { dropped_bytes: 7, .. }))));
+        assert!(matches!(peer.poll_event().unwrap(), Some(PeerEvent::Message(ProtocolMessage::Verack(_)))));
+        assert!(peer.poll_event().unwrap().is_none());
+        assert_eq!(peer.dropped_bytes(), 7);
+
+        peer.set_garbage_policy(GarbagePolicy::Disconnect);
+        peer.receive(b"garbage and more garbage");
+        assert!(matches!(peer.poll_event(), Err(PeerError::WrongNetwork { .. })));
+    }
+}
@@ -5,8 +5,19 @@ use sha2::digest::FixedOutput;
 use strum::{EnumIter, IntoEnumIterator};
 
 use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::block::MAX_BLOCK_SERIALIZED_SIZE;
 use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser, IOBuffer};
-use crate::wire_protocol::messages::{PingMessage, PongMessage, ProtocolMessage, VerackMessage, VersionMessage};
+use crate::wire_protocol::messages::{AddrMessage, AddrV2Message, BlockMessage, BlockTxnMessage, CFCheckptMessage,
+                                      CFHeadersMessage, CFilterMessage, CmpctBlockMessage, FeeFilterMessage, FilterAddMessage,
+                                      FilterClearMessage, FilterLoadMessage, GetAddrMessage, GetBlockTxnMessage,
+                                      GetCFCheckptMessage, GetCFHeadersMessage, GetCFiltersMessage, GetDataMessage,
+                                      GetHeadersMessage, HeadersMessage, InvMessage, MAX_ADDR_PAYLOAD_LENGTH,
+                                      MAX_ADDRV2_PAYLOAD_LENGTH, MAX_CFHEADERS_PAYLOAD_LENGTH, MAX_FILTERADD_PAYLOAD_LENGTH,
+                                      MAX_FILTERLOAD_PAYLOAD_LENGTH, MAX_GETHEADERS_PAYLOAD_LENGTH, MAX_HEADERS_PAYLOAD_LENGTH,
+                                      MAX_INV_PAYLOAD_LENGTH, MAX_VERSION_PAYLOAD_LENGTH, MempoolMessage, MerkleBlockMessage,
+                                      NotFoundMessage, PingMessage, PongMessage, ProtocolMessage, SendAddrV2Message,
+                                      SendCmpctMessage, SendHeadersMessage, TxMessage, VerackMessage, VersionMessage,
+                                      WtxidRelayMessage};
 use crate::wire_protocol::node::Chain;
 
 #[derive(Debug, EnumIter)]
@@ -15,16 +26,138 @@ pub enum Command {
     Verack,
     Ping,
     Pong,
+    GetAddr,
+    Addr,
+    AddrV2,
+    SendAddrV2,
+    Inv,
+    GetData,
+    NotFound,
+    GetHeaders,
+    Headers,
+    Block,
+    Tx,
+    Mempool,
+    SendHeaders,
+    SendCmpct,
+    FeeFilter,
+    WtxidRelay,
+    CmpctBlock,
+    GetBlockTxn,
+    BlockTxn,
+    GetCFilters,
+    CFilter,
+    GetCFHeaders,
+    CFHeaders,
+    GetCFCheckpt,
+    CFCheckpt,
+    FilterLoad,
+    FilterAdd,
+    FilterClear,
+    MerkleBlock,
 }
 
 impl Command {
     // ASCII string identifying the packet content, NULL padded (non-NULL padding results in packet rejected)
-    fn as_bytes(&self) -> &[u8; 12] {
+    pub(super) fn as_bytes(&self) -> &[u8; 12] {
         match self {
             Command::Version => b"version\0\0\0\0\0",
             Command::Verack => b"verack\0\0\0\0\0\0",
             Command::Ping => b"ping\0\0\0\0\0\0\0\0",
             Command::Pong => b"pong\0\0\0\0\0\0\0\0",
+            Command::GetAddr => b"getaddr\0\0\0\0\0",
+            Command::Addr => b"addr\0\0\0\0\0\0\0\0",
+            Command::AddrV2 => b"addrv2\0\0\0\0\0\0",
+            Command::SendAddrV2 => b"sendaddrv2\0\0",
+            Command::Inv => b"inv\0\0\0\0\0\0\0\0\0",
+            Command::GetData => b"getdata\0\0\0\0\0",
+            Command::NotFound => b"notfound\0\0\0\0",
+            Command::GetHeaders => b"getheaders\0\0",
+            Command::Headers => b"headers\0\0\0\0\0",
+            Command::Block => b"block\0\0\0\0\0\0\0",
+            Command::Tx => b"tx\0\0\0\0\0\0\0\0\0\0",
+            Command::Mempool => b"mempool\0\0\0\0\0",
+            Command::SendHeaders => b"sendheaders\0",
+            Command::SendCmpct => b"sendcmpct\0\0\0",
+            Command::FeeFilter => b"feefilter\0\0\0",
+            Command::WtxidRelay => b"wtxidrelay\0\0",
+            Command::CmpctBlock => b"cmpctblock\0\0",
+            Command::GetBlockTxn => b"getblocktxn\0",
+            Command::BlockTxn => b"blocktxn\0\0\0\0",
+            Command::GetCFilters => b"getcfilters\0",
+            Command::CFilter => b"cfilter\0\0\0\0\0",
+            Command::GetCFHeaders => b"getcfheaders",
+            Command::CFHeaders => b"cfheaders\0\0\0",
+            Command::GetCFCheckpt => b"getcfcheckpt",
+            Command::CFCheckpt => b"cfcheckpt\0\0\0",
+            Command::FilterLoad => b"filterload\0\0",
+            Command::FilterAdd => b"filteradd\0\0\0",
+            Command::FilterClear => b"filterclear\0",
+            Command::MerkleBlock => b"merkleblock\0",
+        }
+    }
+
+    /// Upper bound of the payload length of this command.
+    /// Messages announcing a larger payload are rejected right after their header was received.
+    pub fn max_payload_len(&self) -> usize {
+        match self {
+            Command::Version => MAX_VERSION_PAYLOAD_LENGTH,
+            Command::Verack | Command::GetAddr | Command::SendAddrV2 | Command::Mempool | Command::SendHeaders
+            | Command::WtxidRelay | Command::FilterClear => 0,
+            Command::Ping | Command::Pong | Command::FeeFilter => 8,
+            Command::SendCmpct => 9,
+            Command::Addr => MAX_ADDR_PAYLOAD_LENGTH,
+            Command::AddrV2 => MAX_ADDRV2_PAYLOAD_LENGTH,
+            Command::Inv | Command::GetData | Command::NotFound => MAX_INV_PAYLOAD_LENGTH,
+            Command::GetHeaders => MAX_GETHEADERS_PAYLOAD_LENGTH,
+            Command::Headers => MAX_HEADERS_PAYLOAD_LENGTH,
+            Command::Block | Command::Tx => MAX_BLOCK_SERIALIZED_SIZE,
+            Command::CmpctBlock | Command::GetBlockTxn | Command::BlockTxn => MAX_PROTOCOL_MESSAGE_LENGTH,
+            Command::GetCFilters | Command::GetCFHeaders => 1 + 4 + 32,
+            Command::GetCFCheckpt => 1 + 32,
+            Command::CFHeaders => MAX_CFHEADERS_PAYLOAD_LENGTH,
+            Command::CFilter | Command::CFCheckpt => MAX_PROTOCOL_MESSAGE_LENGTH,
+            Command::FilterLoad => MAX_FILTERLOAD_PAYLOAD_LENGTH,
+            Command::FilterAdd => MAX_FILTERADD_PAYLOAD_LENGTH,
+            Command::MerkleBlock => MAX_PROTOCOL_MESSAGE_LENGTH,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Version => "version",
+            Command::Verack => "verack",
+            Command::Ping => "ping",
+            Command::Pong => "pong",
+            Command::GetAddr => "getaddr",
+            Command::Addr => "addr",
+            Command::AddrV2 => "addrv2",
+            Command::SendAddrV2 => "sendaddrv2",
+            Command::Inv => "inv",
+            Command::GetData => "getdata",
+            Command::NotFound => "notfound",
+            Command::GetHeaders => "getheaders",
+            Command::Headers => "headers",
+            Command::Block => "block",
+            Command::Tx => "tx",
+            Command::Mempool => "mempool",
+            Command::SendHeaders => "sendheaders",
+            Command::SendCmpct => "sendcmpct",
+            Command::FeeFilter => "feefilter",
+            Command::WtxidRelay => "wtxidrelay",
+            Command::CmpctBlock => "cmpctblock",
+            Command::GetBlockTxn => "getblocktxn",
+            Command::BlockTxn => "blocktxn",
+            Command::GetCFilters => "getcfilters",
+            Command::CFilter => "cfilter",
+            Command::GetCFHeaders => "getcfheaders",
+            Command::CFHeaders => "cfheaders",
+            Command::GetCFCheckpt => "getcfcheckpt",
+            Command::CFCheckpt => "cfcheckpt",
+            Command::FilterLoad => "filterload",
+            Command::FilterAdd => "filteradd",
+            Command::FilterClear => "filterclear",
+            Command::MerkleBlock => "merkleblock",
         }
     }
 }
@@ -33,26 +166,27 @@ impl TryFrom<&[u8]> for Command {
     type Error = PeerError;
 
     fn try_from(value: &[u8]) -> PeerResult<Self> {
-        fn format_byte_array_as_string(bytes: &[u8]) -> String {
-            let mut result = String::new();
-            for &c in bytes {
-                result.push_str(std::str::from_utf8(&ascii::escape_default(c).collect::<Vec<u8>>()).unwrap())
-            }
-            result
-        }
-
         for command in Command::iter() {
             if command.as_bytes() == value {
                 return Ok(command);
             }
         }
-        let printable = format_byte_array_as_string(value);
-        Err(
-            PeerError::from(format!("'{}' ({:?}) do not represent a known bitcoin command", printable, value))
-        )
+        Err(PeerError::UnknownCommand(printable_command(value)))
     }
 }
 
+/// command bytes as printable string, without the NULL padding
+fn printable_command(bytes: &[u8]) -> String {
+    let len = bytes.iter().rposition(|&c| c != 0).map_or(0, |pos| pos + 1);
+    let mut result = String::new();
+    for &c in &bytes[..len] {
+        result.push_str(std::str::from_utf8(&ascii::escape_default(c).collect::<Vec<u8>>()).unwrap())
+    }
+    result
+}
+
+/// Maximum length of a message payload we are willing to receive (matches `MAX_PROTOCOL_MESSAGE_LENGTH` of bitcoin core)
+pub const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4 * 1000 * 1000;
 
 /// Almost all integers are encoded in little endian. Only IP or port number are encoded big endian.
 pub struct RawMessage {
@@ -61,7 +195,7 @@ pub struct RawMessage {
     pub payload: Vec<u8>,
 }
 
-impl<'a> RawMessage {
+impl RawMessage {
     pub fn new(chain: Chain, command: Command, payload: Vec<u8>) -> Self {
         RawMessage {
             chain,
@@ -90,8 +224,12 @@ impl<'a> RawMessage {
         c.result()
     }
 
-    /// returns the buffer-length of the deserialized message in bytes and the corresponding message object
-    pub fn try_consume_message(buffer: &mut IOBuffer, expected_chain: Chain) -> PeerResult<MessageParseOutcome> {
+    /// Tries to take the next complete message from the beginning of `buffer`.
+    /// Incomplete messages remain in the buffer, so that they can be completed by subsequent reads.
+    ///
+    /// A message announcing a payload larger than `max_payload_len` or the limit of its command
+    /// (see [Command::max_payload_len]) results in [PeerError::PayloadTooLarge], before any of the payload is awaited.
+    pub fn try_consume_message(buffer: &mut IOBuffer, expected_chain: Chain, max_payload_len: usize) -> PeerResult<MessageParseOutcome> {
         let mut parser = ByteBufferParser::new(buffer.content());
 
         const HEADER_LEN: usize = 4 + 12 + 4 + 4;
@@ -100,24 +238,40 @@ impl<'a> RawMessage {
         }
 
         let magic = parser.read_u32_le()?;
-        let chain = Chain::try_from(magic)?;
-        if chain != expected_chain {
-            return Err(PeerError::from(format!("expected network chain {expected_chain:?}, but got a message from {chain:?}")));
+        if magic != expected_chain.magic_value() {
+            return Err(PeerError::WrongNetwork { expected: expected_chain, got: magic });
         }
 
         let command_string = parser.read(12).unwrap();
-        log::debug!("receiving command {}", String::from_utf8(Vec::from(command_string)).unwrap());
+        log::debug!("receiving command {}", String::from_utf8_lossy(command_string));
         let payload_len = parser.read_u32_le()? as usize;
         let checksum: [u8; 4] = parser.read(4)?.try_into().unwrap();
 
+        let command = Command::try_from(command_string);
+        let max_payload_len = match &command {
+            Ok(command) => command.max_payload_len().min(max_payload_len),
+            Err(_) => max_payload_len,
+        };
+        if payload_len > max_payload_len {
+            return Err(PeerError::PayloadTooLarge {
+                command: printable_command(command_string),
+                len: payload_len,
+                max: max_payload_len,
+            });
+        }
+
         if parser.remaining() < payload_len {
             return Ok(MessageParseOutcome::NoMessage);
         }
 
-        let payload = parser.read(payload_len as usize)?.to_vec();
-        Self::verify_checksum(&payload, &checksum)?;
+        let payload = parser.read(payload_len)?.to_vec();
+        if !Self::verify_checksum(&payload, &checksum) {
+            return Err(PeerError::Checksum {
+                command: printable_command(command_string)
+            });
+        }
 
-        let command = match Command::try_from(command_string) {
+        let command = match command {
             Ok(command) => command,
             Err(err) => {
                 buffer.shift_left(parser.pos());
@@ -130,27 +284,75 @@ impl<'a> RawMessage {
 
         Ok(MessageParseOutcome::Message(
             RawMessage {
-                chain,
+                chain: expected_chain,
                 command,
                 payload,
             }))
     }
 
-    pub fn to_protocol_message(self) -> PeerResult<ProtocolMessage> {
-        match self.command {
-            Command::Version => Ok(ProtocolMessage::Version(VersionMessage::from_raw_message(self)?)),
+    /// Discards bytes from the beginning of `buffer` up to the next occurrence of the magic value of `chain`,
+    /// which is likely the start of the next message. The first byte is always discarded, so that progress is made.
+    /// Without any occurrence, the trailing bytes which might begin a magic value are kept.
+    ///
+    /// Returns the number of discarded bytes.
+    pub fn skip_to_next_magic(buffer: &mut IOBuffer, chain: Chain) -> usize {
+        let magic = chain.magic_value().to_le_bytes();
+        let content = buffer.content();
+        let skip = match content.windows(magic.len()).skip(1).position(|w| w == magic) {
+            Some(pos) => pos + 1,
+            // keep the trailing bytes, which might be the beginning of the magic value
+            None => content.len().saturating_sub(magic.len() - 1).max(1).min(content.len()),
+        };
+        buffer.shift_left(skip);
+        skip
+    }
+
+    /// Decodes the payload. Undecodable payloads result in [PeerError::Malformed].
+    pub fn into_protocol_message(self) -> PeerResult<ProtocolMessage> {
+        let command = self.command.name();
+        let result = match self.command {
+            Command::Version => VersionMessage::from_raw_message(self).map(ProtocolMessage::Version),
             Command::Verack => Ok(ProtocolMessage::Verack(VerackMessage::new(self.chain))),
-            Command::Ping => Ok(ProtocolMessage::Ping(PingMessage::new(self.chain))),
-            Command::Pong => Ok(ProtocolMessage::Pong(PongMessage::new(self.chain))),
-        }
+            Command::Ping => PingMessage::from_raw_message(self).map(ProtocolMessage::Ping),
+            Command::Pong => PongMessage::from_raw_message(self).map(ProtocolMessage::Pong),
+            Command::GetAddr => Ok(ProtocolMessage::GetAddr(GetAddrMessage::new(self.chain))),
+            Command::Addr => AddrMessage::from_raw_message(self).map(ProtocolMessage::Addr),
+            Command::AddrV2 => AddrV2Message::from_raw_message(self).map(ProtocolMessage::AddrV2),
+            Command::SendAddrV2 => Ok(ProtocolMessage::SendAddrV2(SendAddrV2Message::new(self.chain))),
+            Command::Inv => InvMessage::from_raw_message(self).map(ProtocolMessage::Inv),
+            Command::GetData => GetDataMessage::from_raw_message(self).map(ProtocolMessage::GetData),
+            Command::NotFound => NotFoundMessage::from_raw_message(self).map(ProtocolMessage::NotFound),
+            Command::GetHeaders => GetHeadersMessage::from_raw_message(self).map(ProtocolMessage::GetHeaders),
+            Command::Headers => HeadersMessage::from_raw_message(self).map(ProtocolMessage::Headers),
+            Command::Block => BlockMessage::from_raw_message(self).map(ProtocolMessage::Block),
+            Command::Tx => TxMessage::from_raw_message(self).map(ProtocolMessage::Tx),
+            Command::Mempool => Ok(ProtocolMessage::Mempool(MempoolMessage::new(self.chain))),
+            Command::SendHeaders => Ok(ProtocolMessage::SendHeaders(SendHeadersMessage::new(self.chain))),
+            Command::SendCmpct => SendCmpctMessage::from_raw_message(self).map(ProtocolMessage::SendCmpct),
+            Command::FeeFilter => FeeFilterMessage::from_raw_message(self).map(ProtocolMessage::FeeFilter),
+            Command::WtxidRelay => Ok(ProtocolMessage::WtxidRelay(WtxidRelayMessage::new(self.chain))),
+            Command::CmpctBlock => CmpctBlockMessage::from_raw_message(self).map(ProtocolMessage::CmpctBlock),
+            Command::GetBlockTxn => GetBlockTxnMessage::from_raw_message(self).map(ProtocolMessage::GetBlockTxn),
+            Command::BlockTxn => BlockTxnMessage::from_raw_message(self).map(ProtocolMessage::BlockTxn),
+            Command::GetCFilters => GetCFiltersMessage::from_raw_message(self).map(ProtocolMessage::GetCFilters),
+            Command::CFilter => CFilterMessage::from_raw_message(self).map(ProtocolMessage::CFilter),
+            Command::GetCFHeaders => GetCFHeadersMessage::from_raw_message(self).map(ProtocolMessage::GetCFHeaders),
+            Command::CFHeaders => CFHeadersMessage::from_raw_message(self).map(ProtocolMessage::CFHeaders),
+            Command::GetCFCheckpt => GetCFCheckptMessage::from_raw_message(self).map(ProtocolMessage::GetCFCheckpt),
+            Command::CFCheckpt => CFCheckptMessage::from_raw_message(self).map(ProtocolMessage::CFCheckpt),
+            Command::FilterLoad => FilterLoadMessage::from_raw_message(self).map(ProtocolMessage::FilterLoad),
+            Command::FilterAdd => FilterAddMessage::from_raw_message(self).map(ProtocolMessage::FilterAdd),
+            Command::FilterClear => Ok(ProtocolMessage::FilterClear(FilterClearMessage::new(self.chain))),
+            Command::MerkleBlock => MerkleBlockMessage::from_raw_message(self).map(ProtocolMessage::MerkleBlock),
+        };
+        result.map_err(|err| match err {
+            PeerError::Io(source) => PeerError::Malformed { command: command.to_string(), source },
+            err => err,
+        })
     }
 
-    fn verify_checksum(payload: &[u8], checksum: &[u8]) -> PeerResult<()> {
-        if *checksum == sha256(&sha256(payload))[..4] {
-            Ok(())
-        } else {
-            Err(PeerError::from("checksum error"))
-        }
+    fn verify_checksum(payload: &[u8], checksum: &[u8]) -> bool {
+        *checksum == sha256(&sha256(payload))[..4]
     }
 }
 
@@ -167,11 +369,40 @@ impl From<ProtocolMessage> for RawMessage {
             ProtocolMessage::Verack(message) => message.to_raw_message(),
             ProtocolMessage::Ping(message) => message.to_raw_message(),
             ProtocolMessage::Pong(message) => message.to_raw_message(),
+            ProtocolMessage::GetAddr(message) => message.to_raw_message(),
+            ProtocolMessage::Addr(message) => message.to_raw_message(),
+            ProtocolMessage::AddrV2(message) => message.to_raw_message(),
+            ProtocolMessage::SendAddrV2(message) => message.to_raw_message(),
+            ProtocolMessage::Inv(message) => message.to_raw_message(),
+            ProtocolMessage::GetData(message) => message.to_raw_message(),
+            ProtocolMessage::NotFound(message) => message.to_raw_message(),
+            ProtocolMessage::GetHeaders(message) => message.to_raw_message(),
+            ProtocolMessage::Headers(message) => message.to_raw_message(),
+            ProtocolMessage::Block(message) => message.to_raw_message(),
+            ProtocolMessage::Tx(message) => message.to_raw_message(),
+            ProtocolMessage::Mempool(message) => message.to_raw_message(),
+            ProtocolMessage::SendHeaders(message) => message.to_raw_message(),
+            ProtocolMessage::SendCmpct(message) => message.to_raw_message(),
+            ProtocolMessage::FeeFilter(message) => message.to_raw_message(),
+            ProtocolMessage::WtxidRelay(message) => message.to_raw_message(),
+            ProtocolMessage::CmpctBlock(message) => message.to_raw_message(),
+            ProtocolMessage::GetBlockTxn(message) => message.to_raw_message(),
+            ProtocolMessage::BlockTxn(message) => message.to_raw_message(),
+            ProtocolMessage::GetCFilters(message) => message.to_raw_message(),
+            ProtocolMessage::CFilter(message) => message.to_raw_message(),
+            ProtocolMessage::GetCFHeaders(message) => message.to_raw_message(),
+            ProtocolMessage::CFHeaders(message) => message.to_raw_message(),
+            ProtocolMessage::GetCFCheckpt(message) => message.to_raw_message(),
+            ProtocolMessage::CFCheckpt(message) => message.to_raw_message(),
+            ProtocolMessage::FilterLoad(message) => message.to_raw_message(),
+            ProtocolMessage::FilterAdd(message) => message.to_raw_message(),
+            ProtocolMessage::FilterClear(message) => message.to_raw_message(),
+            ProtocolMessage::MerkleBlock(message) => message.to_raw_message(),
         }
     }
 }
 
-fn sha256(input: &[u8]) -> [u8; 32] {
+pub(super) fn sha256(input: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::default();
     hasher.update(input);
     hasher.finalize_fixed().into()
@@ -182,10 +413,10 @@ mod test {
     use hex_literal::hex;
     use rstest::*;
 
-    use crate::peer::wire_protocol::sha256;
-    use crate::wire_protocol::messages::sha256;
-    use crate::wire_protocol::raw_message::sha256;
-    use crate::wire_protocol::sha256;
+    use crate::wire_protocol::buffer::IOBuffer;
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
 
     #[rstest]
     #[case(b"hello world", & hex ! ("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")[..])]
@@ -193,4 +424,184 @@ mod test {
     fn test_message_sha256(#[case] input: &[u8], #[case] expected_result: &[u8]) {
         assert_eq!(&sha256(input), expected_result);
     }
+
+    fn consume_all(buffer: &mut IOBuffer) -> Vec<RawMessage> {
+        let mut messages = vec![];
+        loop {
+            match RawMessage::try_consume_message(buffer, Chain::Regtest, MAX_PROTOCOL_MESSAGE_LENGTH).unwrap() {
+                MessageParseOutcome::Message(m) => messages.push(m),
+                MessageParseOutcome::SkippedMessage => {}
+                MessageParseOutcome::NoMessage => return messages,
+            }
+        }
+    }
+
+    #[test]
+    fn test_consume_message_fed_byte_by_byte() {
+        let bytes = RawMessage::new(Chain::Regtest, Command::Ping, vec![1, 2, 3, 4, 5, 6, 7, 8]).to_bytes();
+        let mut buffer = IOBuffer::default();
+
+        for (i, b) in bytes.iter().enumerate() {
+            buffer.append(&[*b]);
+            let messages = consume_all(&mut buffer);
+            if i < bytes.len() - 1 {
+                assert!(messages.is_empty());
+                assert_eq!(buffer.content().len(), i + 1);
+            } else {
+                assert_eq!(messages.len(), 1);
+                assert_eq!(messages[0].payload, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+            }
+        }
+        assert!(buffer.content().is_empty());
+    }
+
+    #[test]
+    fn test_consume_multi_megabyte_message_in_chunks() {
+        // an unknown command, so that only the global limit applies
+        let payload: Vec<u8> = (0..3_500_000_u32).map(|i| i as u8).collect();
+        let mut bytes = RawMessage::new(Chain::Regtest, Command::Pong, payload).to_bytes();
+        bytes[4..16].copy_from_slice(b"bulk\0\0\0\0\0\0\0\0");
+        let big_message_len = bytes.len();
+        bytes.extend(RawMessage::new(Chain::Regtest, Command::Verack, vec![]).to_bytes());
+        let mut buffer = IOBuffer::default();
+
+        let mut messages = vec![];
+        let mut received = 0;
+        for chunk in bytes.chunks(1024 * 1024) {
+            buffer.append(chunk);
+            received += chunk.len();
+            messages.extend(consume_all(&mut buffer));
+            if received < big_message_len {
+                // the incomplete message stays in the buffer
+                assert_eq!(buffer.content().len(), received);
+            }
+        }
+
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(messages[0].command, Command::Verack));
+        assert!(buffer.content().is_empty());
+    }
+
+    #[test]
+    fn test_consume_message_through_writable_part() {
+        let bytes = RawMessage::new(Chain::Regtest, Command::Verack, vec![]).to_bytes();
+        let mut buffer = IOBuffer::default();
+        let writable = buffer.expose_writable_part();
+        writable[..bytes.len()].copy_from_slice(&bytes);
+        buffer.register_added_content(bytes.len());
+
+        assert_eq!(consume_all(&mut buffer).len(), 1);
+    }
+
+    #[test]
+    fn test_oversized_message_is_rejected_after_header() {
+        let bytes = RawMessage::new(Chain::Regtest, Command::Ping, vec![0; 1000]).to_bytes();
+        let mut buffer = IOBuffer::default();
+        buffer.append(&bytes[..24]);
+
+        // the limit of the command applies, regardless of the maximum message size
+        assert!(matches!(
+            RawMessage::try_consume_message(&mut buffer, Chain::Regtest, MAX_PROTOCOL_MESSAGE_LENGTH),
+            Err(PeerError::PayloadTooLarge { len: 1000, max: 8, .. })
+        ));
+    }
+
+    #[test]
+    fn test_oversized_message_of_unknown_command_is_rejected_after_header() {
+        let mut bytes = RawMessage::new(Chain::Regtest, Command::Ping, vec![0; 1000]).to_bytes();
+        bytes[4..16].copy_from_slice(b"foo\0\0\0\0\0\0\0\0\0");
+        let mut buffer = IOBuffer::default();
+        buffer.append(&bytes[..24]);
+
+        assert!(matches!(
+            RawMessage::try_consume_message(&mut buffer, Chain::Regtest, 999),
+            Err(PeerError::PayloadTooLarge { len: 1000, max: 999, .. })
+        ));
+        buffer.append(&bytes[24..]);
+        assert!(matches!(
+            RawMessage::try_consume_message(&mut buffer, Chain::Regtest, 1000),
+            Ok(MessageParseOutcome::SkippedMessage)
+        ));
+    }
+
+    #[test]
+    fn test_version_message_payload_limit() {
+        let mut bytes = RawMessage::new(Chain::Regtest, Command::Version, vec![]).to_bytes();
+        bytes[16..20].copy_from_slice(&(MAX_VERSION_PAYLOAD_LENGTH as u32 + 1).to_le_bytes());
+        let mut buffer = IOBuffer::default();
+        buffer.append(&bytes);
+
+        assert!(matches!(
+            RawMessage::try_consume_message(&mut buffer, Chain::Regtest, MAX_PROTOCOL_MESSAGE_LENGTH),
+            Err(PeerError::PayloadTooLarge { command, .. }) if command == "version"
+        ));
+    }
+
+    #[test]
+    fn test_skip_to_next_magic() {
+        let message = RawMessage::new(Chain::Regtest, Command::Verack, vec![]).to_bytes();
+        let mut buffer = IOBuffer::default();
+        buffer.append(&message[..10]);
+        buffer.append(b"garbage");
+        buffer.append(&message);
+
+        assert_eq!(RawMessage::skip_to_next_magic(&mut buffer, Chain::Regtest), 17);
+        assert_eq!(consume_all(&mut buffer).len(), 1);
+    }
+
+    #[test]
+    fn test_skip_to_next_magic_keeps_possible_magic_prefix() {
+        let mut buffer = IOBuffer::default();
+        buffer.append(b"garbage");
+        buffer.append(&Chain::Regtest.magic_value().to_le_bytes()[..3]);
+
+        assert_eq!(RawMessage::skip_to_next_magic(&mut buffer, Chain::Regtest), 7);
+        assert_eq!(buffer.content().len(), 3);
+    }
+
+    #[test]
+    fn test_message_of_other_network_is_rejected() {
+        let mut buffer = IOBuffer::default();
+        buffer.append(&RawMessage::new(Chain::Testnet3, Command::Verack, vec![]).to_bytes());
+
+        match RawMessage::try_consume_message(&mut buffer, Chain::Regtest, MAX_PROTOCOL_MESSAGE_LENGTH) {
+            Err(PeerError::WrongNetwork { expected: Chain::Regtest, got }) => assert_eq!(got, Chain::Testnet3.magic_value()),
+            other => panic!("expected a wrong network error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_rejected() {
+        let mut bytes = RawMessage::new(Chain::Regtest, Command::Ping, vec![1, 2, 3, 4, 5, 6, 7, 8]).to_bytes();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        let mut buffer = IOBuffer::default();
+        buffer.append(&bytes);
+
+        match RawMessage::try_consume_message(&mut buffer, Chain::Regtest, MAX_PROTOCOL_MESSAGE_LENGTH) {
+            Err(PeerError::Checksum { command }) => assert_eq!(command, "ping"),
+            other => panic!("expected a checksum error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_undecodable_payload_is_malformed() {
+        let raw = RawMessage::new(Chain::Regtest, Command::Ping, vec![1, 2, 3]);
+        match raw.into_protocol_message() {
+            Err(err @ PeerError::Malformed { .. }) => {
+                assert!(matches!(&err, PeerError::Malformed { command, .. } if command == "ping"));
+                let source = std::error::Error::source(&err).and_then(|source| source.downcast_ref::<std::io::Error>());
+                assert_eq!(source.map(|source| source.kind()), Some(std::io::ErrorKind::UnexpectedEof));
+            }
+            other => panic!("expected a malformed message error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_command() {
+        // removed from bitcoin core long ago
+        match Command::try_from(&b"alert\0\0\0\0\0\0\0"[..]) {
+            Err(PeerError::UnknownCommand(command)) => assert_eq!(command, "alert"),
+            other => panic!("expected an unknown command error, got {:?}", other),
+        }
+    }
 }
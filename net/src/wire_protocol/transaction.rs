@@ -0,0 +1,357 @@
+use std::io;
+
+use crate::wire_protocol::buffer::{ByteBufferComposer, ByteBufferParser, MAX_SIZE};
+use crate::wire_protocol::raw_message::sha256;
+
+/// weight of a byte, which is not part of the witness (BIP141)
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+/// minimal size of a serialized [TxIn]: outpoint, empty script and sequence
+const MIN_TX_IN_SIZE: usize = 36 + 1 + 4;
+/// minimal size of a serialized [TxOut]: value and empty script
+const MIN_TX_OUT_SIZE: usize = 8 + 1;
+
+/// Reference to an output of a previous transaction
+///
+/// size | field | type     | description
+/// ---  | ----- | ----     | ------------
+/// 32   | txid  | [u8; 32] | id of the referenced transaction (internal byte order)
+/// 4    | vout  | u32      | index of the referenced output
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+impl OutPoint {
+    /// the outpoint of a coinbase input, which does not spend anything
+    pub const NULL: OutPoint = OutPoint { txid: [0; 32], vout: u32::MAX };
+
+    pub fn is_null(&self) -> bool {
+        *self == Self::NULL
+    }
+}
+
+/// Transaction input
+///
+/// size | field           | type      | description
+/// ---  | -----           | ----      | ------------
+/// 36   | previous_output | outpoint  | the output to be spent
+/// ?    | script_sig      | var_bytes | script satisfying the conditions of the spent output
+/// 4    | sequence        | u32       | sequence number (relative lock time, BIP68)
+///
+/// The witness stack is serialized separately, after all outputs (BIP144).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+    pub witness: Vec<Vec<u8>>,
+}
+
+/// Transaction output
+///
+/// size | field         | type      | description
+/// ---  | -----         | ----      | ------------
+/// 8    | value         | i64       | amount in satoshis
+/// ?    | script_pubkey | var_bytes | conditions to spend this output
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TxOut {
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Transaction
+///
+/// size | field     | type      | description
+/// ---  | -----     | ----      | ------------
+/// 4    | version   | i32       | transaction version
+/// 2    | marker    | [u8; 2]   | `0x00 0x01`, only present in the segwit serialization (BIP144)
+/// ?    | tx_in     | TxIn[]    | inputs with CompactSize count prefix
+/// ?    | tx_out    | TxOut[]   | outputs with CompactSize count prefix
+/// ?    | witness   | witness[] | one stack per input, only present in the segwit serialization
+/// 4    | lock_time | u32       | block height or time, before which the transaction is not final
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    /// Decodes the legacy as well as the segwit serialization
+    pub(super) fn parse(parser: &mut ByteBufferParser) -> io::Result<Self> {
+        let version = parser.read_i32_le()?;
+        let mut inputs = Self::parse_inputs(parser)?;
+        // an empty input list is the marker of the segwit serialization, followed by the flag
+        let segwit = inputs.is_empty();
+        if segwit {
+            let flag = parser.read(1)?[0];
+            if flag != 1 {
+                return Err(invalid_data(format!("unknown transaction serialization flag {}", flag)));
+            }
+            inputs = Self::parse_inputs(parser)?;
+        }
+        let count = parser.read_length(parser.remaining() / MIN_TX_OUT_SIZE)?;
+        let outputs = (0..count)
+            .map(|_| Ok(TxOut {
+                value: parser.read_i64_le()?,
+                script_pubkey: parser.read_var_bytes(MAX_SIZE)?.to_vec(),
+            }))
+            .collect::<io::Result<Vec<_>>>()?;
+        if segwit {
+            for input in inputs.iter_mut() {
+                let count = parser.read_length(parser.remaining())?;
+                input.witness = (0..count)
+                    .map(|_| Ok(parser.read_var_bytes(MAX_SIZE)?.to_vec()))
+                    .collect::<io::Result<Vec<_>>>()?;
+            }
+            if inputs.iter().all(|input| input.witness.is_empty()) {
+                return Err(invalid_data("segwit serialization without witness data".to_string()));
+            }
+        }
+        let lock_time = parser.read_u32_le()?;
+        Ok(Transaction { version, inputs, outputs, lock_time })
+    }
+
+    fn parse_inputs(parser: &mut ByteBufferParser) -> io::Result<Vec<TxIn>> {
+        let count = parser.read_length(parser.remaining() / MIN_TX_IN_SIZE)?;
+        (0..count)
+            .map(|_| Ok(TxIn {
+                previous_output: OutPoint {
+                    txid: parser.read(32)?.try_into().unwrap(),
+                    vout: parser.read_u32_le()?,
+                },
+                script_sig: parser.read_var_bytes(MAX_SIZE)?.to_vec(),
+                sequence: parser.read_u32_le()?,
+                witness: vec![],
+            }))
+            .collect()
+    }
+
+    /// Serializes the transaction; `with_witness` selects the segwit serialization for transactions having witness data
+    pub(super) fn append(&self, composer: &mut ByteBufferComposer, with_witness: bool) {
+        let segwit = with_witness && self.has_witness();
+        composer.append(&self.version.to_le_bytes());
+        if segwit {
+            composer.append(&[0x00, 0x01]);
+        }
+        composer.append_compact_size(self.inputs.len() as u64);
+        for input in &self.inputs {
+            composer.append(&input.previous_output.txid);
+            composer.append(&input.previous_output.vout.to_le_bytes());
+            composer.append_var_bytes(&input.script_sig);
+            composer.append(&input.sequence.to_le_bytes());
+        }
+        composer.append_compact_size(self.outputs.len() as u64);
+        for output in &self.outputs {
+            composer.append(&output.value.to_le_bytes());
+            composer.append_var_bytes(&output.script_pubkey);
+        }
+        if segwit {
+            for input in &self.inputs {
+                composer.append_compact_size(input.witness.len() as u64);
+                for item in &input.witness {
+                    composer.append_var_bytes(item);
+                }
+            }
+        }
+        composer.append(&self.lock_time.to_le_bytes());
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut parser = ByteBufferParser::new(bytes);
+        let tx = Self::parse(&mut parser)?;
+        match parser.remaining() {
+            0 => Ok(tx),
+            n => Err(invalid_data(format!("{} bytes left after the transaction", n))),
+        }
+    }
+
+    /// segwit serialization (BIP144), if the transaction has witness data, legacy serialization otherwise
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut composer = ByteBufferComposer::new();
+        self.append(&mut composer, true);
+        composer.result()
+    }
+
+    /// serialization without witness data
+    pub fn to_legacy_bytes(&self) -> Vec<u8> {
+        let mut composer = ByteBufferComposer::new();
+        self.append(&mut composer, false);
+        composer.result()
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0].previous_output.is_null()
+    }
+
+    /// hash of the legacy serialization in internal byte order
+    pub fn txid(&self) -> [u8; 32] {
+        sha256(&sha256(&self.to_legacy_bytes()))
+    }
+
+    /// hash of the serialization including witness data in internal byte order; equals the txid without witness data
+    pub fn wtxid(&self) -> [u8; 32] {
+        sha256(&sha256(&self.to_bytes()))
+    }
+
+    /// size of the legacy serialization times 3 plus the size of the complete serialization (BIP141)
+    pub fn weight(&self) -> usize {
+        self.to_legacy_bytes().len() * (WITNESS_SCALE_FACTOR - 1) + self.to_bytes().len()
+    }
+
+    /// virtual size: weight / 4, rounded up
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(WITNESS_SCALE_FACTOR)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+
+    use crate::wire_protocol::block::display_hash;
+    use crate::wire_protocol::node::Chain;
+
+    use super::*;
+
+    /// coinbase transaction of the regtest genesis block (shared with mainnet)
+    const GENESIS_COINBASE: [u8; 204] = hex!(
+        "01000000" "01" "0000000000000000000000000000000000000000000000000000000000000000" "ffffffff"
+        "4d" "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73"
+        "ffffffff" "01" "00f2052a01000000"
+        "43" "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac"
+        "00000000");
+
+    /// spends a P2PK and a P2WPKH output (example of BIP143)
+    const SEGWIT_TX: [u8; 343] = hex!(
+        "01000000" "0001" "02"
+        "fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f" "00000000"
+        "49" "4830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01"
+        "eeffffff"
+        "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a" "01000000" "00" "ffffffff"
+        "02"
+        "202cb20600000000" "19" "76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac"
+        "9093510d00000000" "19" "76a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac"
+        "00"
+        "02" "47" "304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee01"
+        "21" "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeeb6357"
+        "11000000");
+
+    /// Coinbase of block 1 on regtest, the way `generatetoaddress` builds it: BIP34 height, 50 BTC to a P2WPKH address
+    /// and the witness commitment, with the witness reserved value as witness. Expected hashes computed independently.
+    const REGTEST_COINBASE: [u8; 167] = hex!(
+        "02000000" "0001" "01" "0000000000000000000000000000000000000000000000000000000000000000" "ffffffff"
+        "02" "5100" "ffffffff"
+        "02"
+        "00f2052a01000000" "16" "00144908bb96b5e86a716a6c7a3ece4f95c45d24b4e2"
+        "0000000000000000" "26" "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9"
+        "01" "20" "0000000000000000000000000000000000000000000000000000000000000000"
+        "00000000");
+
+    /// Regtest wallet style P2PKH spend (version 2, RBF sequence, anti fee sniping lock time) with a change output.
+    /// Expected hashes computed independently.
+    const REGTEST_P2PKH_SPEND: [u8; 225] = hex!(
+        "02000000" "01"
+        "46a6abfc9a010d8942320717f8d4407d8b0f656458ae3ef3adbdbe5b31b9df46" "00000000"
+        "6a" "47304402205fbf7e79db96c7117b06d7faa860f538ff6a111dbb8a05f3f2316a844f125fd70220454872826b08cb0fd678c17a8912aadb9deb5dab2da0782be76098e25c972bd701"
+        "2102d45b64986dad3a337ef84934bd461127040ddb4a7a763ed3f67ca9ce7e22a07c"
+        "fdffffff"
+        "02"
+        "00ca9a3b00000000" "19" "76a914b0e6034938251e7e38b2bc340a673791fc59168088ac"
+        "2c1f6bee00000000" "19" "76a9144908bb96b5e86a716a6c7a3ece4f95c45d24b4e288ac"
+        "65000000");
+
+    #[test]
+    fn test_legacy_transaction_round_trip() {
+        let tx = Transaction::from_bytes(&GENESIS_COINBASE).unwrap();
+        assert!(tx.is_coinbase());
+        assert!(!tx.has_witness());
+        assert_eq!(tx.outputs[0].value, 50_0000_0000);
+        assert_eq!(tx.to_bytes(), GENESIS_COINBASE);
+
+        // the only transaction of the genesis block
+        assert_eq!(tx.txid(), Chain::Regtest.genesis_header().merkle_root);
+        assert_eq!(tx.wtxid(), tx.txid());
+        assert_eq!(tx.weight(), 204 * 4);
+        assert_eq!(tx.vsize(), 204);
+    }
+
+    #[test]
+    fn test_segwit_transaction_round_trip() {
+        let tx = Transaction::from_bytes(&SEGWIT_TX).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert!(tx.inputs[0].witness.is_empty());
+        assert_eq!(tx.inputs[1].witness.len(), 2);
+        assert_eq!(tx.outputs.len(), 2);
+        assert_eq!(tx.lock_time, 17);
+        assert_eq!(tx.to_bytes(), SEGWIT_TX);
+
+        let legacy = tx.to_legacy_bytes();
+        // without marker, flag and the witness stacks of both inputs
+        assert_eq!(legacy.len(), SEGWIT_TX.len() - 2 - 1 - (1 + 1 + 0x47 + 1 + 0x21));
+        assert_eq!(Transaction::from_bytes(&legacy).unwrap().txid(), tx.txid());
+        assert_eq!(display_hash(&tx.wtxid()), "2eade7c9e5e7fba6d26f22d25677070cc8ee9f6b52ce5d9b3f574d1867e5f7b1");
+        assert_eq!(display_hash(&tx.txid()), "e8151a2af31c368a35053ddd4bdb285a8595c769a3ad83e0fa02314a602d4609");
+
+        assert_eq!(tx.weight(), 233 * 3 + 343);
+        assert_eq!(tx.vsize(), 261);
+    }
+
+    #[test]
+    fn test_regtest_legacy_transaction() {
+        let tx = Transaction::from_bytes(&REGTEST_P2PKH_SPEND).unwrap();
+        assert!(!tx.is_coinbase());
+        assert!(!tx.has_witness());
+        assert_eq!(tx.inputs[0].sequence, 0xfffffffd);
+        assert_eq!(tx.outputs.iter().map(|output| output.value).sum::<i64>(), 49_9999_7740);
+        assert_eq!(tx.lock_time, 101);
+        assert_eq!(tx.to_bytes(), REGTEST_P2PKH_SPEND);
+
+        assert_eq!(display_hash(&tx.txid()), "d21ca63560dd4fe5073a719c60a771682976860e0409b6933779f0e058ad17d0");
+        assert_eq!(tx.wtxid(), tx.txid());
+        assert_eq!(tx.vsize(), 225);
+    }
+
+    #[test]
+    fn test_regtest_segwit_coinbase() {
+        let tx = Transaction::from_bytes(&REGTEST_COINBASE).unwrap();
+        assert!(tx.is_coinbase());
+        assert_eq!(tx.inputs[0].witness, vec![vec![0; 32]]);
+        assert_eq!(tx.outputs[0].value, 50_0000_0000);
+        assert_eq!(tx.to_bytes(), REGTEST_COINBASE);
+
+        assert_eq!(display_hash(&tx.txid()), "ed231f5cf1683086880a36cbab0a47679fcf3f8ef3761bf7513e1619e1a4ac71");
+        assert_eq!(display_hash(&tx.wtxid()), "fb974600d6c5255b6d8969a5bc26b6806a3212d69304b7479d5292a72010c1c4");
+        assert_eq!(tx.weight(), 560);
+        assert_eq!(tx.vsize(), 140);
+    }
+
+    #[test]
+    fn test_segwit_serialization_without_witness_is_rejected() {
+        let mut tx = Transaction::from_bytes(&SEGWIT_TX).unwrap();
+        tx.inputs[1].witness.clear();
+        let mut bytes = tx.to_legacy_bytes();
+        // marker, flag and two empty witness stacks
+        bytes.splice(4..4, [0x00, 0x01]);
+        bytes.splice(bytes.len() - 4..bytes.len() - 4, [0x00, 0x00]);
+        assert!(Transaction::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_trailing_bytes_are_rejected() {
+        let mut bytes = GENESIS_COINBASE.to_vec();
+        bytes.push(0);
+        assert!(Transaction::from_bytes(&bytes).is_err());
+    }
+}
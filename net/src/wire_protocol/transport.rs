@@ -0,0 +1,388 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::buffer::IOBuffer;
+use crate::wire_protocol::messages::ProtocolMessage;
+use crate::wire_protocol::misbehavior::Misbehavior;
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::peer::{GarbagePolicy, Peer, PeerEvent};
+use crate::wire_protocol::raw_message::{MAX_PROTOCOL_MESSAGE_LENGTH, MessageParseOutcome, RawMessage};
+use crate::wire_protocol::v2_transport::{ELLSWIFT_KEY_SIZE, GARBAGE_TERMINATOR_SIZE, MAX_GARBAGE_SIZE, V2Cipher, V2Handshake};
+
+/// Exchanges protocol messages with a remote node, hiding how they are framed (and possibly encrypted) on the wire.
+///
+/// Conversation topics run on any transport through a [NodeConnection](crate::wire_protocol::connection::NodeConnection).
+pub trait Transport {
+    /// sends `message` to the remote node
+    fn send(&mut self, message: ProtocolMessage) -> impl Future<Output = PeerResult<()>> + Send;
+
+    /// Receives the next message of the remote node. Messages of unknown commands are skipped.
+    ///
+    /// Implementations must be cancel safe: when the returned future is dropped before completion, no partially
+    /// received message gets lost, so that receiving can be raced against a timer.
+    fn receive(&mut self) -> impl Future<Output = PeerResult<ProtocolMessage>> + Send;
+
+    /// Misbehavior of the remote node noticed since the last call, which did not make the transport fail
+    /// (e.g. garbage dropped to resynchronize the stream)
+    fn take_misbehavior(&mut self) -> Vec<Misbehavior> {
+        vec![]
+    }
+}
+
+/// Unencrypted v1 transport: messages framed by a header carrying the network's magic value, command, length and
+/// checksum (see [RawMessage::to_bytes]), over any byte stream, e.g. a TCP or unix socket, a proxied connection or
+/// an in-memory [tokio::io::duplex] stream. The framing is done by a [Peer], whose [Peer::negotiation] remains unused:
+/// announcements are recorded by [NodeConnection::negotiation](crate::wire_protocol::connection::NodeConnection::negotiation).
+pub struct V1Transport<S> {
+    stream: S,
+    /// survives across reads and conversation topics, so that no partially received message gets lost
+    peer: Peer,
+    misbehavior: Vec<Misbehavior>,
+}
+
+impl<S> V1Transport<S> {
+    pub fn new(chain: Chain, stream: S) -> Self {
+        Self::with_received(chain, stream, &[])
+    }
+
+    /// continues with bytes, which were already received from `stream`
+    pub(super) fn with_received(chain: Chain, stream: S, received: &[u8]) -> Self {
+        let mut peer = Peer::new(chain);
+        peer.receive(received);
+        V1Transport { stream, peer, misbehavior: vec![] }
+    }
+
+    /// see [Peer::set_max_message_size]
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.peer.set_max_message_size(max_message_size);
+    }
+
+    pub fn set_garbage_policy(&mut self, garbage_policy: GarbagePolicy) {
+        self.peer.set_garbage_policy(garbage_policy);
+    }
+
+    /// see [Peer::dropped_bytes]
+    pub fn dropped_bytes(&self) -> u64 {
+        self.peer.dropped_bytes()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for V1Transport<S> {
+    async fn send(&mut self, message: ProtocolMessage) -> PeerResult<()> {
+        self.peer.send(message);
+        self.stream.write_all(&self.peer.take_outgoing()).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> PeerResult<ProtocolMessage> {
+        loop {
+            match self.peer.poll_event()? {
+                Some(PeerEvent::Message(message)) => return Ok(message),
+                Some(PeerEvent::Misbehavior(misbehavior)) => self.misbehavior.push(misbehavior),
+                None => read_more(&mut self.stream, self.peer.receive_buffer()).await?,
+            }
+        }
+    }
+
+    fn take_misbehavior(&mut self) -> Vec<Misbehavior> {
+        std::mem::take(&mut self.misbehavior)
+    }
+}
+
+/// Encrypted v2 transport (BIP324) over any byte stream, see [V2Cipher]
+pub struct V2Transport<S> {
+    chain: Chain,
+    stream: S,
+    /// survives across reads and conversation topics, so that no partially received packet gets lost
+    receive_buffer: IOBuffer,
+    max_message_size: usize,
+    /// boxed, as the cipher states are rather large compared to the rest of a transport
+    cipher: Box<V2Cipher>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> V2Transport<S> {
+    /// Performs the handshake as initiator. Returns `None`, if the responder hangs up or stays silent for `key_timeout`
+    /// before sending its public key, which is what nodes not supporting v2 do. They are to be reconnected with v1.
+    pub async fn initiate(chain: Chain, mut stream: S, key_timeout: Duration) -> PeerResult<Option<Self>> {
+        let handshake = V2Handshake::new();
+        stream.write_all(&handshake.key_and_garbage()).await?;
+
+        let mut receive_buffer = IOBuffer::default();
+        let remote_key = match time::timeout(key_timeout, receive_remote_key(&mut stream, &mut receive_buffer)).await {
+            Ok(Ok(remote_key)) => remote_key,
+            Ok(Err(PeerError::Disconnected | PeerError::Io(_))) | Err(_) => return Ok(None),
+            Ok(Err(err)) => return Err(err),
+        };
+        let cipher = handshake.complete(&remote_key, true, chain);
+        Self::finish_handshake(chain, stream, receive_buffer, cipher, vec![]).await.map(Some)
+    }
+
+    /// Performs the handshake as responder. Initiators starting with a v1 __version__ message are detected, in which
+    /// case the v1 transport continues with the bytes received so far.
+    pub async fn respond(chain: Chain, mut stream: S) -> PeerResult<NegotiatedTransport<S>> {
+        let mut receive_buffer = IOBuffer::default();
+        let mut v1_prefix = chain.magic_value().to_le_bytes().to_vec();
+        v1_prefix.extend_from_slice(b"version\0\0\0\0\0");
+        while receive_buffer.content().len() < v1_prefix.len() && v1_prefix.starts_with(receive_buffer.content()) {
+            read_more(&mut stream, &mut receive_buffer).await?;
+        }
+        if receive_buffer.content().starts_with(&v1_prefix) {
+            log::debug!("initiator uses the v1 transport");
+            return Ok(NegotiatedTransport::V1(V1Transport::with_received(chain, stream, receive_buffer.content())));
+        }
+
+        let remote_key = receive_remote_key(&mut stream, &mut receive_buffer).await?;
+        let handshake = V2Handshake::new();
+        let key_and_garbage = handshake.key_and_garbage();
+        let cipher = handshake.complete(&remote_key, false, chain);
+        Self::finish_handshake(chain, stream, receive_buffer, cipher, key_and_garbage).await.map(NegotiatedTransport::V2)
+    }
+
+    /// Sends `prefix`, our garbage terminator and version packet, then awaits the garbage and version packet of the
+    /// remote node. The contents of the version packets are reserved for future extensions and ignored.
+    async fn finish_handshake(chain: Chain, mut stream: S, mut receive_buffer: IOBuffer, mut cipher: V2Cipher, prefix: Vec<u8>) -> PeerResult<Self> {
+        let mut bytes = prefix;
+        bytes.extend_from_slice(cipher.send_garbage_terminator());
+        bytes.extend(cipher.encrypt_packet(&[], false));
+        stream.write_all(&bytes).await?;
+
+        loop {
+            let content = receive_buffer.content();
+            if let Some(pos) = content.windows(GARBAGE_TERMINATOR_SIZE).position(|w| w == cipher.receive_garbage_terminator()) {
+                cipher.set_received_garbage(&content[..pos]);
+                receive_buffer.shift_left(pos + GARBAGE_TERMINATOR_SIZE);
+                break;
+            }
+            if content.len() >= MAX_GARBAGE_SIZE + GARBAGE_TERMINATOR_SIZE {
+                return Err(PeerError::ProtocolViolation("no garbage terminator within the maximum garbage size".to_string()));
+            }
+            read_more(&mut stream, &mut receive_buffer).await?;
+        }
+
+        loop {
+            match cipher.try_decrypt_packet(&mut receive_buffer, MAX_PROTOCOL_MESSAGE_LENGTH)? {
+                Some((true, _)) => log::trace!("skipping decoy packet"),
+                Some((false, _)) => break,
+                None => read_more(&mut stream, &mut receive_buffer).await?,
+            }
+        }
+        log::debug!("established v2 transport");
+        Ok(V2Transport { chain, stream, receive_buffer, max_message_size: MAX_PROTOCOL_MESSAGE_LENGTH, cipher: Box::new(cipher) })
+    }
+}
+
+impl<S> V2Transport<S> {
+    /// Maximum payload size of a received message. Receiving fails, when a remote node announces a larger one.
+    /// Defaults to [MAX_PROTOCOL_MESSAGE_LENGTH].
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        self.max_message_size = max_message_size;
+    }
+
+    /// see [V2Cipher::session_id]
+    pub fn session_id(&self) -> [u8; 32] {
+        self.cipher.session_id()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for V2Transport<S> {
+    async fn send(&mut self, message: ProtocolMessage) -> PeerResult<()> {
+        let packet = self.cipher.encrypt_message(&RawMessage::from(message));
+        self.stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> PeerResult<ProtocolMessage> {
+        loop {
+            match self.cipher.try_consume_message(&mut self.receive_buffer, self.chain, self.max_message_size)? {
+                MessageParseOutcome::Message(raw_message) => return raw_message.into_protocol_message(),
+                MessageParseOutcome::SkippedMessage => {}
+                MessageParseOutcome::NoMessage => read_more(&mut self.stream, &mut self.receive_buffer).await?,
+            }
+        }
+    }
+}
+
+/// waits for the public key of the remote node and removes it from `buffer`
+async fn receive_remote_key<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut IOBuffer) -> PeerResult<[u8; ELLSWIFT_KEY_SIZE]> {
+    while buffer.content().len() < ELLSWIFT_KEY_SIZE {
+        read_more(stream, buffer).await?;
+    }
+    let key = buffer.content()[..ELLSWIFT_KEY_SIZE].try_into().unwrap();
+    buffer.shift_left(ELLSWIFT_KEY_SIZE);
+    Ok(key)
+}
+
+/// v1 or v2 transport, depending on what both nodes support
+pub enum NegotiatedTransport<S> {
+    V1(V1Transport<S>),
+    V2(V2Transport<S>),
+}
+
+impl<S> NegotiatedTransport<S> {
+    /// see [V1Transport::set_max_message_size]
+    pub fn set_max_message_size(&mut self, max_message_size: usize) {
+        match self {
+            NegotiatedTransport::V1(transport) => transport.set_max_message_size(max_message_size),
+            NegotiatedTransport::V2(transport) => transport.set_max_message_size(max_message_size),
+        }
+    }
+
+    /// applies to the v1 transport only, the v2 transport authenticates each packet
+    pub fn set_garbage_policy(&mut self, garbage_policy: GarbagePolicy) {
+        if let NegotiatedTransport::V1(transport) = self {
+            transport.set_garbage_policy(garbage_policy);
+        }
+    }
+
+    /// see [V1Transport::dropped_bytes]
+    pub fn dropped_bytes(&self) -> u64 {
+        match self {
+            NegotiatedTransport::V1(transport) => transport.dropped_bytes(),
+            NegotiatedTransport::V2(_) => 0,
+        }
+    }
+
+    /// session id of the v2 transport, `None` for the v1 transport
+    pub fn session_id(&self) -> Option<[u8; 32]> {
+        match self {
+            NegotiatedTransport::V1(_) => None,
+            NegotiatedTransport::V2(transport) => Some(transport.session_id()),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for NegotiatedTransport<S> {
+    async fn send(&mut self, message: ProtocolMessage) -> PeerResult<()> {
+        match self {
+            NegotiatedTransport::V1(transport) => transport.send(message).await,
+            NegotiatedTransport::V2(transport) => transport.send(message).await,
+        }
+    }
+
+    async fn receive(&mut self) -> PeerResult<ProtocolMessage> {
+        match self {
+            NegotiatedTransport::V1(transport) => transport.receive().await,
+            NegotiatedTransport::V2(transport) => transport.receive().await,
+        }
+    }
+
+    fn take_misbehavior(&mut self) -> Vec<Misbehavior> {
+        match self {
+            NegotiatedTransport::V1(transport) => transport.take_misbehavior(),
+            NegotiatedTransport::V2(transport) => transport.take_misbehavior(),
+        }
+    }
+}
+
+/// reads the next chunk of bytes from `stream` into `buffer`
+pub(super) async fn read_more<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut IOBuffer) -> PeerResult<()> {
+    match stream.read(buffer.expose_writable_part()).await? {
+        0 => Err(PeerError::Disconnected),
+        n => {
+            buffer.register_added_content(n);
+            log::trace!("received {n} bytes, new buffer pos is {}", buffer.content().len());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use tokio::io::duplex;
+
+    use crate::wire_protocol::connection::NodeConnection;
+    use crate::wire_protocol::handshake::{HandshakeInitConversationTopic, HandshakeRespondConversationTopic, LocalNonces};
+    use crate::wire_protocol::messages::{PingMessage, VerackMessage};
+    use crate::wire_protocol::node::NodeDesc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_over_duplex_stream() {
+        // messages exceed the capacity of the stream, so that sending and receiving have to interleave
+        let (local, remote) = duplex(16);
+        let mut local = V1Transport::new(Chain::Regtest, local);
+        let mut remote = V1Transport::new(Chain::Regtest, remote);
+
+        let ping = ProtocolMessage::Ping(PingMessage::new(Chain::Regtest));
+        let (sent, received) = tokio::join!(
+            async {
+                local.send(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).await?;
+                local.send(ping).await
+            },
+            async { Ok::<_, PeerError>((remote.receive().await?, remote.receive().await?)) },
+        );
+        sent.unwrap();
+        let (first, second) = received.unwrap();
+        assert!(matches!(first, ProtocolMessage::Verack(_)));
+        assert!(matches!(second, ProtocolMessage::Ping(_)));
+    }
+
+    #[tokio::test]
+    async fn test_closed_stream_is_a_disconnect() {
+        let (local, remote) = duplex(64);
+        let mut local = V1Transport::new(Chain::Regtest, local);
+        drop(remote);
+        assert!(matches!(local.receive().await, Err(PeerError::Disconnected)));
+    }
+
+    #[tokio::test]
+    async fn test_garbage_is_taken_as_misbehavior() {
+        let (local, mut remote) = duplex(1024);
+        let mut local = V1Transport::new(Chain::Regtest, local);
+        let mut bytes = b"garbage".to_vec();
+        bytes.extend(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest)).to_bytes());
+        remote.write_all(&bytes).await.unwrap();
+
+        assert!(matches!(local.receive().await.unwrap(), ProtocolMessage::Verack(_)));
+        assert_eq!(local.dropped_bytes(), 7);
+        assert!(matches!(local.take_misbehavior()[..], [Misbehavior::Garbage { dropped_bytes: 7, .. }]));
+        assert!(local.take_misbehavior().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_duplex_stream() {
+        let (initiator, responder) = duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let mut initiator = NodeConnection::with_transport(V1Transport::new(Chain::Regtest, initiator), addr);
+        let mut responder = NodeConnection::with_transport(V1Transport::new(Chain::Regtest, responder), addr);
+
+        let initiating = HandshakeInitConversationTopic::new(&NodeDesc::test_node("/initiator/"), addr, &LocalNonces::default());
+        let responding = HandshakeRespondConversationTopic::new(&NodeDesc::test_node("/responder/"), addr, &LocalNonces::default());
+        let (seen_by_initiator, seen_by_responder) = tokio::join!(
+            initiator.proceed_conversation(initiating),
+            responder.proceed_conversation(responding),
+        );
+        assert_eq!(seen_by_initiator.unwrap().sub_ver, "/responder/");
+        assert_eq!(seen_by_responder.unwrap().sub_ver, "/initiator/");
+    }
+
+    #[tokio::test]
+    async fn test_transport_over_duplex_stream() {
+        // large enough for both sides to send their garbage, before they read
+        let (initiator, responder) = tokio::io::duplex(64 * 1024);
+        let (initiator, responder) = tokio::join!(
+            V2Transport::initiate(Chain::Regtest, initiator, Duration::from_secs(5)),
+            V2Transport::respond(Chain::Regtest, responder),
+        );
+        let mut initiator = initiator.unwrap().unwrap();
+        let mut responder = match responder.unwrap() {
+            NegotiatedTransport::V2(transport) => transport,
+            NegotiatedTransport::V1(_) => panic!("expected the v2 transport"),
+        };
+        assert_eq!(initiator.session_id(), responder.session_id());
+
+        let ping = PingMessage::new(Chain::Regtest);
+        let nonce = ping.nonce;
+        initiator.send(ProtocolMessage::Ping(ping)).await.unwrap();
+        initiator.send(ProtocolMessage::Verack(VerackMessage::new(Chain::Regtest))).await.unwrap();
+        assert!(matches!(responder.receive().await.unwrap(), ProtocolMessage::Ping(received) if received.nonce == nonce));
+        assert!(matches!(responder.receive().await.unwrap(), ProtocolMessage::Verack(_)));
+    }
+}
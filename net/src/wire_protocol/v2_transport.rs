@@ -0,0 +1,575 @@
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use hkdf::Hkdf;
+use rand::{Rng, RngCore, thread_rng};
+use secp256k1::{Secp256k1, SecretKey};
+use secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use sha2::Sha256;
+use strum::IntoEnumIterator;
+
+use crate::error::{PeerError, PeerResult};
+use crate::wire_protocol::buffer::IOBuffer;
+use crate::wire_protocol::node::Chain;
+use crate::wire_protocol::raw_message::{Command, MessageParseOutcome, RawMessage};
+
+/// size of the ElligatorSwift encoded public key, which starts a v2 connection
+pub const ELLSWIFT_KEY_SIZE: usize = 64;
+
+/// size of the garbage terminator, which follows the garbage sent after the public key
+pub const GARBAGE_TERMINATOR_SIZE: usize = 16;
+
+/// maximum number of garbage bytes following the public key
+pub const MAX_GARBAGE_SIZE: usize = 4095;
+
+/// number of packets (or length fields), after which the keys of a cipher are replaced
+const REKEY_INTERVAL: u64 = 224;
+
+/// size of the encrypted contents length of a packet
+const LENGTH_FIELD_SIZE: usize = 3;
+
+/// size of the header byte of a packet, which carries the ignore bit
+const HEADER_SIZE: usize = 1;
+
+/// size of the Poly1305 authentication tag of a packet
+const TAG_SIZE: usize = 16;
+
+/// header flag of decoy packets, which are to be ignored by the receiver
+const IGNORE_BIT: u8 = 0x80;
+
+/// Short message id of `command` in a v2 packet (BIP324). Commands without one are sent with their 12 byte name.
+fn short_id(command: &Command) -> Option<u8> {
+    match command {
+        Command::Addr => Some(1),
+        Command::Block => Some(2),
+        Command::BlockTxn => Some(3),
+        Command::CmpctBlock => Some(4),
+        Command::FeeFilter => Some(5),
+        Command::FilterAdd => Some(6),
+        Command::FilterClear => Some(7),
+        Command::FilterLoad => Some(8),
+        // 9 is getblocks, which we don't support
+        Command::GetBlockTxn => Some(10),
+        Command::GetData => Some(11),
+        Command::GetHeaders => Some(12),
+        Command::Headers => Some(13),
+        Command::Inv => Some(14),
+        Command::Mempool => Some(15),
+        Command::MerkleBlock => Some(16),
+        Command::NotFound => Some(17),
+        Command::Ping => Some(18),
+        Command::Pong => Some(19),
+        Command::SendCmpct => Some(20),
+        Command::Tx => Some(21),
+        Command::GetCFilters => Some(22),
+        Command::CFilter => Some(23),
+        Command::GetCFHeaders => Some(24),
+        Command::CFHeaders => Some(25),
+        Command::GetCFCheckpt => Some(26),
+        Command::CFCheckpt => Some(27),
+        Command::AddrV2 => Some(28),
+        Command::Version | Command::Verack | Command::GetAddr | Command::SendAddrV2 | Command::SendHeaders
+        | Command::WtxidRelay => None,
+    }
+}
+
+/// Our side of the v2 key exchange: an ephemeral key pair and the garbage sent along with the public key.
+///
+/// Sans-IO: send [Self::key_and_garbage], [complete](Self::complete) the handshake with the received public key and
+/// continue with the resulting [V2Cipher].
+pub struct V2Handshake {
+    secret_key: SecretKey,
+    public_key: ElligatorSwift,
+    garbage: Vec<u8>,
+}
+
+impl V2Handshake {
+    /// fresh ephemeral key followed by a random amount of random garbage
+    pub fn new() -> Self {
+        let mut rng = thread_rng();
+        let secret_key = SecretKey::new(&mut rng);
+        let public_key = ElligatorSwift::from_seckey(&Secp256k1::new(), secret_key, Some(rng.gen()));
+        let mut garbage = vec![0; rng.gen_range(0..=MAX_GARBAGE_SIZE)];
+        rng.fill_bytes(&mut garbage);
+        V2Handshake { secret_key, public_key, garbage }
+    }
+
+    /// the ElligatorSwift encoded public key followed by the garbage, i.e. what is sent first
+    pub fn key_and_garbage(&self) -> Vec<u8> {
+        let mut bytes = self.public_key.to_array().to_vec();
+        bytes.extend_from_slice(&self.garbage);
+        bytes
+    }
+
+    /// Derives the session keys from the public key received from the remote node
+    pub fn complete(self, remote_key: &[u8; ELLSWIFT_KEY_SIZE], initiator: bool, chain: Chain) -> V2Cipher {
+        let remote_key = ElligatorSwift::from_array(*remote_key);
+        let shared_secret = match initiator {
+            true => ElligatorSwift::shared_secret(self.public_key, remote_key, self.secret_key, ElligatorSwiftParty::A, None),
+            false => ElligatorSwift::shared_secret(remote_key, self.public_key, self.secret_key, ElligatorSwiftParty::B, None),
+        };
+        V2Cipher::new(&shared_secret.to_secret_bytes(), initiator, chain, self.garbage)
+    }
+}
+
+impl Default for V2Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// ChaCha20 stream for the length fields of the packets, which is rekeyed after every [REKEY_INTERVAL] chunks
+struct FSChaCha20 {
+    cipher: ChaCha20,
+    chunk_counter: u64,
+}
+
+impl FSChaCha20 {
+    fn new(key: [u8; 32]) -> Self {
+        FSChaCha20 { cipher: Self::stream(&key, 0), chunk_counter: 0 }
+    }
+
+    fn stream(key: &[u8; 32], rekey_counter: u64) -> ChaCha20 {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&rekey_counter.to_le_bytes());
+        ChaCha20::new(key.into(), &nonce.into())
+    }
+
+    /// en- or decrypts `chunk` in place, continuing the key stream of the previous chunk
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunk_counter += 1;
+        if self.chunk_counter.is_multiple_of(REKEY_INTERVAL) {
+            // the next 32 bytes of the key stream become the new key
+            let mut key = [0; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.cipher = Self::stream(&key, self.chunk_counter / REKEY_INTERVAL);
+        }
+    }
+}
+
+/// ChaCha20-Poly1305 AEAD for the packet contents, which is rekeyed after every [REKEY_INTERVAL] packets
+struct FSChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u64,
+}
+
+impl FSChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        FSChaCha20Poly1305 { key, packet_counter: 0 }
+    }
+
+    fn nonce(packet_number: u32, rekey_counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..4].copy_from_slice(&packet_number.to_le_bytes());
+        nonce[4..].copy_from_slice(&rekey_counter.to_le_bytes());
+        nonce
+    }
+
+    fn cipher(&self) -> (ChaCha20Poly1305, [u8; 12]) {
+        let nonce = Self::nonce((self.packet_counter % REKEY_INTERVAL) as u32, self.packet_counter / REKEY_INTERVAL);
+        (ChaCha20Poly1305::new(&self.key.into()), nonce)
+    }
+
+    /// ciphertext followed by the authentication tag
+    fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let (cipher, nonce) = self.cipher();
+        let ciphertext = cipher.encrypt(&nonce.into(), Payload { msg: plaintext, aad }).expect("packet size is bounded");
+        self.next_packet();
+        ciphertext
+    }
+
+    /// `None`, if the authentication tag does not match
+    fn decrypt(&mut self, ciphertext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        let (cipher, nonce) = self.cipher();
+        let plaintext = cipher.decrypt(&nonce.into(), Payload { msg: ciphertext, aad }).ok()?;
+        self.next_packet();
+        Some(plaintext)
+    }
+
+    fn next_packet(&mut self) {
+        self.packet_counter += 1;
+        if self.packet_counter.is_multiple_of(REKEY_INTERVAL) {
+            // the new key is the start of the key stream of a nonce, which is never used for a packet
+            let rekey_counter = self.packet_counter / REKEY_INTERVAL - 1;
+            let cipher = ChaCha20Poly1305::new(&self.key.into());
+            let nonce = Self::nonce(u32::MAX, rekey_counter);
+            let key_stream = cipher.encrypt(&nonce.into(), Payload { msg: &[0; 32], aad: &[] }).unwrap();
+            self.key.copy_from_slice(&key_stream[..32]);
+        }
+    }
+}
+
+/// Encrypted v2 transport (BIP324) of an established connection
+///
+/// Each message is sent as a packet:
+///
+/// size | field    | type     | description
+/// ---  | -----    | ----     | ------------
+/// 3    | length   | u24      | length of the contents, encrypted with the length cipher
+/// 1    | header   | u8       | flags, only [IGNORE_BIT] is defined
+/// ?    | contents | Vec<u8>  | short message id (or 0 followed by the 12 byte command), followed by the payload
+/// 16   | tag      | [u8; 16] | Poly1305 authentication tag of header and contents
+///
+/// The first packet in each direction authenticates the garbage sent before it.
+pub struct V2Cipher {
+    send_length: FSChaCha20,
+    send_packet: FSChaCha20Poly1305,
+    receive_length: FSChaCha20,
+    receive_packet: FSChaCha20Poly1305,
+    send_garbage_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+    receive_garbage_terminator: [u8; GARBAGE_TERMINATOR_SIZE],
+    session_id: [u8; 32],
+    /// garbage authenticated by the next sent packet
+    send_aad: Vec<u8>,
+    /// garbage authenticated by the next received packet
+    receive_aad: Vec<u8>,
+    /// decrypted length of the packet currently being received
+    pending_length: Option<usize>,
+}
+
+impl V2Cipher {
+    /// Derives the keys from the ECDH `shared_secret` using HKDF-SHA256, salted with the network's magic value.
+    /// `garbage` is the garbage we sent after our public key.
+    fn new(shared_secret: &[u8; 32], initiator: bool, chain: Chain, garbage: Vec<u8>) -> Self {
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&chain.magic_value().to_le_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret);
+        let expand = |label: &str| {
+            let mut key = [0; 32];
+            hkdf.expand(label.as_bytes(), &mut key).unwrap();
+            key
+        };
+
+        let garbage_terminators = expand("garbage_terminators");
+        let initiator_garbage_terminator = garbage_terminators[..GARBAGE_TERMINATOR_SIZE].try_into().unwrap();
+        let responder_garbage_terminator = garbage_terminators[GARBAGE_TERMINATOR_SIZE..].try_into().unwrap();
+        let (send, receive) = match initiator {
+            true => (("initiator_L", "initiator_P", initiator_garbage_terminator), ("responder_L", "responder_P", responder_garbage_terminator)),
+            false => (("responder_L", "responder_P", responder_garbage_terminator), ("initiator_L", "initiator_P", initiator_garbage_terminator)),
+        };
+
+        V2Cipher {
+            send_length: FSChaCha20::new(expand(send.0)),
+            send_packet: FSChaCha20Poly1305::new(expand(send.1)),
+            receive_length: FSChaCha20::new(expand(receive.0)),
+            receive_packet: FSChaCha20Poly1305::new(expand(receive.1)),
+            send_garbage_terminator: send.2,
+            receive_garbage_terminator: receive.2,
+            session_id: expand("session_id"),
+            send_aad: garbage,
+            receive_aad: vec![],
+            pending_length: None,
+        }
+    }
+
+    /// Identifies the session. Both nodes derive the same one, which can be compared out of band to rule out a
+    /// man in the middle.
+    pub fn session_id(&self) -> [u8; 32] {
+        self.session_id
+    }
+
+    pub fn send_garbage_terminator(&self) -> &[u8; GARBAGE_TERMINATOR_SIZE] {
+        &self.send_garbage_terminator
+    }
+
+    pub fn receive_garbage_terminator(&self) -> &[u8; GARBAGE_TERMINATOR_SIZE] {
+        &self.receive_garbage_terminator
+    }
+
+    /// sets the garbage received before the terminator, which is authenticated by the first received packet
+    pub fn set_received_garbage(&mut self, garbage: &[u8]) {
+        self.receive_aad = garbage.to_vec();
+    }
+
+    /// Encrypts `contents` as a packet. Decoy packets (`ignore`) are discarded by the receiver.
+    pub fn encrypt_packet(&mut self, contents: &[u8], ignore: bool) -> Vec<u8> {
+        let mut packet = (contents.len() as u32).to_le_bytes()[..LENGTH_FIELD_SIZE].to_vec();
+        self.send_length.crypt(&mut packet);
+
+        let mut plaintext = Vec::with_capacity(HEADER_SIZE + contents.len());
+        plaintext.push(if ignore { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+        let aad = std::mem::take(&mut self.send_aad);
+        packet.extend(self.send_packet.encrypt(&plaintext, &aad));
+        packet
+    }
+
+    /// Tries to take the next complete packet from the beginning of `buffer` and returns its ignore flag and contents.
+    /// An incomplete packet remains in the buffer, apart from its length field, which is decrypted only once.
+    ///
+    /// Contents longer than `max_contents_len` result in [PeerError::PayloadTooLarge], before any of them is awaited.
+    pub fn try_decrypt_packet(&mut self, buffer: &mut IOBuffer, max_contents_len: usize) -> PeerResult<Option<(bool, Vec<u8>)>> {
+        let contents_len = match self.pending_length {
+            Some(len) => len,
+            None => {
+                if buffer.content().len() < LENGTH_FIELD_SIZE {
+                    return Ok(None);
+                }
+                let mut length_field = [0; 4];
+                length_field[..LENGTH_FIELD_SIZE].copy_from_slice(&buffer.content()[..LENGTH_FIELD_SIZE]);
+                self.receive_length.crypt(&mut length_field[..LENGTH_FIELD_SIZE]);
+                buffer.shift_left(LENGTH_FIELD_SIZE);
+                let len = u32::from_le_bytes(length_field) as usize;
+                if len > max_contents_len {
+                    return Err(PeerError::PayloadTooLarge { command: "v2 packet".to_string(), len, max: max_contents_len });
+                }
+                self.pending_length = Some(len);
+                len
+            }
+        };
+
+        let packet_len = HEADER_SIZE + contents_len + TAG_SIZE;
+        if buffer.content().len() < packet_len {
+            return Ok(None);
+        }
+        let aad = std::mem::take(&mut self.receive_aad);
+        let plaintext = self.receive_packet.decrypt(&buffer.content()[..packet_len], &aad)
+            .ok_or_else(|| PeerError::ProtocolViolation("authentication of v2 packet failed".to_string()))?;
+        buffer.shift_left(packet_len);
+        self.pending_length = None;
+        Ok(Some((plaintext[0] & IGNORE_BIT != 0, plaintext[HEADER_SIZE..].to_vec())))
+    }
+
+    /// Tries to take the next complete message from the beginning of `buffer`, like [RawMessage::try_consume_message]
+    /// does for the v1 transport. Decoy packets and messages of unknown commands are skipped.
+    pub fn try_consume_message(&mut self, buffer: &mut IOBuffer, chain: Chain, max_payload_len: usize) -> PeerResult<MessageParseOutcome> {
+        let contents = match self.try_decrypt_packet(buffer, 1 + 12 + max_payload_len)? {
+            None => return Ok(MessageParseOutcome::NoMessage),
+            Some((true, _)) => {
+                log::trace!("skipping decoy packet");
+                return Ok(MessageParseOutcome::SkippedMessage);
+            }
+            Some((false, contents)) => contents,
+        };
+
+        let (command, payload) = match contents.first() {
+            None => return Err(PeerError::ProtocolViolation("v2 packet without message type".to_string())),
+            Some(0) if contents.len() < 1 + 12 => {
+                return Err(PeerError::ProtocolViolation("v2 packet with truncated command".to_string()));
+            }
+            Some(0) => (Command::try_from(&contents[1..1 + 12]), &contents[1 + 12..]),
+            Some(&id) => {
+                let command = Command::iter().find(|command| short_id(command) == Some(id))
+                    .ok_or_else(|| PeerError::UnknownCommand(format!("short message id {}", id)));
+                (command, &contents[1..])
+            }
+        };
+        let command = match command {
+            Ok(command) => command,
+            Err(err) => {
+                log::warn!("{}", err);
+                return Ok(MessageParseOutcome::SkippedMessage);
+            }
+        };
+
+        let max_payload_len = command.max_payload_len().min(max_payload_len);
+        if payload.len() > max_payload_len {
+            return Err(PeerError::PayloadTooLarge { command: command.name().to_string(), len: payload.len(), max: max_payload_len });
+        }
+        Ok(MessageParseOutcome::Message(RawMessage::new(chain, command, payload.to_vec())))
+    }
+
+    /// encrypts `message` as a packet, using its short message id if there is one
+    pub fn encrypt_message(&mut self, message: &RawMessage) -> Vec<u8> {
+        let mut contents = Vec::with_capacity(1 + 12 + message.payload.len());
+        match short_id(&message.command) {
+            Some(id) => contents.push(id),
+            None => {
+                contents.push(0);
+                contents.extend_from_slice(message.command.as_bytes());
+            }
+        }
+        contents.extend_from_slice(&message.payload);
+        self.encrypt_packet(&contents, false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use hex_literal::hex;
+    use rstest::*;
+
+    use super::*;
+
+    fn handshake(secret_key: [u8; 32], public_key: [u8; 64]) -> V2Handshake {
+        V2Handshake {
+            secret_key: SecretKey::from_slice(&secret_key).unwrap(),
+            public_key: ElligatorSwift::from_array(public_key),
+            garbage: vec![],
+        }
+    }
+
+    /// a connected pair of ciphers (initiator, responder)
+    fn cipher_pair() -> (V2Cipher, V2Cipher) {
+        let initiator = V2Handshake::new();
+        let responder = V2Handshake::new();
+        let initiator_key = initiator.public_key.to_array();
+        let responder_key = responder.public_key.to_array();
+        let mut initiator = initiator.complete(&responder_key, true, Chain::Regtest);
+        let mut responder = responder.complete(&initiator_key, false, Chain::Regtest);
+        responder.set_received_garbage(&initiator.send_aad);
+        initiator.set_received_garbage(&responder.send_aad);
+        (initiator, responder)
+    }
+
+    /// ECDH test vectors of BIP324
+    #[rstest]
+    #[case(hex!("61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7"),
+           hex!("ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b"),
+           hex!("a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5"),
+           true, hex!("c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592"))]
+    #[case(hex!("1f9c581b35231838f0f17cf0c979835baccb7f3abbbb96ffcc318ab71e6e126f"),
+           hex!("a1855e10e94e00baa23041d916e259f7044e491da6171269694763f018c7e63693d29575dcb464ac816baa1be353ba12e3876cba7628bd0bd8e755e721eb0140"),
+           hex!("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f0000000000000000000000000000000000000000000000000000000000000000"),
+           false, hex!("a0138f564f74d0ad70bc337dacc9d0bf1d2349364caf1188a1e6e8ddb3b7b184"))]
+    #[case(hex!("0286c41cd30913db0fdff7a64ebda5c8e3e7cef10f2aebc00a7650443cf4c60d"),
+           hex!("d1ee8a93a01130cbf299249a258f94feb5f469e7d0f2f28f69ee5e9aa8f9b54a60f2c3ff2d023634ec7f4127a96cc11662e402894cf1f694fb9a7eaa5f1d9244"),
+           hex!("ffffffffffffffffffffffffffffffffffffffffffffffffffffffff22d5e441524d571a52b3def126189d3f416890a99d4da6ede2b0cde1760ce2c3f98457ae"),
+           true, hex!("250b93570d411149105ab8cb0bc5079914906306368c23e9d77c2a33265b994c"))]
+    fn test_key_exchange(#[case] secret_key: [u8; 32], #[case] public_key: [u8; 64], #[case] remote_key: [u8; 64],
+                         #[case] initiator: bool, #[case] shared_secret: [u8; 32]) {
+        let ours = handshake(secret_key, public_key);
+        let expected = V2Cipher::new(&shared_secret, initiator, Chain::Mainnet, vec![]);
+        let cipher = ours.complete(&remote_key, initiator, Chain::Mainnet);
+        assert_eq!(cipher.session_id(), expected.session_id());
+        assert_eq!(cipher.send_garbage_terminator(), expected.send_garbage_terminator());
+    }
+
+    /// vector with in_idx 1 of BIP324's `packet_encoding_test_vectors.csv`
+    #[test]
+    fn test_packet_encryption_vector() {
+        let ours = handshake(
+            hex!("61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7"),
+            hex!("ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b"),
+        );
+        let remote_key = hex!("a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5");
+        let mut cipher = ours.complete(&remote_key, true, Chain::Mainnet);
+
+        assert_eq!(cipher.session_id(), hex!("ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5"));
+        assert_eq!(cipher.send_garbage_terminator(), &hex!("faef555dfcdb936425d84aba524758f3"));
+        assert_eq!(cipher.receive_garbage_terminator(), &hex!("02cb8ff24307a6e27de3b4e7ea3fa65b"));
+        cipher.encrypt_packet(&[], false);
+        assert_eq!(cipher.encrypt_packet(&hex!("8e"), false), hex!("7530d2a18720162ac09c25329a60d75adf36eda3c3"));
+    }
+
+    /// vector with in_idx 999 of BIP324's `packet_encoding_test_vectors.csv`: packet 999 of the responder (i.e. after
+    /// four rekeyings), starting from the vector's mid_shared_secret
+    #[test]
+    fn test_packet_encryption_after_rekeying() {
+        let shared_secret = hex!("a0138f564f74d0ad70bc337dacc9d0bf1d2349364caf1188a1e6e8ddb3b7b184");
+        let mut cipher = V2Cipher::new(&shared_secret, false, Chain::Mainnet, vec![]);
+        assert_eq!(cipher.send_garbage_terminator(), &hex!("efb64fd80acd3825ac9bc2a67216535a"));
+        for _ in 0..999 {
+            cipher.encrypt_packet(&[], false);
+        }
+        assert_eq!(cipher.encrypt_packet(&hex!("3eb1d4e98035cfd8eeb29bac969ed3824a"), false),
+                   hex!("1da1bcf589f9b61872f45b7fa5371dd3f8bdf5d515b0c5f9fe9f0044afb8dc0aa1cd39a8c4"));
+    }
+
+    #[test]
+    fn test_salt_depends_on_network() {
+        let shared_secret = hex!("c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592");
+        let mut cipher = V2Cipher::new(&shared_secret, true, Chain::Regtest, b"garbage".to_vec());
+        assert_eq!(cipher.session_id(), hex!("37e9ffd8b245d6005c9934646d66011074d5091fa074bc810611ec2d01477960"));
+        assert_eq!(cipher.encrypt_packet(&[], true), hex!("dcfdcfdb0e1ee80224930d0b154377da87a39ee4"));
+    }
+
+    #[test]
+    fn test_packets_round_trip_across_rekeying() {
+        let (mut initiator, mut responder) = cipher_pair();
+        assert_eq!(initiator.session_id(), responder.session_id());
+        let mut buffer = IOBuffer::default();
+        for i in 0..500_u32 {
+            let packet = initiator.encrypt_packet(&i.to_le_bytes(), i % 3 == 0);
+            // feed the packet in two parts, splitting the length field
+            buffer.append(&packet[..2]);
+            assert!(responder.try_decrypt_packet(&mut buffer, 100).unwrap().is_none());
+            buffer.append(&packet[2..]);
+            let (ignore, contents) = responder.try_decrypt_packet(&mut buffer, 100).unwrap().unwrap();
+            assert_eq!(ignore, i % 3 == 0);
+            assert_eq!(contents, i.to_le_bytes());
+        }
+        assert!(buffer.content().is_empty());
+    }
+
+    #[test]
+    fn test_tampered_packet_is_rejected() {
+        let (mut initiator, mut responder) = cipher_pair();
+        let mut packet = initiator.encrypt_packet(b"contents", false);
+        *packet.last_mut().unwrap() ^= 1;
+        let mut buffer = IOBuffer::default();
+        buffer.append(&packet);
+        assert!(matches!(responder.try_decrypt_packet(&mut buffer, 100), Err(PeerError::ProtocolViolation(_))));
+    }
+
+    #[test]
+    fn test_wrong_garbage_is_rejected() {
+        let (mut initiator, mut responder) = cipher_pair();
+        responder.set_received_garbage(b"other garbage");
+        let mut buffer = IOBuffer::default();
+        buffer.append(&initiator.encrypt_packet(&[], false));
+        assert!(responder.try_decrypt_packet(&mut buffer, 100).is_err());
+    }
+
+    #[test]
+    fn test_oversized_packet_is_rejected_after_length() {
+        let (mut initiator, mut responder) = cipher_pair();
+        let packet = initiator.encrypt_packet(&[0; 101], false);
+        let mut buffer = IOBuffer::default();
+        buffer.append(&packet[..LENGTH_FIELD_SIZE]);
+        assert!(matches!(responder.try_decrypt_packet(&mut buffer, 100), Err(PeerError::PayloadTooLarge { len: 101, max: 100, .. })));
+    }
+
+    #[rstest]
+    #[case(Command::Ping, 18)]
+    #[case(Command::AddrV2, 28)]
+    #[case(Command::Version, 0)]
+    #[case(Command::WtxidRelay, 0)]
+    fn test_messages_round_trip(#[case] command: Command, #[case] first_byte: u8) {
+        let (mut initiator, mut responder) = cipher_pair();
+        let payload = if command.max_payload_len() >= 8 { vec![7; 8] } else { vec![] };
+        let message = RawMessage::new(Chain::Regtest, command, payload.clone());
+        let mut buffer = IOBuffer::default();
+        buffer.append(&initiator.encrypt_message(&message));
+        let (_, contents) = responder.try_decrypt_packet(&mut buffer, 1000).unwrap().unwrap();
+        assert_eq!(contents[0], first_byte);
+        let command_len = if first_byte == 0 { 12 } else { 0 };
+        assert_eq!(contents.len(), 1 + command_len + payload.len());
+
+        let packet = initiator.encrypt_message(&message);
+        let mut buffer = IOBuffer::default();
+        buffer.append(&packet);
+        match responder.try_consume_message(&mut buffer, Chain::Regtest, 1000).unwrap() {
+            MessageParseOutcome::Message(received) => {
+                assert_eq!(received.command.name(), message.command.name());
+                assert_eq!(received.payload, payload);
+            }
+            _ => panic!("expected a message"),
+        }
+    }
+
+    #[test]
+    fn test_decoys_and_unknown_messages_are_skipped() {
+        let (mut initiator, mut responder) = cipher_pair();
+        let mut buffer = IOBuffer::default();
+        buffer.append(&initiator.encrypt_packet(b"decoy", true));
+        buffer.append(&initiator.encrypt_packet(&[9], false));
+        buffer.append(&initiator.encrypt_packet(b"\0unknown\0\0\0\0\0", false));
+        buffer.append(&initiator.encrypt_packet(&[255, 1, 2], false));
+        for _ in 0..4 {
+            assert!(matches!(responder.try_consume_message(&mut buffer, Chain::Regtest, 1000).unwrap(), MessageParseOutcome::SkippedMessage));
+        }
+        assert!(matches!(responder.try_consume_message(&mut buffer, Chain::Regtest, 1000).unwrap(), MessageParseOutcome::NoMessage));
+    }
+
+    #[test]
+    fn test_payload_limit_of_command() {
+        let (mut initiator, mut responder) = cipher_pair();
+        let mut buffer = IOBuffer::default();
+        buffer.append(&initiator.encrypt_packet(&[18, 0, 0, 0, 0, 0, 0, 0, 0, 0], false));
+        assert!(matches!(responder.try_consume_message(&mut buffer, Chain::Regtest, 1000),
+                         Err(PeerError::PayloadTooLarge { len: 9, max: 8, .. })));
+    }
+}
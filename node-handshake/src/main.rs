@@ -4,10 +4,15 @@ use clap::Parser;
 use log::LevelFilter;
 use simple_logger::SimpleLogger;
 use tokio::io::{self};
+use tokio::sync::mpsc;
 use tokio::time::{Duration, timeout};
 
 use crate::node::Node;
+use net::wire_protocol::block::{BlockHeader, display_hash};
+use net::wire_protocol::keepalive::KeepaliveConfig;
+use net::wire_protocol::listener::NodeListener;
 use net::wire_protocol::node::{Chain, NodeDesc, NodeService, NodeServiceSet};
+use net::wire_protocol::transaction::Transaction;
 
 mod node;
 
@@ -16,8 +21,40 @@ mod node;
 #[command(version, about, long_about = None)]
 struct Args {
     /// Remote IP socket address. E.g. 127.0.0.1:18445 for a local regression testnet node
+    #[arg(short, long, required_unless_present = "listen", conflicts_with = "listen")]
+    remote: Option<SocketAddr>,
+
+    /// Local IP socket address to accept inbound connections on (passive mode). E.g. 127.0.0.1:18500
     #[arg(short, long)]
-    remote: SocketAddr,
+    listen: Option<SocketAddr>,
+
+    /// Network of the remote node: main, test, testnet4, signet or regtest
+    #[arg(short, long, default_value = "regtest")]
+    chain: Chain,
+
+    /// Number of pings to exchange after the handshake to measure the round-trip time
+    #[arg(short, long, default_value_t = 0)]
+    pings: u32,
+
+    /// Ask the remote node for addresses of other nodes after the handshake
+    #[arg(short, long)]
+    getaddr: bool,
+
+    /// Download the block headers of the remote node after the handshake
+    #[arg(long)]
+    headers: bool,
+
+    /// Download the block headers and then the compact block filters of the remote node after the handshake
+    #[arg(long)]
+    filters: bool,
+
+    /// Watch the transactions relayed by the remote node after the handshake, until it disconnects
+    #[arg(long)]
+    mempool: bool,
+
+    /// Use the encrypted v2 transport (BIP324), falling back to v1 for remote nodes which don't support it
+    #[arg(long)]
+    v2: bool,
 }
 
 fn init_logging() {
@@ -36,26 +73,96 @@ async fn main() -> io::Result<()> {
     init_logging();
     let args = Args::parse();
 
+    let services = match args.v2 {
+        true => NodeServiceSet::new(&[NodeService::NodeNetwork, NodeService::NodeP2pV2]),
+        false => NodeServiceSet::new(&[NodeService::NodeNetwork]),
+    };
     let mut node = Node::new(NodeDesc {
-        chain: Chain::Regtest,
+        chain: args.chain,
         protocol_version: BITCOIN_PROTOCOL_VERSION,
-        services: NodeServiceSet(vec![NodeService::NodeNetwork]),
+        services,
         sub_ver: "/p2p_showcase.bitmagier:1.0".to_string(),
         start_height: 1,
+        relay: args.mempool,
     });
+    node.set_v2_transport(args.v2);
+
+    match (args.remote, args.listen) {
+        (Some(remote), _) => connect(&mut node, remote, &args).await,
+        (None, Some(listen)) => accept_inbound(&mut node, listen, args.v2).await?,
+        (None, None) => unreachable!("ensured by argument parser"),
+    }
+
+    Ok(())
+}
 
-    let handshake_timeout = Duration::from_secs(5);
-    match timeout(handshake_timeout, node.connect_with(args.remote)).await {
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn connect(node: &mut Node, remote: SocketAddr, args: &Args) {
+    match timeout(HANDSHAKE_TIMEOUT, node.connect_with(remote)).await {
         Ok(result) => {
             match result {
                 Ok(node_desc) => {
-                    log::info!("connection + handshake to node @ {} successfully established", args.remote);
+                    log::info!("connection + handshake to node @ {} successfully established", remote);
+                    if let Some(session_id) = node.session_id(remote) {
+                        let session_id: String = session_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        log::info!("v2 transport session id: {}", session_id);
+                    }
                     log::debug!("Remote node details: {:?}", node_desc);
-                    node.close_connection(args.remote);
+                    log::info!("Remote node services: {}", node_desc.services);
+                    if args.pings > 0 {
+                        let config = KeepaliveConfig {
+                            ping_interval: Duration::from_secs(1),
+                            max_missed_pongs: 3,
+                            rounds: Some(args.pings),
+                        };
+                        match node.keepalive(remote, config).await {
+                            Ok(latency) => log::info!("ping round-trip time: last {:?}, min {:?}", latency.last, latency.min),
+                            Err(err) => log::warn!("error while pinging {}: {}", remote, err),
+                        }
+                    }
+                    if args.getaddr {
+                        match node.discover_addresses(remote, Duration::from_secs(10)).await {
+                            Ok(addresses) => {
+                                log::info!("received {} addresses", addresses.len());
+                                for addr in addresses {
+                                    log::debug!("{} ({})", addr, addr.services);
+                                }
+                            }
+                            Err(err) => log::warn!("error while requesting addresses from {}: {}", remote, err),
+                        }
+                    }
+                    if args.headers || args.filters {
+                        match node.sync_headers(remote).await {
+                            Ok(chain) => {
+                                let tip = chain.last().expect("chain starts with genesis");
+                                log::info!("synchronized {} headers, tip {}", chain.len() - 1, display_hash(&tip.block_hash()));
+                                if args.filters {
+                                    download_filters(node, remote, &node_desc, &chain).await;
+                                }
+                            }
+                            Err(err) => log::warn!("error while synchronizing headers from {}: {}", remote, err),
+                        }
+                    }
+                    if args.mempool {
+                        // bitcoin core disconnects peers asking for its mempool, unless it offers NODE_BLOOM
+                        let request_mempool = node_desc.services.contains(NodeService::NodeBloom);
+                        let (sender, mut receiver) = mpsc::unbounded_channel::<Transaction>();
+                        let log_transactions = async move {
+                            while let Some(tx) = receiver.recv().await {
+                                log::info!("transaction {} ({} vbytes)", display_hash(&tx.txid()), tx.vsize());
+                            }
+                        };
+                        let (result, _) = tokio::join!(node.observe_mempool(remote, request_mempool, sender), log_transactions);
+                        if let Err(err) = result {
+                            log::warn!("error while observing the mempool of {}: {}", remote, err);
+                        }
+                    }
+                    node.close_connection(remote);
                     log::debug!("connection intentionally closed, because this is the end of the showcase");
                 }
                 Err(err) => {
-                    log::warn!("error while communicating with {}: {}", args.remote, err);
+                    log::warn!("error while communicating with {}: {}", remote, err);
                 }
             }
         },
@@ -63,6 +170,46 @@ async fn main() -> io::Result<()> {
             log::warn!("handshake timeout")
         }
     }
+}
 
-    Ok(())
+async fn download_filters(node: &mut Node, remote: SocketAddr, remote_desc: &NodeDesc, chain: &[BlockHeader]) {
+    if !remote_desc.services.contains(NodeService::NodeCompactFilters) {
+        log::warn!("{} does not serve compact block filters", remote);
+        return;
+    }
+    let block_hashes = chain.iter().map(|header| header.block_hash()).collect();
+    match node.download_filters(remote, 0, block_hashes).await {
+        Ok(download) => {
+            let tip = download.filter_headers.last().expect("chain starts with genesis");
+            log::info!("downloaded {} compact block filters, filter header of the tip {}", download.filters.len(), display_hash(tip));
+        }
+        Err(err) => log::warn!("error while downloading compact block filters from {}: {}", remote, err),
+    }
+}
+
+/// Passive mode: accepts inbound connections (e.g. from a bitcoin core node started with `-connect=<listen address>`)
+async fn accept_inbound(node: &mut Node, listen: SocketAddr, v2: bool) -> io::Result<()> {
+    let listener = NodeListener::bind(node.chain(), listen).await?;
+    log::info!("waiting for inbound connections on {}", listener.local_addr()?);
+    loop {
+        let (connection, remote) = match v2 {
+            false => listener.accept().await?,
+            true => match listener.accept_v2().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::warn!("v2 handshake of inbound connection failed: {}", err);
+                    continue;
+                }
+            },
+        };
+        match timeout(HANDSHAKE_TIMEOUT, node.respond_to(connection, remote)).await {
+            Ok(Ok(node_desc)) => {
+                log::info!("inbound connection + handshake from node @ {} successfully established", remote);
+                log::debug!("Remote node details: {:?}", node_desc);
+                log::info!("Remote node services: {}", node_desc.services);
+            }
+            Ok(Err(err)) => log::warn!("error while communicating with {}: {}", remote, err),
+            Err(_) => log::warn!("handshake timeout"),
+        }
+    }
 }
@@ -1,29 +1,52 @@
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use net::error::PeerResult;
+use tokio::sync::mpsc;
+
+use net::error::{PeerError, PeerResult};
 use net::wire_protocol::connection::NodeConnection;
-use net::wire_protocol::handshake::HandshakeInitConversationTopic;
-use net::wire_protocol::node::NodeDesc;
+use net::wire_protocol::addr_discovery::AddrDiscoveryConversationTopic;
+use net::wire_protocol::address::PeerAddress;
+use net::wire_protocol::block::BlockHeader;
+use net::wire_protocol::filter_download::{FilterDownload, FilterDownloadConversationTopic};
+use net::wire_protocol::handshake::{HandshakeInitConversationTopic, HandshakeRespondConversationTopic, LocalNonces};
+use net::wire_protocol::headers_sync::HeadersSyncConversationTopic;
+use net::wire_protocol::keepalive::{KeepaliveConfig, KeepaliveConversationTopic, LatencyStats, PeerLatency};
+use net::wire_protocol::mempool_observer::MempoolObserverConversationTopic;
+use net::wire_protocol::node::{Chain, NodeDesc};
+use net::wire_protocol::transaction::Transaction;
 
 pub struct Node {
     node_desc: NodeDesc,
+    local_nonces: LocalNonces,
     remote_nodes: HashMap<SocketAddr, NodeConnection>,
+    /// connect using the encrypted v2 transport (BIP324)
+    v2_transport: bool,
 }
 
 impl Node {
     pub fn new(node_desc: NodeDesc) -> Self {
         Node {
             node_desc,
+            local_nonces: LocalNonces::default(),
             remote_nodes: HashMap::new(),
+            v2_transport: false,
         }
     }
 
+    pub fn set_v2_transport(&mut self, v2_transport: bool) {
+        self.v2_transport = v2_transport;
+    }
+
     pub async fn connect_with(&mut self, remote_addr: SocketAddr) -> PeerResult<NodeDesc> {
-        let mut connection = NodeConnection::new(self.node_desc.chain, remote_addr).await?;
+        let mut connection = match self.v2_transport {
+            true => NodeConnection::new_v2(self.node_desc.chain, remote_addr).await?,
+            false => NodeConnection::new(self.node_desc.chain, remote_addr).await?,
+        };
 
         let result = connection.proceed_conversation(
-            HandshakeInitConversationTopic::new(&self.node_desc, remote_addr)
+            HandshakeInitConversationTopic::new(&self.node_desc, remote_addr, &self.local_nonces)
         ).await?;
 
         self.remote_nodes.insert(remote_addr, connection);
@@ -31,6 +54,71 @@ impl Node {
         Ok(result)
     }
 
+    pub fn chain(&self) -> Chain {
+        self.node_desc.chain
+    }
+
+    /// session id of the connection to `remote_addr`, if it uses the v2 transport
+    pub fn session_id(&self, remote_addr: SocketAddr) -> Option<[u8; 32]> {
+        self.remote_nodes.get(&remote_addr).and_then(NodeConnection::session_id)
+    }
+
+    /// performs the handshake on an inbound connection as responder
+    pub async fn respond_to(&mut self, mut connection: NodeConnection, remote_addr: SocketAddr) -> PeerResult<NodeDesc> {
+        let result = connection.proceed_conversation(
+            HandshakeRespondConversationTopic::new(&self.node_desc, remote_addr, &self.local_nonces)
+        ).await?;
+
+        self.remote_nodes.insert(remote_addr, connection);
+
+        Ok(result)
+    }
+
+    pub async fn keepalive(&mut self, remote_addr: SocketAddr, config: KeepaliveConfig) -> PeerResult<LatencyStats> {
+        let connection = self.remote_nodes.get_mut(&remote_addr)
+            .ok_or(PeerError::NotConnected(remote_addr))?;
+        connection.proceed_conversation(
+            KeepaliveConversationTopic::new(self.node_desc.chain, config, &PeerLatency::default())
+        ).await
+    }
+
+    pub async fn discover_addresses(&mut self, remote_addr: SocketAddr, response_timeout: Duration) -> PeerResult<Vec<PeerAddress>> {
+        let connection = self.remote_nodes.get_mut(&remote_addr)
+            .ok_or(PeerError::NotConnected(remote_addr))?;
+        connection.proceed_conversation(
+            AddrDiscoveryConversationTopic::new(self.node_desc.chain, response_timeout)
+        ).await
+    }
+
+    /// pulls the header chain of the remote node, starting at genesis
+    pub async fn sync_headers(&mut self, remote_addr: SocketAddr) -> PeerResult<Vec<BlockHeader>> {
+        let connection = self.remote_nodes.get_mut(&remote_addr)
+            .ok_or(PeerError::NotConnected(remote_addr))?;
+        connection.proceed_conversation(
+            HeadersSyncConversationTopic::new(&self.node_desc)
+        ).await
+    }
+
+    /// downloads and verifies the compact block filters of the blocks with the given hashes, starting at `start_height`
+    pub async fn download_filters(&mut self, remote_addr: SocketAddr, start_height: u32, block_hashes: Vec<[u8; 32]>) -> PeerResult<FilterDownload> {
+        let connection = self.remote_nodes.get_mut(&remote_addr)
+            .ok_or(PeerError::NotConnected(remote_addr))?;
+        connection.proceed_conversation(
+            FilterDownloadConversationTopic::new(self.node_desc.chain, start_height, block_hashes)
+        ).await
+    }
+
+    /// Streams the transactions relayed by the remote node to `sender`, until the receiver is dropped. With
+    /// `request_mempool`, the remote node is asked to announce its whole mempool, which bitcoin core only accepts when
+    /// it offers NODE_BLOOM.
+    pub async fn observe_mempool(&mut self, remote_addr: SocketAddr, request_mempool: bool, sender: mpsc::UnboundedSender<Transaction>) -> PeerResult<()> {
+        let connection = self.remote_nodes.get_mut(&remote_addr)
+            .ok_or(PeerError::NotConnected(remote_addr))?;
+        connection.proceed_conversation(
+            MempoolObserverConversationTopic::new(self.node_desc.chain, request_mempool, sender)
+        ).await
+    }
+
     pub fn close_connection(&mut self, remote: SocketAddr) {
         // connection is closed by tokio when socket is dropped
         self.remote_nodes.remove(&remote);